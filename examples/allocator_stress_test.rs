@@ -0,0 +1,98 @@
+
+extern crate dibs;
+extern crate rand;
+
+use dibs::*;
+use rand::{thread_rng, random, Rng};
+
+const TOTAL_SIZE: usize = 4096;
+
+fn main() {
+    for &strategy in &[AllocStrategy::BestFit, AllocStrategy::FirstFit] {
+        println!("testing {:?}", strategy);
+        test_strategy(strategy);
+    }
+}
+
+fn test_strategy(strategy: AllocStrategy) {
+    let mut allocator = Allocator::with_strategy(Size::from_bytes(TOTAL_SIZE), strategy);
+
+    // The reference model: which bytes are currently allocated.
+    let mut occupied = vec![false; TOTAL_SIZE];
+    let mut allocations: Vec<Allocation> = Vec::new();
+
+    for iteration in 0 .. 200000 {
+        if iteration > 0 && iteration % 20000 == 0 {
+            println!("tested {} operations, allocation count = {}", iteration, allocations.len());
+        }
+
+        let action: u8 = random();
+
+        match action {
+            0 ... 140 => {
+                // Alloc
+                let size = Size(thread_rng().gen_range(1, 128));
+
+                if size > allocator.largest_free_block() {
+                    continue
+                }
+
+                let allocation = allocator.alloc(size);
+                assert_eq!(allocation.size, size);
+
+                for byte in &mut occupied[allocation.addr.offset() .. allocation.end().offset()] {
+                    assert!(!*byte, "{:?} overlaps an existing allocation", allocation);
+                    *byte = true;
+                }
+
+                allocations.push(allocation);
+            }
+            141 ... 255 => {
+                // Free
+                if allocations.is_empty() {
+                    continue
+                }
+
+                let index = thread_rng().gen_range(0, allocations.len());
+                let allocation = allocations.swap_remove(index);
+                allocator.free(allocation);
+
+                for byte in &mut occupied[allocation.addr.offset() .. allocation.end().offset()] {
+                    assert!(*byte);
+                    *byte = false;
+                }
+            }
+            _ => unreachable!()
+        }
+
+        allocator.verify().unwrap();
+        check_against_model(&allocator, &occupied, &allocations);
+    }
+}
+
+fn check_against_model(allocator: &Allocator, occupied: &[bool], allocations: &[Allocation]) {
+    let mut expected_allocations = allocations.to_vec();
+    expected_allocations.sort_by_key(|alloc| alloc.addr);
+
+    let actual_allocations: Vec<_> = allocator.allocated_regions().collect();
+    assert_eq!(actual_allocations, expected_allocations);
+
+    // Free regions must be maximal runs of unoccupied bytes, i.e. fully merged.
+    let mut expected_free = Vec::new();
+    let mut start = None;
+
+    for (index, &is_occupied) in occupied.iter().chain(Some(&true)).enumerate() {
+        match (start, is_occupied) {
+            (None, false) => start = Some(index),
+            (Some(run_start), true) => {
+                expected_free.push(Allocation::new(Address::from_offset(run_start),
+                                                   Size::from_bytes(index - run_start)));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let actual_free: Vec<_> = allocator.free_regions().collect();
+    assert_eq!(actual_free, expected_free);
+}
@@ -1,7 +1,18 @@
 
 
 use memory::{Storage, Address, Size};
-use persist::{Serialize, StorageWriter};
+use byteorder::ByteOrder;
+use persist::{Serialize, Deserialize, FixedSizeSerialize, StorageWriter, StorageReader};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct AllocatorStats {
+    pub total_size: Size,
+    pub allocated_bytes: Size,
+    pub free_bytes: Size,
+    pub allocation_count: usize,
+    pub free_block_count: usize,
+    pub largest_free_block: Size,
+}
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Allocation {
@@ -27,11 +38,51 @@ impl Allocation {
     pub fn end(&self) -> Address {
         self.addr + self.size
     }
+
+    #[inline]
+    pub fn contains(&self, addr: Address) -> bool {
+        addr >= self.start() && addr < self.end()
+    }
+
+    /// Checks whether the `len` bytes starting at `addr` lie completely
+    /// within this allocation.
+    #[inline]
+    pub fn contains_range(&self, addr: Address, len: Size) -> bool {
+        addr >= self.start() && addr + len <= self.end()
+    }
+
+    #[inline]
+    pub fn overlaps(&self, other: &Allocation) -> bool {
+        self.start() < other.end() && other.start() < self.end()
+    }
+
+    /// Splits into the first `offset` bytes and the rest.
+    #[inline]
+    pub fn split_at(&self, offset: Size) -> (Allocation, Allocation) {
+        assert!(offset < self.size,
+                "Cannot split {:?} at offset {}", self, offset.as_u32());
+
+        (Allocation::new(self.addr, offset),
+         Allocation::new(self.addr + offset, self.size - offset))
+    }
+
+    /// Returns the block covering both `self` and `other` if one directly
+    /// follows the other.
+    #[inline]
+    pub fn try_merge(&self, other: &Allocation) -> Option<Allocation> {
+        if self.end() == other.start() {
+            Some(Allocation::new(self.addr, self.size + other.size))
+        } else if other.end() == self.start() {
+            Some(Allocation::new(other.addr, self.size + other.size))
+        } else {
+            None
+        }
+    }
 }
 
 impl Serialize for Allocation {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         let Allocation {
             addr,
             size,
@@ -42,24 +93,91 @@ impl Serialize for Allocation {
     }
 }
 
+/// Determines which free block `Allocator::alloc` picks for a request.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum AllocStrategy {
+    /// Use the smallest free block that is large enough.
+    BestFit,
+    /// Use the free block with the lowest address that is large enough.
+    FirstFit,
+    /// Always allocate right after the allocation with the highest address,
+    /// never reusing space that has been freed before it. As long as the
+    /// last allocation is not freed, addresses only ever increase.
+    Bump,
+}
+
+/// Determines how `Allocator::alloc` rounds up the requested size. Rounding
+/// to a few size classes wastes some space within allocations but makes freed
+/// blocks much more likely to fit later requests exactly.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SizeClasses {
+    /// Allocate exactly the requested number of bytes.
+    Exact,
+    /// Round up to the next power of two.
+    PowerOfTwo,
+    /// Round up to the smallest class that is large enough. Classes must be
+    /// sorted in ascending order; larger requests are not rounded.
+    Table(Vec<Size>),
+}
+
+impl Deserialize for Allocation {
+    #[inline]
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Allocation {
+        let addr = Address::read(reader);
+        let size = Size::read(reader);
+        Allocation::new(addr, size)
+    }
+}
+
+impl FixedSizeSerialize for Allocation {
+    const SERIALIZED_SIZE: Size = Size(8);
+}
+
 pub struct Allocator {
     allocations: Vec<Allocation>,
     free_by_addr: Vec<Allocation>,
     free_by_size: Vec<Allocation>,
     total_size: Size,
+    strategy: AllocStrategy,
+    size_classes: SizeClasses,
+    min_split_size: Size,
 
     // TODO: this could be optimized by using an interval tree
     live_mem_refs: Vec<LiveMemRef>,
 }
 
+/// Clones the allocation state. Live memory references are transient and
+/// belong to the original, so the clone starts out without any.
+impl Clone for Allocator {
+    fn clone(&self) -> Allocator {
+        Allocator {
+            allocations: self.allocations.clone(),
+            free_by_addr: self.free_by_addr.clone(),
+            free_by_size: self.free_by_size.clone(),
+            total_size: self.total_size,
+            strategy: self.strategy,
+            size_classes: self.size_classes.clone(),
+            min_split_size: self.min_split_size,
+            live_mem_refs: vec![],
+        }
+    }
+}
+
 impl Allocator {
 
     pub fn new(total_size: Size) -> Allocator {
+        Allocator::with_strategy(total_size, AllocStrategy::BestFit)
+    }
+
+    pub fn with_strategy(total_size: Size, strategy: AllocStrategy) -> Allocator {
         Allocator {
             allocations: vec![],
             free_by_addr: vec![Allocation::new(Address(0), total_size)],
             free_by_size: vec![Allocation::new(Address(0), total_size)],
             total_size,
+            strategy,
+            size_classes: SizeClasses::Exact,
+            min_split_size: Size(0),
             live_mem_refs: vec![],
         }
     }
@@ -68,14 +186,110 @@ impl Allocator {
         self.total_size
     }
 
+    pub fn strategy(&self) -> AllocStrategy {
+        self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: AllocStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn size_classes(&self) -> &SizeClasses {
+        &self.size_classes
+    }
+
+    /// Note that with anything but `SizeClasses::Exact`, allocations can be
+    /// larger than requested.
+    pub fn set_size_classes(&mut self, size_classes: SizeClasses) {
+        if let SizeClasses::Table(ref classes) = size_classes {
+            assert!(classes.windows(2).all(|pair| pair[0] < pair[1]),
+                    "Size classes must be sorted in ascending order");
+        }
+
+        self.size_classes = size_classes;
+    }
+
+    pub fn min_split_size(&self) -> Size {
+        self.min_split_size
+    }
+
+    /// If taking a request out of a free block would leave less than
+    /// `min_split_size` bytes, the whole block is allocated instead, so that
+    /// the free lists don't fill up with slivers too small to be useful.
+    pub fn set_min_split_size(&mut self, min_split_size: Size) {
+        self.min_split_size = min_split_size;
+    }
+
+    /// The size that `alloc()` would actually allocate for a request of
+    /// `size` bytes.
+    pub fn size_class_for(&self, size: Size) -> Size {
+        let rounded = match self.size_classes {
+            SizeClasses::Exact => size,
+            SizeClasses::PowerOfTwo => {
+                size.as_u32().checked_next_power_of_two().map(Size).unwrap_or(size)
+            }
+            SizeClasses::Table(ref classes) => {
+                classes.iter().cloned().find(|&class| class >= size).unwrap_or(size)
+            }
+        };
+
+        // Don't let rounding make an allocation impossible.
+        if rounded > self.total_size { size } else { rounded }
+    }
+
     pub fn max_addr(&self) -> Address {
-        let last_allocation = self.allocations.last().unwrap();
-        last_allocation.end()
+        self.allocations
+            .last()
+            .map(|alloc| alloc.end())
+            .unwrap_or(Address(0))
+    }
+
+    /// Reduces the managed address range to `[0, new_size)`. There must not
+    /// be any allocations beyond `new_size`.
+    pub fn truncate(&mut self, new_size: Size) {
+        let new_end = Address(0) + new_size;
+
+        assert!(new_size <= self.total_size,
+                "Cannot truncate to {:?}, total size is only {:?}", new_size, self.total_size);
+        assert!(self.max_addr() <= new_end,
+                "Cannot truncate to {:?}, there are allocations up to {:?}", new_size, self.max_addr());
+
+        while let Some(&last_free) = self.free_by_addr.last() {
+            if last_free.end() <= new_end {
+                break
+            }
+
+            self.free_by_addr.pop();
+            self.remove_free_by_size(last_free);
+
+            if last_free.start() < new_end {
+                let remaining = Allocation::new(last_free.start(),
+                                                Size(new_end.as_u32() - last_free.start().as_u32()));
+                self.free_by_addr.push(remaining);
+                self.insert_free_by_size(remaining);
+                break
+            }
+        }
+
+        self.total_size = new_size;
     }
 
     pub fn alloc(&mut self, size: Size) -> Allocation {
         assert!(size != Size(0));
+        assert!(size <= self.total_size,
+                "Could not allocate memory. Requested {} bytes, store is only {} bytes.",
+                size.as_u32(), self.total_size.as_u32());
+
+        let size = self.size_class_for(size);
 
+        match self.strategy {
+            AllocStrategy::BestFit => self.alloc_best_fit(size),
+            AllocStrategy::FirstFit => self.alloc_first_fit(size),
+            AllocStrategy::Bump => self.alloc_bump(size),
+        }
+    }
+
+    fn alloc_best_fit(&mut self, size: Size) -> Allocation {
         match self.find_free_by_size(size) {
             Ok(index) => {
                 let alloc = self.free_by_size.remove(index);
@@ -86,20 +300,20 @@ impl Allocator {
             Err(index) => {
                 // Next best fit.
                 if index == self.free_by_size.len() {
-                    let max_available_size = self.free_by_size
-                        .last()
-                        .map(|alloc| alloc.size.as_u32())
-                        .unwrap_or(0);
-
-                    panic!("Could not allocate memory of size {}. Max available size is {}",
-                        size.as_u32(), max_available_size);
+                    self.out_of_memory(size);
                 }
 
                 let available_alloc = self.free_by_size[index];
-                assert!(available_alloc.size >= size);
+                let remaining_space = size_diff(available_alloc.size, size, "best fit remainder");
+
+                if remaining_space < self.min_split_size {
+                    self.free_by_size.remove(index);
+                    self.remove_free_by_addr(available_alloc);
+                    self.insert_alloc(available_alloc);
+                    return available_alloc
+                }
 
                 self.free_by_size.remove(index);
-                let remaining_space = available_alloc.size - size;
                 let remaining_free_alloc = Allocation::new(available_alloc.start() + size, remaining_space);
                 self.insert_free_by_size(remaining_free_alloc);
                 match self.find_free_by_address(available_alloc.addr) {
@@ -120,11 +334,125 @@ impl Allocator {
         }
     }
 
+    fn alloc_first_fit(&mut self, size: Size) -> Allocation {
+        let index = match self.free_by_addr.iter().position(|alloc| alloc.size >= size) {
+            Some(index) => index,
+            None => self.out_of_memory(size),
+        };
+
+        self.alloc_from_free_block(index, size)
+    }
+
+    fn alloc_bump(&mut self, size: Size) -> Allocation {
+        let index = match self.bump_block_index(size) {
+            Some(index) => index,
+            None => self.out_of_memory(size),
+        };
+
+        self.alloc_from_free_block(index, size)
+    }
+
+    // The index in `free_by_addr` of the free block after the last
+    // allocation, if it can hold `size` bytes.
+    fn bump_block_index(&self, size: Size) -> Option<usize> {
+        // The free block after the last allocation, if any, is always the
+        // last one.
+        match self.free_by_addr.last() {
+            Some(free) if free.start() == self.max_addr() && free.size >= size => {
+                Some(self.free_by_addr.len() - 1)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether `alloc(size)` would currently succeed. Unlike comparing
+    /// against `largest_free_block()`, this takes the strategy and the size
+    /// classes into account.
+    pub fn can_alloc(&self, size: Size) -> bool {
+        if size == Size(0) {
+            return false
+        }
+
+        let size = self.size_class_for(size);
+
+        match self.strategy {
+            AllocStrategy::BestFit |
+            AllocStrategy::FirstFit => size <= self.largest_free_block(),
+            AllocStrategy::Bump => self.bump_block_index(size).is_some(),
+        }
+    }
+
+    // Allocates `size` bytes from the start of the free block at `index` in
+    // `free_by_addr`.
+    fn alloc_from_free_block(&mut self, index: usize, size: Size) -> Allocation {
+        let available_alloc = self.free_by_addr[index];
+        self.remove_free_by_size(available_alloc);
+
+        let size = if size_diff(available_alloc.size, size, "free block remainder") < self.min_split_size {
+            available_alloc.size
+        } else {
+            size
+        };
+
+        if available_alloc.size == size {
+            self.free_by_addr.remove(index);
+        } else {
+            let remaining_space = size_diff(available_alloc.size, size, "free block remainder");
+            let remaining_free_alloc = Allocation::new(available_alloc.start() + size, remaining_space);
+            self.free_by_addr[index] = remaining_free_alloc;
+            self.assert_order_free_by_addr(index);
+            self.insert_free_by_size(remaining_free_alloc);
+        }
+
+        let new_alloc = Allocation::new(available_alloc.start(), size);
+        self.insert_alloc(new_alloc);
+        new_alloc
+    }
+
+    fn out_of_memory(&self, size: Size) -> ! {
+        panic!("Could not allocate memory of size {}. Max available size is {}",
+            size.as_u32(), self.largest_free_block().as_u32());
+    }
+
+    /// The size of the largest allocation that can currently succeed.
+    pub fn largest_free_block(&self) -> Size {
+        self.free_by_size
+            .last()
+            .map(|alloc| alloc.size)
+            .unwrap_or(Size(0))
+    }
+
+    /// The free blocks, in address order.
+    pub fn free_regions(&self) -> impl Iterator<Item=Allocation> + '_ {
+        self.free_by_addr.iter().cloned()
+    }
+
+    /// The allocated blocks, in address order.
+    pub fn allocated_regions(&self) -> impl Iterator<Item=Allocation> + '_ {
+        self.allocations.iter().cloned()
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let free_bytes = self.free_by_addr
+                             .iter()
+                             .fold(Size(0), |sum, alloc| sum + alloc.size);
+
+        AllocatorStats {
+            total_size: self.total_size,
+            allocated_bytes: size_diff(self.total_size, free_bytes, "allocated bytes"),
+            free_bytes,
+            allocation_count: self.allocations.len(),
+            free_block_count: self.free_by_addr.len(),
+            largest_free_block: self.largest_free_block(),
+        }
+    }
+
     pub fn free(&mut self, freed_alloc: Allocation) {
         let addr = freed_alloc.addr;
-        if let Ok(alloc_index) = self.find_alloc_by_address(addr) {
+        let freed_alloc = if let Ok(alloc_index) = self.find_alloc_by_address(addr) {
             let alloc = self.allocations.remove(alloc_index);
             assert_eq!(alloc, freed_alloc, "Allocations differ in size.");
+            alloc
         } else {
             panic!("Could not find allocation at {:?}", addr);
         };
@@ -134,43 +462,51 @@ impl Allocator {
                 panic!("Free-list already contains allocation ({:?}) at {:?}", self.free_by_addr[index], addr);
             }
             Err(index) => {
-                if index == self.free_by_addr.len() {
-                    self.free_by_addr.push(freed_alloc);
-                    self.assert_order_free_by_addr(index);
-                    self.insert_free_by_size(freed_alloc);
-                }
-
-                {
-                    let next_free_alloc = self.free_by_addr[index];
-
-                    if freed_alloc.end() == next_free_alloc.start() {
+                let merge_with_prev = index > 0 &&
+                    self.free_by_addr[index - 1].end() == freed_alloc.start();
+                let merge_with_next = index < self.free_by_addr.len() &&
+                    freed_alloc.end() == self.free_by_addr[index].start();
+
+                match (merge_with_prev, merge_with_next) {
+                    (true, true) => {
+                        // The freed block closes the gap between two free
+                        // blocks, so all three become one.
+                        let prev_free_alloc = self.free_by_addr[index - 1];
+                        let next_free_alloc = self.free_by_addr.remove(index);
+                        self.remove_free_by_size(prev_free_alloc);
                         self.remove_free_by_size(next_free_alloc);
-                        let replacement = Allocation::new(freed_alloc.start(),
-                                                          next_free_alloc.size + freed_alloc.size);
-                        self.free_by_addr[index] = replacement;
-                        self.assert_order_free_by_addr(index);
+                        let replacement = Allocation::new(prev_free_alloc.start(),
+                                                          prev_free_alloc.size +
+                                                          freed_alloc.size +
+                                                          next_free_alloc.size);
+                        self.free_by_addr[index - 1] = replacement;
+                        self.assert_order_free_by_addr(index - 1);
                         self.insert_free_by_size(replacement);
-                        return
                     }
-                }
-
-                if index > 0 {
-                    let prev_free_alloc = self.free_by_addr[index - 1];
-
-                    if prev_free_alloc.end() == freed_alloc.start() {
+                    (true, false) => {
+                        let prev_free_alloc = self.free_by_addr[index - 1];
                         self.remove_free_by_size(prev_free_alloc);
                         let replacement = Allocation::new(prev_free_alloc.start(),
                                                           prev_free_alloc.size + freed_alloc.size);
                         self.free_by_addr[index - 1] = replacement;
                         self.assert_order_free_by_addr(index - 1);
                         self.insert_free_by_size(replacement);
-                        return
+                    }
+                    (false, true) => {
+                        let next_free_alloc = self.free_by_addr[index];
+                        self.remove_free_by_size(next_free_alloc);
+                        let replacement = Allocation::new(freed_alloc.start(),
+                                                          next_free_alloc.size + freed_alloc.size);
+                        self.free_by_addr[index] = replacement;
+                        self.assert_order_free_by_addr(index);
+                        self.insert_free_by_size(replacement);
+                    }
+                    (false, false) => {
+                        self.free_by_addr.insert(index, freed_alloc);
+                        self.assert_order_free_by_addr(index);
+                        self.insert_free_by_size(freed_alloc);
                     }
                 }
-
-                self.free_by_addr.insert(index, freed_alloc);
-                self.assert_order_free_by_addr(index);
-                self.insert_free_by_size(freed_alloc);
             }
         }
     }
@@ -183,11 +519,13 @@ impl Allocator {
 
         match self.free_by_size.binary_search_by_key(&alloc.size, |alloc| alloc.size) {
             Ok(mut index) => {
-                while self.free_by_size[index].addr < alloc.addr && self.free_by_size[index].size == alloc.size {
+                while index < self.free_by_size.len() &&
+                      self.free_by_size[index].addr < alloc.addr &&
+                      self.free_by_size[index].size == alloc.size {
                     index += 1;
                 }
 
-                assert_ne!(alloc, self.free_by_size[index]);
+                assert!(index == self.free_by_size.len() || alloc != self.free_by_size[index]);
 
                 self.free_by_size.insert(index, alloc);
             }
@@ -273,6 +611,84 @@ impl Allocator {
         }
     }
 
+    /// Checks that the allocator's internal data structures are consistent
+    /// with each other, e.g. after loading an allocator from disk.
+    pub fn verify(&self) -> Result<(), String> {
+        for pair in self.free_by_addr.windows(2) {
+            if pair[0].addr >= pair[1].addr {
+                return Err(format!("free_by_addr not sorted: {:?} before {:?}", pair[0], pair[1]));
+            }
+
+            if pair[0].overlaps(&pair[1]) {
+                return Err(format!("Free blocks {:?} and {:?} overlap", pair[0], pair[1]));
+            }
+
+            if pair[0].end() == pair[1].start() {
+                return Err(format!("Free blocks {:?} and {:?} are adjacent but not merged",
+                                   pair[0], pair[1]));
+            }
+        }
+
+        for pair in self.free_by_size.windows(2) {
+            if pair[0].size > pair[1].size {
+                return Err(format!("free_by_size not sorted: {:?} before {:?}", pair[0], pair[1]));
+            }
+        }
+
+        {
+            let mut free_by_size = self.free_by_size.clone();
+            free_by_size.sort_by_key(|alloc| alloc.addr);
+
+            if free_by_size != self.free_by_addr {
+                return Err(format!("free_by_size and free_by_addr differ: {:?} vs {:?}",
+                                   self.free_by_size,
+                                   self.free_by_addr));
+            }
+        }
+
+        for pair in self.allocations.windows(2) {
+            if pair[0].addr >= pair[1].addr {
+                return Err(format!("allocations not sorted: {:?} before {:?}", pair[0], pair[1]));
+            }
+        }
+
+        let mut all_blocks: Vec<(Allocation, bool)> = self.allocations
+            .iter()
+            .map(|&alloc| (alloc, false))
+            .chain(self.free_by_addr.iter().map(|&alloc| (alloc, true)))
+            .collect();
+        all_blocks.sort_by_key(|&(alloc, _)| alloc.addr);
+
+        let mut expected_addr = Address(0);
+
+        for &(alloc, is_free) in all_blocks.iter() {
+            let kind = if is_free { "Free block" } else { "Allocation" };
+
+            if alloc.size == Size(0) {
+                return Err(format!("{} {:?} is empty", kind, alloc));
+            }
+
+            if alloc.addr < expected_addr {
+                return Err(format!("{} {:?} overlaps preceding block ending at {:?}",
+                                   kind, alloc, expected_addr));
+            }
+
+            if alloc.addr > expected_addr {
+                return Err(format!("Gap between {:?} and {:?} is neither allocated nor free",
+                                   expected_addr, alloc.addr));
+            }
+
+            expected_addr = alloc.end();
+        }
+
+        if expected_addr != Address(0) + self.total_size {
+            return Err(format!("Blocks end at {:?} but total size is {:?}",
+                               expected_addr, self.total_size));
+        }
+
+        Ok(())
+    }
+
     fn assert_order_free_by_addr(&self, index: usize) {
         if index > 0 {
             assert!(self.free_by_addr[index - 1].addr < self.free_by_addr[index].addr);
@@ -299,7 +715,7 @@ impl Allocator {
         assert!(alloc_index < self.allocations.len());
 
         // Check that the borrowed range does not extend beyond the allocation
-        assert!(new_mem_ref.end <= self.allocations[alloc_index].end());
+        assert!(self.allocations[alloc_index].contains_range(addr, len));
 
         // Check that we don't conflict with any other borrowed range
         assert!(!self.live_mem_refs.iter().any(|lmr| lmr.conflicts_with(&new_mem_ref)));
@@ -309,6 +725,15 @@ impl Allocator {
         new_mem_ref
     }
 
+    // Like `register_mem_ref()` but the range may span several allocations
+    // and the free space between them.
+    pub(crate) fn register_spanning_mem_ref(&mut self, addr: Address, len: Size) -> LiveMemRef {
+        let new_mem_ref = LiveMemRef::new(addr, len, false);
+        assert!(!self.live_mem_refs.iter().any(|lmr| lmr.conflicts_with(&new_mem_ref)));
+        self.live_mem_refs.push(new_mem_ref);
+        new_mem_ref
+    }
+
     pub(crate) fn unregister_mem_ref(&mut self, mem_ref: LiveMemRef) {
         let idx = self.live_mem_refs.iter().rposition(|&x| x == mem_ref).expect("wat?!");
 
@@ -324,12 +749,15 @@ impl Allocator {
 
 impl Serialize for Allocator {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         let Allocator {
             ref allocations,
             ref free_by_addr,
             ref free_by_size,
             total_size,
+            strategy,
+            ref size_classes,
+            min_split_size,
             live_mem_refs: _,
         } = *self;
 
@@ -337,6 +765,135 @@ impl Serialize for Allocator {
         free_by_addr.write(writer);
         free_by_size.write(writer);
         total_size.write(writer);
+
+        let strategy: u32 = match strategy {
+            AllocStrategy::BestFit => 0,
+            AllocStrategy::FirstFit => 1,
+            AllocStrategy::Bump => 2,
+        };
+        strategy.write(writer);
+
+        // The class table is always written, so that the size does not
+        // depend on the kind of size classes.
+        let (size_classes, class_table): (u32, &[Size]) = match *size_classes {
+            SizeClasses::Exact => (0, &[]),
+            SizeClasses::PowerOfTwo => (1, &[]),
+            SizeClasses::Table(ref classes) => (2, classes),
+        };
+        size_classes.write(writer);
+        class_table.to_vec().write(writer);
+        min_split_size.write(writer);
+    }
+}
+
+impl Deserialize for Allocator {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Allocator {
+        Allocator::try_read(reader).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl Allocator {
+    /// Like `Deserialize::read()` but returns an error instead of panicking
+    /// if the data is invalid, e.g. when loading an untrusted file.
+    pub fn try_read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Result<Allocator, String> {
+        let allocations = reader.try_read_vec::<Allocation>()?;
+        let free_by_addr = reader.try_read_vec::<Allocation>()?;
+        let free_by_size = reader.try_read_vec::<Allocation>()?;
+
+        reader.check_remaining(Size(12))?;
+        let total_size = Size::read(reader);
+
+        let strategy = match u32::read(reader) {
+            0 => AllocStrategy::BestFit,
+            1 => AllocStrategy::FirstFit,
+            2 => AllocStrategy::Bump,
+            other => return Err(format!("Invalid allocation strategy {}", other)),
+        };
+
+        let size_classes_tag = u32::read(reader);
+        let class_table = reader.try_read_vec::<Size>()?;
+        let size_classes = match size_classes_tag {
+            0 => SizeClasses::Exact,
+            1 => SizeClasses::PowerOfTwo,
+            2 => SizeClasses::Table(class_table),
+            other => return Err(format!("Invalid size classes {}", other)),
+        };
+
+        reader.check_remaining(Size(4))?;
+        let min_split_size = Size::read(reader);
+
+        Ok(Allocator {
+            allocations,
+            free_by_addr,
+            free_by_size,
+            total_size,
+            strategy,
+            size_classes,
+            min_split_size,
+            live_mem_refs: vec![],
+        })
+    }
+}
+
+impl Allocator {
+    /// The number of bytes `Serialize::write` would produce for an allocator
+    /// with the given number of allocations, free blocks and entries in the
+    /// size class table.
+    pub(crate) fn serialized_size_for(allocation_count: usize,
+                                      free_block_count: usize,
+                                      size_class_count: usize) -> Size {
+        const VEC_LEN_SIZE: usize = 4;
+        const ALLOCATION_SIZE: usize = 8;
+        const TOTAL_SIZE_SIZE: usize = 4;
+        const STRATEGY_SIZE: usize = 4;
+        const SIZE_CLASSES_TAG_SIZE: usize = 4;
+        const SIZE_SIZE: usize = 4;
+
+        Size::from_bytes(VEC_LEN_SIZE + ALLOCATION_SIZE * allocation_count +
+                         (VEC_LEN_SIZE + ALLOCATION_SIZE * free_block_count) * 2 +
+                         TOTAL_SIZE_SIZE +
+                         STRATEGY_SIZE +
+                         SIZE_CLASSES_TAG_SIZE +
+                         VEC_LEN_SIZE + SIZE_SIZE * size_class_count +
+                         SIZE_SIZE)
+    }
+
+    pub(crate) fn serialized_size(&self) -> Size {
+        Allocator::serialized_size_for(self.allocations.len(),
+                                       self.free_by_addr.len(),
+                                       self.size_class_count())
+    }
+
+    pub(crate) fn size_class_count(&self) -> usize {
+        match self.size_classes {
+            SizeClasses::Table(ref classes) => classes.len(),
+            SizeClasses::Exact |
+            SizeClasses::PowerOfTwo => 0,
+        }
+    }
+
+    pub(crate) fn has_live_mem_refs(&self) -> bool {
+        !self.live_mem_refs.is_empty()
+    }
+
+    // Whether any live reference overlaps the given range.
+    pub(crate) fn is_borrowed(&self, addr: Address, len: Size) -> bool {
+        let range = LiveMemRef::new(addr, len, true);
+        self.live_mem_refs.iter().any(|lmr| lmr.conflicts_with(&range))
+    }
+
+    pub(crate) fn allocation_at(&self, addr: Address) -> Option<Allocation> {
+        self.find_alloc_by_address(addr)
+            .ok()
+            .map(|index| self.allocations[index])
+    }
+
+    pub(crate) fn allocation_count(&self) -> usize {
+        self.allocations.len()
+    }
+
+    pub(crate) fn free_block_count(&self) -> usize {
+        self.free_by_addr.len()
     }
 }
 
@@ -357,6 +914,10 @@ impl LiveMemRef {
         }
     }
 
+    pub(crate) fn start(&self) -> Address {
+        self.start
+    }
+
     fn conflicts_with(&self, other: &LiveMemRef) -> bool {
         if !self.mutable && !other.mutable {
             // two shared slices never conflict
@@ -368,6 +929,16 @@ impl LiveMemRef {
     }
 }
 
+// Computes `a - b` where the allocator's bookkeeping guarantees that `a` is
+// not smaller than `b`. If it is, the free lists are inconsistent and
+// continuing with a wrapped-around size would only corrupt them further.
+#[inline]
+fn size_diff(a: Size, b: Size, what: &str) -> Size {
+    a.checked_sub(b).unwrap_or_else(|| {
+        panic!("Size underflow in {}: {} - {}", what, a.as_u32(), b.as_u32())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -436,4 +1007,463 @@ mod tests {
         assert_eq!(allocator.free_by_size, vec![Allocation::new(Address(10), Size(20)),
                                                 Allocation::new(Address(40), Size(60))]);
     }
+
+    #[test]
+    #[should_panic(expected = "Requested 101 bytes, store is only 100 bytes.")]
+    fn alloc_larger_than_total_size() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.alloc(Size(101));
+    }
+
+    #[test]
+    fn verify_healthy() {
+        let mut allocator = Allocator::new(Size(100));
+        assert_eq!(allocator.verify(), Ok(()));
+
+        let alloc1 = allocator.alloc(Size(10));
+        let alloc2 = allocator.alloc(Size(20));
+        allocator.alloc(Size(10));
+        let alloc4 = allocator.alloc(Size(5));
+        allocator.alloc(Size(10));
+        assert_eq!(allocator.verify(), Ok(()));
+
+        allocator.free(alloc2);
+        allocator.free(alloc4);
+        allocator.free(alloc1);
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    #[test]
+    fn verify_corrupted() {
+        let create_allocator = || {
+            let mut allocator = Allocator::new(Size(100));
+            allocator.alloc(Size(10));
+            let alloc = allocator.alloc(Size(10));
+            allocator.alloc(Size(10));
+            allocator.free(alloc);
+            assert_eq!(allocator.verify(), Ok(()));
+            allocator
+        };
+
+        // Allocation overlapping a free block
+        let mut allocator = create_allocator();
+        allocator.allocations[0].size = Size(15);
+        assert!(allocator.verify().unwrap_err().contains("overlaps"));
+
+        // Leaked bytes
+        let mut allocator = create_allocator();
+        allocator.allocations[0].size = Size(5);
+        assert!(allocator.verify().unwrap_err().contains("neither allocated nor free"));
+
+        // free_by_size out of sync with free_by_addr
+        let mut allocator = create_allocator();
+        allocator.free_by_size.pop();
+        assert!(allocator.verify().unwrap_err().contains("differ"));
+
+        // free_by_addr out of order
+        let mut allocator = create_allocator();
+        allocator.free_by_addr.swap(0, 1);
+        assert!(allocator.verify().unwrap_err().contains("not sorted"));
+
+        // Wrong total size
+        let mut allocator = create_allocator();
+        allocator.total_size = Size(200);
+        assert!(allocator.verify().unwrap_err().contains("total size"));
+
+        // Adjacent free blocks that should have been coalesced
+        let mut allocator = create_allocator();
+        let alloc = allocator.allocations.remove(1);
+        allocator.free_by_addr.insert(1, alloc);
+        allocator.free_by_size.push(alloc);
+        allocator.free_by_size.sort_by_key(|alloc| alloc.size);
+        assert!(allocator.verify().unwrap_err().contains("not merged"));
+    }
+
+    #[test]
+    fn free_merges_both_neighbors() {
+        let mut allocator = Allocator::new(Size(100));
+        let alloc1 = allocator.alloc(Size(10));
+        let alloc2 = allocator.alloc(Size(10));
+        let alloc3 = allocator.alloc(Size(10));
+        allocator.alloc(Size(10));
+
+        allocator.free(alloc1);
+        allocator.free(alloc3);
+        assert_eq!(allocator.free_by_addr.len(), 3);
+
+        allocator.free(alloc2);
+        assert_eq!(allocator.free_by_addr[0], Allocation::new(Address(0), Size(30)));
+        assert_eq!(allocator.free_by_addr.len(), 2);
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    fn alloc_with_holes(strategy: AllocStrategy) -> Allocator {
+        let mut allocator = Allocator::with_strategy(Size(100), strategy);
+        let alloc1 = allocator.alloc(Size(20));
+        allocator.alloc(Size(10));
+        let alloc3 = allocator.alloc(Size(8));
+        allocator.alloc(Size(10));
+        allocator.free(alloc1);
+        allocator.free(alloc3);
+
+        assert_eq!(allocator.free_by_addr, vec![Allocation::new(Address(0), Size(20)),
+                                                Allocation::new(Address(30), Size(8)),
+                                                Allocation::new(Address(48), Size(52))]);
+        allocator
+    }
+
+    #[test]
+    fn best_fit() {
+        let mut allocator = alloc_with_holes(AllocStrategy::BestFit);
+        assert_eq!(allocator.alloc(Size(8)), Allocation::new(Address(30), Size(8)));
+        assert_eq!(allocator.alloc(Size(21)), Allocation::new(Address(48), Size(21)));
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    #[test]
+    fn first_fit() {
+        let mut allocator = alloc_with_holes(AllocStrategy::FirstFit);
+        assert_eq!(allocator.alloc(Size(8)), Allocation::new(Address(0), Size(8)));
+        assert_eq!(allocator.alloc(Size(12)), Allocation::new(Address(8), Size(12)));
+        assert_eq!(allocator.alloc(Size(21)), Allocation::new(Address(48), Size(21)));
+        assert_eq!(allocator.free_by_addr, vec![Allocation::new(Address(30), Size(8)),
+                                                Allocation::new(Address(69), Size(31))]);
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    #[test]
+    fn truncate() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.alloc(Size(10));
+        let alloc2 = allocator.alloc(Size(10));
+        let alloc3 = allocator.alloc(Size(10));
+        allocator.free(alloc2);
+        allocator.free(alloc3);
+
+        allocator.truncate(Size(15));
+        assert_eq!(allocator.total_size(), Size(15));
+        assert_eq!(allocator.free_by_addr, vec![Allocation::new(Address(10), Size(5))]);
+        assert_eq!(allocator.verify(), Ok(()));
+
+        allocator.truncate(Size(10));
+        assert_eq!(allocator.free_by_addr, vec![]);
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "there are allocations up to")]
+    fn truncate_below_max_addr() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.alloc(Size(10));
+        allocator.truncate(Size(5));
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        use memory::{Memory, MemStore};
+
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::FirstFit);
+        allocator.alloc(Size(10));
+        let alloc = allocator.alloc(Size(10));
+        allocator.alloc(Size(10));
+        allocator.free(alloc);
+
+        let memory = Memory::new(MemStore::new(1000));
+        let storage_alloc = memory.alloc(allocator.serialized_size());
+        allocator.write_at(&memory, storage_alloc.addr);
+
+        let mut reader = StorageReader::new(&memory, storage_alloc.addr);
+        let read_back = Allocator::read(&mut reader);
+        assert_eq!(reader.addr(), storage_alloc.end());
+
+        assert_eq!(read_back.allocations, allocator.allocations);
+        assert_eq!(read_back.free_by_addr, allocator.free_by_addr);
+        assert_eq!(read_back.free_by_size, allocator.free_by_size);
+        assert_eq!(read_back.total_size, allocator.total_size);
+        assert_eq!(read_back.strategy, AllocStrategy::FirstFit);
+    }
+
+    #[test]
+    fn clone() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.alloc(Size(10));
+        let alloc = allocator.alloc(Size(20));
+        allocator.alloc(Size(30));
+        allocator.free(alloc);
+        allocator.register_mem_ref(Address(0), Size(10), false);
+
+        let clone = allocator.clone();
+        assert_eq!(clone.allocations, allocator.allocations);
+        assert_eq!(clone.free_by_addr, allocator.free_by_addr);
+        assert_eq!(clone.free_by_size, allocator.free_by_size);
+        assert_eq!(clone.total_size, allocator.total_size);
+        assert!(allocator.has_live_mem_refs());
+        assert!(!clone.has_live_mem_refs());
+        assert_eq!(clone.verify(), Ok(()));
+    }
+
+    #[test]
+    fn stats() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.alloc(Size(10));
+        let alloc = allocator.alloc(Size(20));
+        allocator.alloc(Size(30));
+        allocator.free(alloc);
+
+        assert_eq!(allocator.stats(), AllocatorStats {
+            total_size: Size(100),
+            allocated_bytes: Size(40),
+            free_bytes: Size(60),
+            allocation_count: 2,
+            free_block_count: 2,
+            largest_free_block: Size(40),
+        });
+    }
+
+    #[test]
+    fn bump() {
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::Bump);
+        let a = allocator.alloc(Size(10));
+        let b = allocator.alloc(Size(20));
+        allocator.free(a);
+
+        // The hole left by `a` is not reused
+        let c = allocator.alloc(Size(5));
+        assert_eq!(c.addr, b.end());
+        let d = allocator.alloc(Size(10));
+        assert_eq!(d.addr, c.end());
+        assert_eq!(allocator.verify(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not allocate memory of size 70")]
+    fn bump_out_of_memory() {
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::Bump);
+        let a = allocator.alloc(Size(50));
+        allocator.alloc(Size(10));
+        allocator.free(a);
+        allocator.alloc(Size(70));
+    }
+
+    #[test]
+    fn regions() {
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::FirstFit);
+        let a = allocator.alloc(Size(10));
+        let b = allocator.alloc(Size(20));
+        let c = allocator.alloc(Size(30));
+        let d = allocator.alloc(Size(15));
+        allocator.free(b);
+        allocator.free(d);
+
+        let free: Vec<_> = allocator.free_regions().collect();
+        assert_eq!(free, vec![
+            Allocation::new(Address(10), Size(20)),
+            Allocation::new(Address(60), Size(40)),
+        ]);
+
+        let allocated: Vec<_> = allocator.allocated_regions().collect();
+        assert_eq!(allocated, vec![a, c]);
+    }
+
+    #[test]
+    fn can_alloc_bump() {
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::Bump);
+        let alloc = allocator.alloc(Size(50));
+        allocator.alloc(Size(30));
+        allocator.free(alloc);
+
+        // The largest free block is before the last allocation, where bump
+        // allocation never goes.
+        assert_eq!(allocator.largest_free_block(), Size(50));
+        assert!(allocator.can_alloc(Size(20)));
+        assert!(!allocator.can_alloc(Size(21)));
+
+        allocator.set_strategy(AllocStrategy::FirstFit);
+        assert!(allocator.can_alloc(Size(50)));
+    }
+
+    #[test]
+    fn can_alloc_size_classes() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.set_size_classes(SizeClasses::PowerOfTwo);
+        allocator.alloc(Size(64));
+
+        assert_eq!(allocator.largest_free_block(), Size(36));
+        assert!(allocator.can_alloc(Size(32)));
+        assert!(!allocator.can_alloc(Size(33)));
+    }
+
+    #[test]
+    fn largest_free_block() {
+        let mut allocator = Allocator::new(Size(100));
+        assert_eq!(allocator.largest_free_block(), Size(100));
+
+        allocator.alloc(Size(10));
+        let alloc = allocator.alloc(Size(30));
+        allocator.alloc(Size(50));
+        assert_eq!(allocator.largest_free_block(), Size(10));
+
+        allocator.free(alloc);
+        assert_eq!(allocator.largest_free_block(), Size(30));
+
+        allocator.alloc(Size(30));
+        allocator.alloc(Size(10));
+        assert_eq!(allocator.largest_free_block(), Size(0));
+    }
+
+    #[test]
+    fn allocation_contains() {
+        let alloc = Allocation::new(Address(10), Size(5));
+
+        assert!(!alloc.contains(Address(9)));
+        assert!(alloc.contains(Address(10)));
+        assert!(alloc.contains(Address(14)));
+        assert!(!alloc.contains(Address(15)));
+
+        assert!(alloc.contains_range(Address(10), Size(5)));
+        assert!(alloc.contains_range(Address(12), Size(3)));
+        assert!(alloc.contains_range(Address(15), Size(0)));
+        assert!(!alloc.contains_range(Address(12), Size(4)));
+        assert!(!alloc.contains_range(Address(9), Size(2)));
+    }
+
+    #[test]
+    fn allocation_split_at() {
+        let alloc = Allocation::new(Address(10), Size(5));
+        assert_eq!(alloc.split_at(Size(2)),
+                   (Allocation::new(Address(10), Size(2)), Allocation::new(Address(12), Size(3))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot split")]
+    fn allocation_split_at_out_of_range() {
+        Allocation::new(Address(10), Size(5)).split_at(Size(5));
+    }
+
+    #[test]
+    fn allocation_try_merge() {
+        let a = Allocation::new(Address(10), Size(5));
+        let b = Allocation::new(Address(15), Size(3));
+        let c = Allocation::new(Address(19), Size(3));
+
+        assert_eq!(a.try_merge(&b), Some(Allocation::new(Address(10), Size(8))));
+        assert_eq!(b.try_merge(&a), Some(Allocation::new(Address(10), Size(8))));
+        assert_eq!(a.try_merge(&c), None);
+        assert_eq!(b.try_merge(&c), None);
+    }
+
+    #[test]
+    fn allocation_overlaps() {
+        let alloc = Allocation::new(Address(10), Size(5));
+
+        assert!(alloc.overlaps(&alloc));
+        assert!(alloc.overlaps(&Allocation::new(Address(14), Size(10))));
+        assert!(alloc.overlaps(&Allocation::new(Address(0), Size(11))));
+        assert!(alloc.overlaps(&Allocation::new(Address(11), Size(1))));
+        assert!(!alloc.overlaps(&Allocation::new(Address(15), Size(10))));
+        assert!(!alloc.overlaps(&Allocation::new(Address(0), Size(10))));
+    }
+
+    #[test]
+    fn size_classes() {
+        let mut allocator = Allocator::new(Size(1000));
+        allocator.set_size_classes(SizeClasses::PowerOfTwo);
+
+        assert_eq!(allocator.size_class_for(Size(1)), Size(1));
+        assert_eq!(allocator.size_class_for(Size(17)), Size(32));
+        assert_eq!(allocator.size_class_for(Size(32)), Size(32));
+        // Rounding up would exceed the total size
+        assert_eq!(allocator.size_class_for(Size(600)), Size(600));
+
+        let allocations: Vec<_> = (0 .. 20).map(|i| allocator.alloc(Size(17 + i % 16))).collect();
+        assert!(allocations.iter().all(|alloc| alloc.size == Size(32)));
+
+        // Free every other allocation, interleaved with live ones so that
+        // freed blocks cannot merge.
+        let freed: Vec<_> = allocations.iter().cloned().step_by(2).collect();
+        for &alloc in &freed {
+            allocator.free(alloc);
+        }
+        allocator.verify().unwrap();
+
+        // Allocations of the same class fit the holes exactly
+        let mut reused: Vec<_> = (0 .. freed.len()).map(|_| allocator.alloc(Size(30))).collect();
+        reused.sort_by_key(|alloc| alloc.addr);
+        assert_eq!(reused, freed);
+        assert_eq!(allocator.free_regions().count(), 1);
+        allocator.verify().unwrap();
+
+        allocator.set_size_classes(SizeClasses::Table(vec![Size(8), Size(24)]));
+        assert_eq!(allocator.size_class_for(Size(3)), Size(8));
+        assert_eq!(allocator.size_class_for(Size(9)), Size(24));
+        assert_eq!(allocator.size_class_for(Size(25)), Size(25));
+    }
+
+    #[test]
+    fn min_split_size() {
+        for &strategy in &[AllocStrategy::BestFit, AllocStrategy::FirstFit] {
+            let mut allocator = Allocator::with_strategy(Size(100), strategy);
+            allocator.set_min_split_size(Size(16));
+
+            let alloc1 = allocator.alloc(Size(40));
+            let alloc2 = allocator.alloc(Size(40));
+            allocator.free(alloc1);
+
+            // Splitting the 40 byte hole would leave a 5 byte sliver
+            let alloc3 = allocator.alloc(Size(35));
+            assert_eq!(alloc3, Allocation::new(Address(0), Size(40)));
+            assert_eq!(allocator.free_regions().collect::<Vec<_>>(),
+                       vec![Allocation::new(Address(80), Size(20))]);
+
+            // Large enough remainders are still split off
+            let alloc4 = allocator.alloc(Size(4));
+            assert_eq!(alloc4, Allocation::new(Address(80), Size(4)));
+            assert_eq!(allocator.free_regions().count(), 1);
+            allocator.verify().unwrap();
+
+            allocator.free(alloc3);
+            allocator.free(alloc2);
+            allocator.free(alloc4);
+            assert_eq!(allocator.free_regions().collect::<Vec<_>>(),
+                       vec![Allocation::new(Address(0), Size(100))]);
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Allocations differ in size")]
+    fn min_split_size_free_wrong_size() {
+        let mut allocator = Allocator::new(Size(100));
+        allocator.set_min_split_size(Size(16));
+        allocator.alloc(Size(40));
+        let alloc = allocator.alloc(Size(50));
+        assert_eq!(alloc.size, Size(60));
+        allocator.free(Allocation::new(alloc.addr, Size(30)));
+    }
+
+    #[test]
+    fn alloc_exact_fit() {
+        // Every allocation here uses up a free block completely, so the
+        // remaining space is exactly zero.
+        let mut allocator = alloc_with_holes(AllocStrategy::FirstFit);
+        assert_eq!(allocator.alloc(Size(20)), Allocation::new(Address(0), Size(20)));
+        assert_eq!(allocator.alloc(Size(8)), Allocation::new(Address(30), Size(8)));
+        assert_eq!(allocator.free_by_addr, vec![Allocation::new(Address(48), Size(52))]);
+        assert_eq!(allocator.verify(), Ok(()));
+
+        let mut allocator = alloc_with_holes(AllocStrategy::BestFit);
+        allocator.set_min_split_size(Size(4));
+        assert_eq!(allocator.alloc(Size(52)), Allocation::new(Address(48), Size(52)));
+        assert_eq!(allocator.alloc(Size(17)), Allocation::new(Address(0), Size(20)));
+        assert_eq!(allocator.alloc(Size(8)), Allocation::new(Address(30), Size(8)));
+        assert_eq!(allocator.largest_free_block(), Size(0));
+        assert_eq!(allocator.stats().allocated_bytes, Size(100));
+        assert_eq!(allocator.verify(), Ok(()));
+
+        let mut allocator = Allocator::with_strategy(Size(100), AllocStrategy::Bump);
+        allocator.alloc(Size(40));
+        assert_eq!(allocator.alloc(Size(60)), Allocation::new(Address(40), Size(60)));
+        assert_eq!(allocator.stats().free_bytes, Size(0));
+        assert_eq!(allocator.verify(), Ok(()));
+    }
 }
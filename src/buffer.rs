@@ -1,13 +1,26 @@
 
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use memory::Size;
 
 pub struct Buffer<'data> {
     data: &'data mut Vec<u8>,
     start: usize,
+
+    // In debug builds, every buffer knows how deeply it is nested and the
+    // provider knows the nesting level of the innermost live buffer, so that
+    // writes to a buffer with a live (e.g. leaked) sub-buffer are detected.
+    // An atomic is used only to keep `BufferProvider` `Sync`.
+    #[cfg(debug_assertions)]
+    depth: usize,
+    #[cfg(debug_assertions)]
+    live_depth: &'data AtomicUsize,
 }
 
 pub struct BufferProvider {
     data: Vec<u8>,
+    #[cfg(debug_assertions)]
+    live_depth: AtomicUsize,
 }
 
 impl BufferProvider {
@@ -15,13 +28,33 @@ impl BufferProvider {
      pub fn new() -> BufferProvider {
         BufferProvider {
             data: Vec::new(),
+            #[cfg(debug_assertions)]
+            live_depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> BufferProvider {
+        BufferProvider {
+            data: Vec::with_capacity(capacity),
+            #[cfg(debug_assertions)]
+            live_depth: AtomicUsize::new(0),
         }
     }
 
     pub fn get_buffer(&mut self) -> Buffer {
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(self.live_depth.load(Ordering::Relaxed), 0, "BufferProvider still has a live buffer");
+            self.live_depth.store(1, Ordering::Relaxed);
+        }
+
         Buffer {
             data: &mut self.data,
             start: 0,
+            #[cfg(debug_assertions)]
+            depth: 1,
+            #[cfg(debug_assertions)]
+            live_depth: &self.live_depth,
         }
     }
 }
@@ -29,11 +62,13 @@ impl BufferProvider {
 impl<'data> Buffer<'data> {
     #[inline(always)]
     pub fn write_byte(&mut self, byte: u8) {
+        self.assert_no_live_sub_buffer();
         self.data.push(byte);
     }
 
     #[inline(always)]
     pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.assert_no_live_sub_buffer();
         self.data.extend_from_slice(bytes);
     }
 
@@ -45,23 +80,118 @@ impl<'data> Buffer<'data> {
     pub fn start_sub_buffer<'s>(&'s mut self) -> Buffer<'s>
         where 'data: 's
     {
+        self.assert_no_live_sub_buffer();
+
         let start = self.data.len();
 
+        #[cfg(debug_assertions)]
+        self.live_depth.store(self.depth + 1, Ordering::Relaxed);
+
         Buffer {
             data: self.data,
             start,
+            #[cfg(debug_assertions)]
+            depth: self.depth + 1,
+            #[cfg(debug_assertions)]
+            live_depth: self.live_depth,
         }
     }
 
     pub fn bytes(&self) -> &[u8] {
         &self.data[self.start ..]
     }
+
+    /// Allows overwriting bytes that have already been written, e.g. to fill
+    /// in a length prefix once the length is known.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.assert_no_live_sub_buffer();
+        &mut self.data[self.start ..]
+    }
+
+    /// Discards everything written to this buffer, keeping the contents of
+    /// any parent buffer intact.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.assert_no_live_sub_buffer();
+        self.data.truncate(self.start);
+    }
+
+    #[inline(always)]
+    fn assert_no_live_sub_buffer(&self) {
+        #[cfg(debug_assertions)]
+        assert!(self.live_depth.load(Ordering::Relaxed) == self.depth,
+                "Buffer used while one of its sub-buffers is still live");
+    }
 }
 
 impl<'data> Drop for Buffer<'data> {
     fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            // Don't panic while already panicking.
+            if !::std::thread::panicking() {
+                self.assert_no_live_sub_buffer();
+            }
+            self.live_depth.store(self.depth - 1, Ordering::Relaxed);
+        }
+
         self.data.truncate(self.start);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_provider() {
+        let mut provider = BufferProvider::with_capacity(64);
+        assert!(provider.data.capacity() >= 64);
+
+        {
+            let mut buffer = provider.get_buffer();
+            buffer.write_bytes(b"first record");
+            assert_eq!(buffer.bytes(), b"first record");
+        }
+
+        {
+            let mut buffer = provider.get_buffer();
+            assert_eq!(buffer.bytes(), b"");
+            buffer.write_bytes(b"second");
+            assert_eq!(buffer.bytes(), b"second");
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let mut provider = BufferProvider::new();
+        let mut buffer = provider.get_buffer();
+        buffer.write_bytes(b"parent");
 
+        {
+            let mut sub_buffer = buffer.start_sub_buffer();
+            sub_buffer.write_bytes(b"child");
+            sub_buffer.clear();
+            assert_eq!(sub_buffer.bytes(), b"");
+            sub_buffer.write_byte(b'!');
+            assert_eq!(sub_buffer.bytes(), b"!");
+        }
+
+        assert_eq!(buffer.bytes(), b"parent");
+        buffer.clear();
+        assert_eq!(buffer.bytes(), b"");
+        assert_eq!(buffer.len(), Size(0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sub-buffers is still live")]
+    fn leaked_sub_buffer() {
+        let mut provider = BufferProvider::new();
+        let mut buffer = provider.get_buffer();
+
+        ::std::mem::forget(buffer.start_sub_buffer());
+
+        buffer.write_bytes(b"parent");
+    }
+}
@@ -0,0 +1,188 @@
+use allocator::Allocation;
+use memory::{Memory, Storage, Address, Size, AllocatorCell};
+
+/// A range of bytes that differs between two storages, together with the new
+/// contents of that range.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Patch {
+    pub addr: Address,
+    pub bytes: Vec<u8>,
+}
+
+// Differing ranges that are separated by fewer equal bytes than this are
+// merged into a single patch, since every patch comes with some overhead.
+const MIN_GAP: usize = 16;
+
+/// Computes the byte ranges that have to be written to `old` in order to make
+/// its contents equal to `new`, e.g. for incremental backups of persisted
+/// databases.
+///
+/// Only the ranges allocated in `new` are compared, including the header and
+/// the footer, since the contents of free space don't matter. `new` therefore
+/// has to come from `Database::load_memory()`. Both storages must have the
+/// same size.
+pub fn diff<S: Storage, S2: Storage>(old: &Memory<S>, new: &Memory<S2>) -> Vec<Patch> {
+    assert_eq!(old.size(), new.size(), "Cannot diff storages of different size");
+
+    let regions: Vec<Allocation> = new.allocator.lock().allocated_regions().collect();
+    assert!(regions.first().map(|region| region.addr) == Some(Address(0)),
+            "diff() needs the allocations of a persisted database, see Database::load_memory()");
+
+    let (old_bytes, new_bytes) = unsafe {
+        (old.storage().get_bytes(Address(0), old.size()),
+         new.storage().get_bytes(Address(0), new.size()))
+    };
+
+    let mut patches: Vec<Patch> = vec![];
+
+    for region in regions {
+        let mut index = region.start().offset();
+        let end = region.end().offset();
+
+        while index < end {
+            if old_bytes[index] == new_bytes[index] {
+                index += 1;
+                continue
+            }
+
+            let start = index;
+            while index < end && old_bytes[index] != new_bytes[index] {
+                index += 1;
+            }
+
+            match patches.last_mut() {
+                Some(ref mut last) if start - (last.addr.offset() + last.bytes.len()) < MIN_GAP => {
+                    let last_end = last.addr.offset() + last.bytes.len();
+                    last.bytes.extend_from_slice(&new_bytes[last_end .. index]);
+                }
+                _ => {
+                    patches.push(Patch {
+                        addr: Address::from_offset(start),
+                        bytes: new_bytes[start .. index].to_vec(),
+                    });
+                }
+            }
+        }
+    }
+
+    patches
+}
+
+/// Writes `patches`, as computed by `diff()`, to `storage`. This works on the
+/// storage rather than on a `Memory` because the allocator state of a
+/// `Memory` would not match the patched contents anymore. Open the result
+/// via `Database::open()`.
+pub fn apply_patches<S: Storage>(storage: &mut S, patches: &[Patch]) {
+    assert!(!S::IS_READONLY);
+
+    for patch in patches {
+        let len = Size::from_bytes(patch.bytes.len());
+        assert!(patch.addr + len <= Address(0) + storage.size(),
+                "Patch at {} exceeds storage", patch.addr);
+
+        unsafe {
+            storage.get_bytes_mut(patch.addr, len).copy_from_slice(&patch.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MemStore;
+    use {Database, RecordId};
+
+    fn copy_store(storage: &MemStore) -> MemStore {
+        let copy = MemStore::new(storage.size().bytes());
+        unsafe {
+            copy.get_bytes_mut(Address(0), storage.size())
+                .copy_from_slice(storage.get_bytes(Address(0), storage.size()));
+        }
+        copy
+    }
+
+    #[test]
+    fn diff_and_apply() {
+        let mut db = Database::init(Memory::new(MemStore::new(10000)));
+        let ids: Vec<RecordId> = (0 .. 10u8).map(|i| {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(&[i; 32]))
+        }).collect();
+
+        let old = db.persist();
+        let db = Database::open(copy_store(&old)).unwrap();
+
+        {
+            let record = db.record(ids[3]);
+            db.memory.get_bytes_mut(record.addr + Size(8), Size(4)).copy_from_slice(b"abcd");
+        }
+
+        let new = Database::load_memory(db.persist()).unwrap();
+        let mut old = Memory::new(old);
+
+        let patches = diff(&old, &new);
+        assert!(!patches.is_empty());
+        assert!(patches.iter().any(|patch| patch.bytes.windows(4).any(|w| w == b"abcd")));
+
+        apply_patches(&mut old.storage, &patches);
+        assert!(diff(&old, &new).is_empty());
+
+        let db = Database::open(old.storage).unwrap();
+        assert_eq!(&db.get_record(ids[3])[8 .. 12], b"abcd");
+        assert_eq!(&db.get_record(ids[4])[..], &[4; 32][..]);
+    }
+
+    #[test]
+    fn free_space_is_skipped() {
+        let mut db = Database::init(Memory::new(MemStore::new(10000)));
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[1; 32]));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[2; 32]));
+        let a_addr = db.record(a).addr;
+        db.delete_record(a);
+
+        let storage = db.persist();
+        let old = Memory::new(copy_store(&storage));
+        let new = Database::load_memory(storage).unwrap();
+        assert!(diff(&old, &new).is_empty());
+
+        // Neither the old contents of `a` nor the untouched space at the end
+        // of the storage are compared.
+        let free: Vec<_> = new.allocator.lock().free_regions().collect();
+        assert!(free.iter().any(|region| region.contains(a_addr)));
+        for region in free {
+            unsafe {
+                for byte in new.storage().get_bytes_mut(region.addr, region.size) {
+                    *byte = 0xff;
+                }
+            }
+        }
+
+        assert!(diff(&old, &new).is_empty());
+        assert_eq!(&Database::open(new.storage).unwrap().get_record(b)[..], &[2; 32][..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "load_memory")]
+    fn diff_requires_allocations() {
+        let old = Memory::new(MemStore::new(100));
+        let new = Memory::new(MemStore::new(100));
+        diff(&old, &new);
+    }
+
+    #[test]
+    fn nearby_changes_are_merged() {
+        let old = Memory::new(MemStore::new(100));
+        let new = Memory::new(MemStore::new(100));
+        new.alloc(Size(100));
+
+        unsafe {
+            new.storage().get_bytes_mut(Address(10), Size(1))[0] = 1;
+            new.storage().get_bytes_mut(Address(12), Size(1))[0] = 2;
+            new.storage().get_bytes_mut(Address(80), Size(1))[0] = 3;
+        }
+
+        assert_eq!(diff(&old, &new), vec![
+            Patch { addr: Address(10), bytes: vec![1, 0, 2] },
+            Patch { addr: Address(80), bytes: vec![3] },
+        ]);
+    }
+}
@@ -2,18 +2,195 @@
 use memory::*;
 use persist::*;
 use allocator::*;
+use byteorder::ByteOrder;
 
 const FOOTER_MAGIC: [u8; 4] = [b'D', b'I', b'B', b'S'];
 
-pub fn write_footer<S: Storage>(memory: Memory<S>) {
-    // // Write footer magic
-    // memory.get_bytes(addr, Size::from_usize(FOOTER_MAGIC.len()))
-    //        .copy_from_slice(&FOOTER_MAGIC);
+// Layout:
+//
+// footer_magic: [u8; 4]
+// record_table: Allocation
+// sequence_table: Allocation (zero-sized if there is none)
+// structures: Vec<StructureRef>
+// checksum: u64
+// append_only: u32 (0 or 1)
+// allocator: Allocator
+//
+// The footer is itself an allocation, so the persisted allocator state
+// contains it. It is freed again when the database is opened.
+pub struct Footer {
+    pub roots: Roots,
+    pub allocator: Allocator,
+}
+
+// The entry points into the persisted data, i.e. everything in the footer
+// except for the allocator state.
+pub struct Roots {
+    pub record_table: Allocation,
+    pub sequence_table: Option<Allocation>,
+    pub structures: Vec<StructureRef>,
+    pub checksum: u64,
+    pub append_only: bool,
+}
+
+/// What kind of data a top-level structure in a database holds.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum StructureKind {
+    RecordTable,
+    HashTable,
+    UserBlob,
+}
+
+/// An entry in the directory of top-level structures of a database, see
+/// `Database::structures()`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct StructureRef {
+    pub kind: StructureKind,
+    pub allocation: Allocation,
+}
+
+impl Serialize for StructureRef {
+    #[inline]
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+        let kind: u32 = match self.kind {
+            StructureKind::RecordTable => 0,
+            StructureKind::HashTable => 1,
+            StructureKind::UserBlob => 2,
+        };
+
+        kind.write(writer);
+        self.allocation.write(writer);
+    }
+}
+
+impl Deserialize for StructureRef {
+    #[inline]
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> StructureRef {
+        StructureRef::try_read(reader).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl FixedSizeSerialize for StructureRef {
+    const SERIALIZED_SIZE: Size = STRUCTURE_REF_SIZE;
+}
+
+impl StructureRef {
+    /// Like `Deserialize::read()` but returns an error for an unknown kind.
+    pub fn try_read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Result<StructureRef, String> {
+        reader.check_remaining(STRUCTURE_REF_SIZE)?;
+
+        let kind = match u32::read(reader) {
+            0 => StructureKind::RecordTable,
+            1 => StructureKind::HashTable,
+            2 => StructureKind::UserBlob,
+            other => return Err(format!("Invalid structure kind {}", other)),
+        };
+
+        Ok(StructureRef {
+            kind,
+            allocation: Allocation::read(reader),
+        })
+    }
+}
+
+const FOOTER_MAGIC_SIZE: Size = Size(4);
+const ALLOCATION_SIZE: Size = Size(8);
+const STRUCTURE_REF_SIZE: Size = Size(12);
+const CHECKSUM_SIZE: Size = Size(8);
+const APPEND_ONLY_SIZE: Size = Size(4);
+
+// Checksum over all allocations except for the header and the footer, which
+// are rewritten every time the database is persisted.
+pub fn content_checksum<S: Storage>(memory: &Memory<S>, footer_addr: Option<Address>) -> u64 {
+    memory.checksum_filtered(|allocation| {
+        allocation.addr != Address(0) && Some(allocation.addr) != footer_addr
+    })
+}
+
+pub fn write_footer<S: Storage>(memory: &Memory<S>, roots: &Roots) -> Allocation {
+    // Allocating the footer adds at most one allocation to the allocator,
+    // so reserve space for that.
+    let allocator_size = {
+        let allocator = memory.allocator.lock();
+        Allocator::serialized_size_for(allocator.allocation_count() + 1,
+                                       allocator.free_block_count(),
+                                       allocator.size_class_count())
+    };
+
+    let structures_size = Size(4) + STRUCTURE_REF_SIZE * roots.structures.len();
+
+    let footer = memory.alloc(FOOTER_MAGIC_SIZE +
+                              ALLOCATION_SIZE * 2u32 +
+                              structures_size +
+                              CHECKSUM_SIZE +
+                              APPEND_ONLY_SIZE +
+                              allocator_size);
+
+    // Write footer magic
+    memory.get_bytes_mut(footer.addr, FOOTER_MAGIC_SIZE)
+          .copy_from_slice(&FOOTER_MAGIC);
+
+    let mut writer = StorageWriter::new(memory, footer.addr + FOOTER_MAGIC_SIZE);
+
+    // Write record table
+    roots.record_table.write(&mut writer);
+
+    // Write sequence table
+    roots.sequence_table.unwrap_or(Allocation::new(Address(0), Size(0))).write(&mut writer);
+
+    // Write structure directory
+    roots.structures.write(&mut writer);
+
+    // Write checksum
+    roots.checksum.write(&mut writer);
+
+    // Write append-only flag
+    (roots.append_only as u32).write(&mut writer);
+
+    // Write allocator
+    let allocator = memory.allocator.lock().clone();
+    assert!(allocator.serialized_size() <= allocator_size);
+    allocator.write(&mut writer);
+
+    footer
+}
+
+// The footer comes from storage that may be corrupt, so nothing read here is
+// allowed to panic.
+pub fn read_footer<S: Storage>(memory: &Memory<S>, addr: Address) -> Result<Footer, String> {
+    StorageReader::new(memory, addr).check_remaining(FOOTER_MAGIC_SIZE + ALLOCATION_SIZE * 2u32)?;
+    if *memory.get_bytes(addr, FOOTER_MAGIC_SIZE) != FOOTER_MAGIC[..] {
+        return Err("Footer magic does not match.".to_string());
+    }
+
+    let mut reader = StorageReader::new(memory, addr + FOOTER_MAGIC_SIZE);
+
+    let record_table = Allocation::read(&mut reader);
+    let sequence_table = Allocation::read(&mut reader);
 
-    // let mut writer = StorageWriter::new(storage, addr + Size::from_usize(FOOTER_MAGIC.len()));
+    let structure_count = reader.read_vec_len::<StructureRef>()?;
+    let mut structures = Vec::with_capacity(structure_count);
+    for _ in 0 .. structure_count {
+        structures.push(StructureRef::try_read(&mut reader)?);
+    }
 
-    // // Write allocator
-    // allocator.write(&mut writer);
+    reader.check_remaining(CHECKSUM_SIZE + APPEND_ONLY_SIZE)?;
+    let checksum = u64::read(&mut reader);
+    let append_only = match u32::read(&mut reader) {
+        0 => false,
+        1 => true,
+        other => return Err(format!("Invalid append-only flag {}", other)),
+    };
+    let allocator = Allocator::try_read(&mut reader)?;
 
-    // Write record index
+    Ok(Footer {
+        roots: Roots {
+            record_table,
+            sequence_table: if sequence_table.size == Size(0) { None } else { Some(sequence_table) },
+            structures,
+            checksum,
+            append_only,
+        },
+        allocator,
+    })
 }
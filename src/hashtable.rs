@@ -1,17 +1,43 @@
 
 use std::mem;
 use std::marker::PhantomData;
+use std::ops::{ControlFlow, Deref};
+#[cfg(feature = "hashtable-metrics")]
+use std::cell::Cell;
+#[cfg(feature = "hashtable-metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
 use byteorder::{LittleEndian, ByteOrder};
 use allocator::Allocation;
 use persist::*;
 use memory::*;
+use {GrowEvent, GrowObserver};
 
 pub struct HashTable<'m, S: Storage + 'm, C: HashTableConfig = DefaultHashTableConfig> {
     data: Allocation,
     memory: &'m Memory<S>,
     config: PhantomData<C>,
+    grow_observer: Option<GrowObserver<'m>>,
+    // Set while an incremental rehash is in progress, see
+    // `HashTableConfig::INCREMENTAL_REHASH`.
+    migration: Option<Migration>,
+    #[cfg(feature = "hashtable-metrics")]
+    probe_metrics: ProbeMetricsCounter,
 }
 
+// The state of an incremental rehash. `HashTable::data` already refers to the
+// new entry array, `old_data` still holds the entries that have not been
+// moved yet. Every key is in exactly one of the two.
+#[derive(Copy, Clone)]
+struct Migration {
+    old_data: Allocation,
+    // The next slot of the old entry array to look at.
+    next_index: u32,
+}
+
+// The number of slots of the old entry array that are migrated per
+// modification during an incremental rehash.
+const MIGRATION_STEP: u32 = 8;
+
 impl<'m, S: Storage + 'm, C: HashTableConfig> HashTable<'m, S, C> {
 
     #[inline]
@@ -19,63 +45,678 @@ impl<'m, S: Storage + 'm, C: HashTableConfig> HashTable<'m, S, C> {
         HashTable::with_capacity(memory, Size(0))
     }
 
+    /// Creates a table with a randomly chosen hash seed, so that the
+    /// placement of keys cannot be predicted from the outside.
     #[inline]
     pub fn with_capacity(memory: &'m Memory<S>, capacity: Size) -> HashTable<'m, S, C> {
-        let data = RawTable::<S, C>::alloc_with_capacity(memory, capacity);
+        HashTable::with_capacity_and_seed(memory, capacity, random_seed())
+    }
+
+    #[inline]
+    pub fn with_capacity_and_seed(memory: &'m Memory<S>,
+                                  capacity: Size,
+                                  seed: u64)
+                                  -> HashTable<'m, S, C> {
+        let data = RawTable::<S, C>::alloc_with_capacity(memory, capacity, seed);
 
         HashTable {
             data,
             memory,
             config: PhantomData,
+            grow_observer: None,
+            migration: None,
+            #[cfg(feature = "hashtable-metrics")]
+            probe_metrics: ProbeMetricsCounter::default(),
+        }
+    }
+
+    /// Creates a table holding the given key/value pairs. The table is
+    /// allocated with enough capacity up front, so it never has to grow while
+    /// the pairs are inserted. For duplicate keys the last value wins.
+    pub fn from_pairs(memory: &'m Memory<S>, pairs: &[(&[u8], &[u8])]) -> HashTable<'m, S, C> {
+        let mut table = HashTable::with_capacity(memory, Size::from_bytes(pairs.len()));
+
+        for &(key, value) in pairs {
+            table.insert(key, value);
         }
+
+        table
+    }
+
+    /// Wraps a table that already exists in memory, e.g. one that was
+    /// persisted earlier, without re-initializing it.
+    pub fn from_existing(memory: &'m Memory<S>, data: Allocation) -> Result<HashTable<'m, S, C>, String> {
+        RawTable::<S, C>::validate(memory, data)?;
+
+        Ok(HashTable {
+            data,
+            memory,
+            config: PhantomData,
+            grow_observer: None,
+            migration: None,
+            #[cfg(feature = "hashtable-metrics")]
+            probe_metrics: ProbeMetricsCounter::default(),
+        })
+    }
+
+    /// Like `from_existing()` but the returned handle only supports lookups.
+    /// Any number of read-only handles to the same table can exist at once.
+    pub fn open_readonly(memory: &'m Memory<S>, data: Allocation) -> Result<ReadOnlyHashTable<'m, S, C>, String> {
+        RawTable::<S, C>::validate(memory, data)?;
+
+        Ok(ReadOnlyHashTable {
+            data,
+            memory,
+            config: PhantomData,
+        })
+    }
+
+    /// The allocation holding the table header and entry array. Note that
+    /// this changes when the table is resized. Must not be called during an
+    /// incremental rehash, because then some entries are still stored
+    /// elsewhere. Call `finish_migration()` first.
+    #[inline]
+    pub fn allocation(&self) -> Allocation {
+        assert!(self.migration.is_none(),
+                "Incremental rehash in progress, call finish_migration() first");
+        self.data
+    }
+
+    #[inline]
+    pub fn seed(&self) -> u64 {
+        RawTable::<S, C>::seed(self.memory, self.data)
     }
 
     #[inline]
     pub fn len(&self) -> usize {
-        RawTable::<S, C>::len(self.memory, self.data).as_usize()
+        let old_len = match self.migration {
+            Some(migration) => RawTable::<S, C>::len(self.memory, migration.old_data),
+            None => Size(0),
+        };
+
+        (RawTable::<S, C>::len(self.memory, self.data) + old_len).bytes()
     }
 
     #[inline]
     pub fn capacity(&self) -> usize {
-        RawTable::<S, C>::capacity(self.memory, self.data).as_usize()
+        RawTable::<S, C>::capacity(self.memory, self.data).bytes()
     }
 
-    pub fn find(&self, key: &[u8]) -> Option<MemRef> {
-        RawTable::<S, C>::find(self.memory, self.data, key)
+    /// The number of bytes a table with the given capacity occupies in memory,
+    /// not counting keys and values that are stored out-of-line.
+    #[inline]
+    pub fn bytes_for_capacity(capacity: Size) -> Size {
+        RawTable::<S, C>::byte_count_for_capacity(capacity)
+    }
+
+    /// The number of bytes this table's entry array (including the table
+    /// header) currently occupies in memory.
+    #[inline]
+    pub fn footprint(&self) -> Size {
+        let footprint = Self::bytes_for_capacity(RawTable::<S, C>::capacity(self.memory, self.data));
+
+        match self.migration {
+            Some(migration) => footprint + migration.old_data.size,
+            None => footprint,
+        }
+    }
+
+    pub fn find(&self, key: &[u8]) -> Option<ValueRef<'_>> {
+        start_probe_count();
+        let value = RawTable::<S, C>::find(self.memory, self.data, key).or_else(|| {
+            self.migration.and_then(|migration| {
+                RawTable::<S, C>::find(self.memory, migration.old_data, key)
+            })
+        });
+        self.finish_probe_count();
+        value
+    }
+
+    /// Looks up several keys at once. The result has one item per key, in the
+    /// same order as `keys`. The entry array is probed in slot order, which
+    /// makes for better cache locality than calling `find()` for each key.
+    pub fn find_many(&self, keys: &[&[u8]]) -> Vec<Option<ValueRef<'_>>> {
+        let mut values = RawTable::<S, C>::find_many(self.memory, self.data, keys);
+
+        if let Some(migration) = self.migration {
+            for (value, key) in values.iter_mut().zip(keys) {
+                if value.is_none() {
+                    *value = RawTable::<S, C>::find(self.memory, migration.old_data, key);
+                }
+            }
+        }
+
+        values
+    }
+
+    /// Returns the address at which the key bytes of the entry for `key` are
+    /// stored, e.g. for building secondary indexes on top of the table.
+    ///
+    /// The address is only valid as long as the table is not modified in a
+    /// way that moves entries: resizing (including growing on insert) and
+    /// removing any entry can both relocate the key bytes.
+    pub fn stable_key_ref(&self, key: &[u8]) -> Option<Address> {
+        start_probe_count();
+        let addr = RawTable::<S, C>::key_addr(self.memory, self.data, key).or_else(|| {
+            self.migration.and_then(|migration| {
+                RawTable::<S, C>::key_addr(self.memory, migration.old_data, key)
+            })
+        });
+        self.finish_probe_count();
+        addr
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        start_probe_count();
+        let contains = RawTable::<S, C>::contains(self.memory, self.data, key) ||
+            self.migration.is_some_and(|migration| {
+                RawTable::<S, C>::contains(self.memory, migration.old_data, key)
+            });
+        self.finish_probe_count();
+        contains
+    }
+
+    /// Like `find()` but returns an empty value if the key is not present.
+    pub fn get_or_empty(&self, key: &[u8]) -> ValueRef<'_> {
+        self.find(key).unwrap_or_else(|| ValueRef::Borrowed(self.memory.get_bytes(self.data.addr, Size(0))))
     }
 
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
-        RawTable::<S, C>::insert(self.memory, &mut self.data, key, value)
+        let old_data = self.data;
+        let old_capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+        self.start_migration_if_full();
+        self.migrate(key);
+        start_probe_count();
+        let key_added = RawTable::<S, C>::insert(self.memory, &mut self.data, key, value);
+        self.finish_probe_count();
+        self.notify_if_grown(old_data, old_capacity);
+        key_added
+    }
+
+    /// Like `insert()` but returns a copy of the value that was replaced, if
+    /// the key was already present.
+    pub fn insert_replace(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let old_data = self.data;
+        let old_capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+        self.start_migration_if_full();
+        self.migrate(key);
+        start_probe_count();
+        let old_value = RawTable::<S, C>::insert_replace(self.memory, &mut self.data, key, value);
+        self.finish_probe_count();
+        self.notify_if_grown(old_data, old_capacity);
+        old_value
+    }
+
+    /// Looks up `key` and lets `f` decide what to do with it: `f` gets the
+    /// current value (or `None` if the key is not present) and returns the new
+    /// value, or `None` to remove the entry.
+    pub fn update<F>(&mut self, key: &[u8], f: F)
+        where F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>
+    {
+        let old_data = self.data;
+        let old_capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+        self.start_migration_if_full();
+        self.migrate(key);
+        start_probe_count();
+        RawTable::<S, C>::update(self.memory, &mut self.data, key, f);
+        self.finish_probe_count();
+        self.notify_if_grown(old_data, old_capacity);
+    }
+
+    /// Sets the value for `key` to `new`, but only if its current value equals
+    /// `expected`. `None` means that the key must not be present yet. Returns
+    /// whether the value was written.
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> bool {
+        let old_data = self.data;
+        let old_capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+        self.start_migration_if_full();
+        self.migrate(key);
+        start_probe_count();
+        let swapped = RawTable::<S, C>::compare_and_swap(self.memory, &mut self.data, key, expected, new);
+        self.finish_probe_count();
+        self.notify_if_grown(old_data, old_capacity);
+        swapped
+    }
+
+    /// Moves the table to a new allocation with the given capacity, which
+    /// can be smaller than the current one as long as all entries fit.
+    pub fn resize(&mut self, new_capacity: Size) {
+        self.finish_migration();
+        assert!(new_capacity.bytes() >= self.len(),
+                "Cannot resize table with {} entries to capacity {}",
+                self.len(),
+                new_capacity.bytes());
+        RawTable::<S, C>::resize(self.memory, &mut self.data, new_capacity);
+    }
+
+    /// Shrinks the table to the smallest capacity that holds all entries.
+    pub fn shrink_to_fit(&mut self) {
+        self.finish_migration();
+        let len = Size::from_bytes(self.len());
+        RawTable::<S, C>::resize(self.memory, &mut self.data, len);
+    }
+
+    /// Registers a callback that is invoked whenever the table has to grow.
+    pub fn set_grow_observer(&mut self, observer: GrowObserver<'m>) {
+        self.grow_observer = Some(observer);
+    }
+
+    fn notify_if_grown(&mut self, old_data: Allocation, old_capacity: Size) {
+        let new_capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+
+        // Rebuilding the table to get rid of tombstones keeps the capacity.
+        if old_data == self.data || new_capacity == old_capacity {
+            return
+        }
+
+        if let Some(ref mut observer) = self.grow_observer {
+            observer(GrowEvent {
+                old_capacity,
+                new_capacity,
+                old_byte_size: old_data.size,
+                new_byte_size: self.data.size,
+            });
+        }
     }
 
     #[inline]
     pub fn remove(&mut self, key: &[u8]) -> bool {
-        RawTable::<S, C>::remove_entry(self.memory, self.data, key)
+        self.remove_and_report(key).is_some()
+    }
+
+    /// Like `remove()` but returns the number of bytes of out-of-line key and
+    /// value storage that were freed (zero for fully inline entries), or
+    /// `None` if the key was not present.
+    #[inline]
+    pub fn remove_and_report(&mut self, key: &[u8]) -> Option<Size> {
+        self.migrate(key);
+        start_probe_count();
+        let freed = RawTable::<S, C>::remove_entry(self.memory, self.data, key);
+        self.finish_probe_count();
+        freed
+    }
+
+    /// How many slots `find()`, `insert()`, `remove()` and friends had to
+    /// look at, accumulated over all operations on this handle so far.
+    #[cfg(feature = "hashtable-metrics")]
+    pub fn probe_metrics(&self) -> ProbeMetrics {
+        self.probe_metrics.get()
+    }
+
+    #[cfg(feature = "hashtable-metrics")]
+    pub fn reset_probe_metrics(&self) {
+        self.probe_metrics.reset();
+    }
+
+    #[inline(always)]
+    fn finish_probe_count(&self) {
+        #[cfg(feature = "hashtable-metrics")]
+        self.probe_metrics.record(PROBE_STEPS.with(|steps| steps.get()));
+    }
+
+    /// Whether an incremental rehash is in progress, see
+    /// `HashTableConfig::INCREMENTAL_REHASH`.
+    #[inline]
+    pub fn is_migrating(&self) -> bool {
+        self.migration.is_some()
+    }
+
+    /// Completes an incremental rehash in one go, if there is one in progress.
+    pub fn finish_migration(&mut self) {
+        while self.migration.is_some() {
+            self.migrate_some(u32::MAX);
+        }
+    }
+
+    // With `HashTableConfig::INCREMENTAL_REHASH`, a full table switches to a
+    // larger entry array right away, but leaves the existing entries where
+    // they are. `migrate()` moves them over bit by bit.
+    fn start_migration_if_full(&mut self) {
+        if !C::INCREMENTAL_REHASH || self.migration.is_some() {
+            return
+        }
+
+        let capacity = RawTable::<S, C>::capacity(self.memory, self.data);
+        if capacity == Size(0) || RawTable::<S, C>::len(self.memory, self.data) < capacity {
+            return
+        }
+
+        RawTable::<S, C>::assert_not_iterating(self.memory, self.data);
+
+        let seed = RawTable::<S, C>::seed(self.memory, self.data);
+        let new_capacity = RawTable::<S, C>::grown_capacity(capacity);
+        let new_data = RawTable::<S, C>::alloc_with_capacity(self.memory, new_capacity, seed);
+
+        self.migration = Some(Migration {
+            old_data: self.data,
+            next_index: 0,
+        });
+        self.data = new_data;
+    }
+
+    // Moves `key` (if it is still in the old entry array) and a few more
+    // entries to the current entry array. Every modification calls this
+    // first, so that it only has to deal with the current entry array.
+    fn migrate(&mut self, key: &[u8]) {
+        if let Some(migration) = self.migration {
+            if let Some(index) = RawTable::<S, C>::find_slot(self.memory, migration.old_data, key) {
+                RawTable::<S, C>::migrate_entry(self.memory, migration.old_data, index, &mut self.data);
+            }
+
+            self.migrate_some(MIGRATION_STEP);
+        }
+    }
+
+    fn migrate_some(&mut self, max_steps: u32) {
+        let mut migration = match self.migration {
+            Some(migration) => migration,
+            None => return,
+        };
+
+        let old_table_size = RawTable::<S, C>::entry_array_len(self.memory, migration.old_data);
+
+        for _ in 0 .. max_steps {
+            if migration.next_index >= old_table_size {
+                break
+            }
+
+            let entry = RawTable::<S, C>::get_entry(self.memory, migration.old_data, migration.next_index);
+
+            if entry.is_empty() {
+                migration.next_index += 1;
+            } else {
+                // Don't advance: removing the entry from the old entry array
+                // can move another entry into its slot.
+                RawTable::<S, C>::migrate_entry(self.memory, migration.old_data, migration.next_index, &mut self.data);
+            }
+        }
+
+        if RawTable::<S, C>::len(self.memory, migration.old_data) == Size(0) {
+            self.memory.free(migration.old_data);
+            self.migration = None;
+        } else {
+            if migration.next_index >= old_table_size {
+                // Only possible if entries were moved behind the cursor.
+                migration.next_index = 0;
+            }
+
+            self.migration = Some(migration);
+        }
     }
 
     #[inline]
-    pub fn delete_table(self) {
+    pub fn delete_table(mut self) {
+        if let Some(migration) = self.migration.take() {
+            RawTable::<S, C>::delete_table(self.memory, migration.old_data);
+        }
+
         RawTable::<S, C>::delete_table(self.memory, self.data);
     }
 
     pub fn sanity_check_table(&self) {
         RawTable::<S, C>::sanity_check_table(self.memory, self.data);
+
+        if let Some(migration) = self.migration {
+            RawTable::<S, C>::sanity_check_table(self.memory, migration.old_data);
+        }
+    }
+
+    /// Counts the occupied slots and stores the result as the table's length.
+    /// Use this to repair a table whose stored length cannot be trusted.
+    pub fn recount(&mut self) -> usize {
+        self.finish_migration();
+        RawTable::<S, C>::recount(self.memory, self.data).bytes()
+    }
+
+    /// Decodes the metadata of the entry in the given slot of the current
+    /// entry array. Returns `None` if `index` is out of bounds.
+    pub fn entry_at(&self, index: usize) -> Option<EntryInfo> {
+        RawTable::<S, C>::entry_info(self.memory, self.data, index)
+    }
+
+    /// Renders the entry array, one slot per line, for debugging.
+    pub fn dump(&self) -> String {
+        let mut dump = RawTable::<S, C>::dump(self.memory, self.data);
+
+        if let Some(migration) = self.migration {
+            dump.push_str("old entry array:\n");
+            dump.push_str(&RawTable::<S, C>::dump(self.memory, migration.old_data));
+        }
+
+        dump
+    }
+
+    pub fn iter<F: FnMut(&[u8], &[u8])>(&self, mut f: F) {
+        RawTable::<S, C>::iter(self.memory, self.data, &mut f);
+
+        if let Some(migration) = self.migration {
+            RawTable::<S, C>::iter(self.memory, migration.old_data, f);
+        }
+    }
+
+    /// Like `iter()` but stops as soon as `f` returns `ControlFlow::Break`.
+    /// Returns `true` if all entries have been visited.
+    pub fn try_iter<F>(&self, mut f: F) -> bool
+        where F: FnMut(&[u8], &[u8]) -> ControlFlow<()>
+    {
+        RawTable::<S, C>::try_iter(self.memory, self.data, &mut f) &&
+            self.migration.is_none_or(|migration| {
+                RawTable::<S, C>::try_iter(self.memory, migration.old_data, f)
+            })
+    }
+
+    /// The combined length of all keys, not counting unused inline space.
+    pub fn key_bytes(&self) -> Size {
+        let mut total = Size(0);
+        self.iter(|key, _| total += Size::from_bytes(key.len()));
+        total
+    }
+
+    /// The combined length of all values, not counting unused inline space.
+    pub fn value_bytes(&self) -> Size {
+        let mut total = Size(0);
+        self.iter(|_, value| total += Size::from_bytes(value.len()));
+        total
+    }
+
+    /// Copies all entries out of the table, in no particular order.
+    pub fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::with_capacity(self.len());
+        self.iter(|key, value| entries.push((key.to_vec(), value.to_vec())));
+        entries
+    }
+
+    /// Like `iter()` but visits entries in lexicographic key order.
+    pub fn iter_sorted<F: FnMut(&[u8], &[u8])>(&self, mut f: F) {
+        if self.migration.is_some() {
+            let mut entries = self.entries();
+            entries.sort();
+
+            for (key, value) in &entries {
+                f(key, value);
+            }
+        } else {
+            RawTable::<S, C>::iter_sorted(self.memory, self.data, f);
+        }
+    }
+}
+
+// Dropping a table in the middle of an incremental rehash would lose the
+// entries that haven't been moved yet, since the migration state only lives
+// here.
+impl<'m, S: Storage + 'm, C: HashTableConfig> Drop for HashTable<'m, S, C> {
+    fn drop(&mut self) {
+        if !::std::thread::panicking() {
+            self.finish_migration();
+        }
+    }
+}
+
+
+
+/// A handle to a hash table that can only be used for lookups, see
+/// `HashTable::open_readonly()`.
+#[derive(Clone, Copy)]
+pub struct ReadOnlyHashTable<'m, S: Storage + 'm, C: HashTableConfig = DefaultHashTableConfig> {
+    data: Allocation,
+    memory: &'m Memory<S>,
+    config: PhantomData<C>,
+}
+
+impl<'m, S: Storage + 'm, C: HashTableConfig> ReadOnlyHashTable<'m, S, C> {
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        RawTable::<S, C>::len(self.memory, self.data).bytes()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        RawTable::<S, C>::capacity(self.memory, self.data).bytes()
+    }
+
+    pub fn find(&self, key: &[u8]) -> Option<ValueRef<'m>> {
+        RawTable::<S, C>::find(self.memory, self.data, key)
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        RawTable::<S, C>::contains(self.memory, self.data, key)
     }
 
     pub fn iter<F: FnMut(&[u8], &[u8])>(&self, f: F) {
         RawTable::<S, C>::iter(self.memory, self.data, f);
     }
+
+    /// Like `iter()` but stops as soon as `f` returns `ControlFlow::Break`.
+    /// Returns `true` if all entries have been visited.
+    pub fn try_iter<F>(&self, f: F) -> bool
+        where F: FnMut(&[u8], &[u8]) -> ControlFlow<()>
+    {
+        RawTable::<S, C>::try_iter(self.memory, self.data, f)
+    }
+}
+
+/// A value found in a hash table. Values that are stored in several chunks,
+/// see `HashTableConfig::VALUE_CHUNK_SIZE`, are reassembled into a buffer,
+/// everything else refers to the table's memory directly.
+pub enum ValueRef<'m> {
+    Borrowed(MemRef<'m>),
+    Assembled(Vec<u8>),
+}
+
+impl<'m> Deref for ValueRef<'m> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            ValueRef::Borrowed(ref bytes) => bytes,
+            ValueRef::Assembled(ref bytes) => bytes,
+        }
+    }
+}
+
+/// The decoded metadata of a single slot in a hash table's entry array, see
+/// `HashTable::entry_at()`. All fields except `is_empty` are zero/false for
+/// empty slots.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EntryInfo {
+    pub is_empty: bool,
+    pub hash: u64,
+    pub key_inline: bool,
+    pub key_len: Size,
+    pub value_inline: bool,
+    pub value_len: Size,
+}
+
+/// Probe lengths of the operations on a `HashTable`, see
+/// `HashTable::probe_metrics()`.
+#[cfg(feature = "hashtable-metrics")]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct ProbeMetrics {
+    pub operation_count: u64,
+    pub total_probe_steps: u64,
+    pub max_probe_steps: u64,
+}
+
+#[cfg(feature = "hashtable-metrics")]
+impl ProbeMetrics {
+    pub fn average_probe_steps(&self) -> f64 {
+        if self.operation_count == 0 {
+            0.0
+        } else {
+            self.total_probe_steps as f64 / self.operation_count as f64
+        }
+    }
+}
+
+// Atomics rather than `Cell`s, so that `HashTable` stays `Sync`.
+#[cfg(feature = "hashtable-metrics")]
+#[derive(Default)]
+struct ProbeMetricsCounter {
+    operation_count: AtomicU64,
+    total_probe_steps: AtomicU64,
+    max_probe_steps: AtomicU64,
+}
+
+#[cfg(feature = "hashtable-metrics")]
+impl ProbeMetricsCounter {
+    fn record(&self, probe_steps: u64) {
+        self.operation_count.fetch_add(1, Ordering::Relaxed);
+        self.total_probe_steps.fetch_add(probe_steps, Ordering::Relaxed);
+        self.max_probe_steps.fetch_max(probe_steps, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> ProbeMetrics {
+        ProbeMetrics {
+            operation_count: self.operation_count.load(Ordering::Relaxed),
+            total_probe_steps: self.total_probe_steps.load(Ordering::Relaxed),
+            max_probe_steps: self.max_probe_steps.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.operation_count.store(0, Ordering::Relaxed);
+        self.total_probe_steps.store(0, Ordering::Relaxed);
+        self.max_probe_steps.store(0, Ordering::Relaxed);
+    }
+}
+
+// The number of slots the current operation has looked at so far. A thread
+// local keeps `RawTable` free of any bookkeeping parameters.
+#[cfg(feature = "hashtable-metrics")]
+thread_local! {
+    static PROBE_STEPS: Cell<u64> = const { Cell::new(0) };
 }
 
+#[inline(always)]
+fn start_probe_count() {
+    #[cfg(feature = "hashtable-metrics")]
+    PROBE_STEPS.with(|steps| steps.set(0));
+}
 
+#[inline(always)]
+fn count_probe_step() {
+    #[cfg(feature = "hashtable-metrics")]
+    PROBE_STEPS.with(|steps| steps.set(steps.get() + 1));
+}
 
 const MAGIC_HEADER: [u8; 4] = [b'H', b'A', b'S', b'H'];
 
 const MAGIC_HEADER_OFFSET: Size = Size(0);
 const LEN_OFFSET: Size = Size(MAGIC_HEADER_OFFSET.0 + 4);
 const CAPACITY_OFFSET: Size = Size(LEN_OFFSET.0 + 4);
+const TOMBSTONE_COUNT_OFFSET: Size = Size(CAPACITY_OFFSET.0 + 4);
+
+const SEED_OFFSET: Size = Size(TOMBSTONE_COUNT_OFFSET.0 + 4);
 
-const HEADER_SIZE: Size = Size(CAPACITY_OFFSET.0 + 4);
+const HEADER_SIZE: Size = Size(SEED_OFFSET.0 + 8);
 const ENTRY_META_SIZE: Size = Size(8);
 
 // Layout:
@@ -83,13 +724,43 @@ const ENTRY_META_SIZE: Size = Size(8);
 // magic_header: u32
 // item_count: u32
 // capacity: u32
+// tombstone_count: u32
+// seed: u64
 // entry*
 pub struct RawTable<S: Storage, C: HashTableConfig = DefaultHashTableConfig> {
     memory: PhantomData<S>,
     config: PhantomData<C>,
 }
 
+/// The order in which slots are tried when looking for a key.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ProbeSequence {
+    /// Try the slots following the home slot one by one. Removing an entry
+    /// moves later entries back, so tables never contain tombstones.
+    Linear,
+    /// Try the slots at offsets 1, 3, 6, 10, ... from the home slot, which
+    /// clusters less. Removed entries leave tombstones behind, which are
+    /// cleared by rebuilding the table once they and the entries together
+    /// reach the capacity. The number of slots is always a power of two.
+    Triangular,
+}
+
 pub trait HashTableConfig {
+    const PROBE_SEQUENCE: ProbeSequence = ProbeSequence::Linear;
+    /// Tables that have any capacity at all have at least this much, so that
+    /// tables which are known to get large skip the small sizes.
+    const MIN_CAPACITY: Size = Size(0);
+    /// If set, a full table does not move all of its entries at once when it
+    /// grows. Instead, the old entry array is kept around and a few of its
+    /// entries are moved with every following modification, which avoids
+    /// long pauses for large tables. Lookups consult both entry arrays until
+    /// the old one is drained. Dropping the table finishes the rehash.
+    const INCREMENTAL_REHASH: bool = false;
+    /// If set, out-of-line values are split into blocks holding at most this
+    /// many bytes, so that large values can be stored even if there is no
+    /// contiguous free space for them. It also lifts the limit of 255 bytes
+    /// for values. `find()` has to reassemble values spanning several blocks.
+    const VALUE_CHUNK_SIZE: Option<Size> = None;
     const MAX_INLINE_KEY_LEN: Size = Size(4);
     const MAX_INLINE_VALUE_LEN: Size = Size(4);
     const ENTRY_SIZE: Size = Size(Self::MAX_INLINE_KEY_LEN.0 +
@@ -101,12 +772,26 @@ pub enum DefaultHashTableConfig {}
 impl HashTableConfig for DefaultHashTableConfig {}
 
 const ENTRY_META_IS_EMPTY_BIT: u64 = 1 << 63;
-// const ENTRY_META_IS_TOMBSTONE_BIT: u64 = 1 << 62;
+// A tombstone has only this bit set, so it counts as empty.
+const ENTRY_META_IS_TOMBSTONE_BIT: u64 = 1 << 62;
 const ENTRY_META_INLINE_LEN_BIT_COUNT: usize = 7;
 const ENTRY_META_INLINE_LEN_MASK: u64 = (1u64 << ENTRY_META_INLINE_LEN_BIT_COUNT) - 1;
 const ENTRY_META_HASH_BIT_COUNT: usize = 64 - (4 + ENTRY_META_INLINE_LEN_BIT_COUNT * 2);
 const ENTRY_META_HASH_MASK: u64 = (1u64 << ENTRY_META_HASH_BIT_COUNT) - 1;
 
+// Out-of-line data is prefixed with its length as a single byte, unless it is
+// split into chunks, see `HashTableConfig::VALUE_CHUNK_SIZE`. Then it is
+// stored as a list of blocks, each starting with the number of data bytes in
+// the block and the address of the next block, which is zero for the last.
+const INDIRECT_LEN_SIZE: Size = Size(1);
+const CHUNK_HEADER_SIZE: Size = Size(8);
+const NO_NEXT_CHUNK: Address = Address(0);
+
+#[inline]
+fn read_chunk_header<S: Storage>(memory: &Memory<S>, block: Address) -> (Size, Address) {
+    (Size::read_at(memory, block), Address::read_at(memory, block + Size(4)))
+}
+
 
 trait EntryDataKind {
     const IS_INLINE_BIT_SHIFT: usize;
@@ -118,6 +803,7 @@ trait EntryDataKind {
 
     fn max_inline_size<C: HashTableConfig>() -> Size;
     fn offset_within_entry<C: HashTableConfig>() -> Size;
+    fn chunk_size<C: HashTableConfig>() -> Option<Size>;
 }
 
 enum DataKindKey {}
@@ -132,6 +818,10 @@ impl EntryDataKind for DataKindKey {
     fn offset_within_entry<C: HashTableConfig>() -> Size {
         ENTRY_META_SIZE
     }
+
+    fn chunk_size<C: HashTableConfig>() -> Option<Size> {
+        None
+    }
 }
 
 enum DataKindValue {}
@@ -146,6 +836,10 @@ impl EntryDataKind for DataKindValue {
     fn offset_within_entry<C: HashTableConfig>() -> Size {
         ENTRY_META_SIZE + C::MAX_INLINE_KEY_LEN
     }
+
+    fn chunk_size<C: HashTableConfig>() -> Option<Size> {
+        C::VALUE_CHUNK_SIZE
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -164,13 +858,23 @@ impl<C: HashTableConfig, S: Storage> Entry<C, S> {
         debug_assert!(!self.is_empty());
     }
 
-    fn clear(&mut self, memory: &Memory<S>) {
+    // Returns the number of out-of-line bytes that were freed.
+    fn clear(&mut self, memory: &Memory<S>) -> Size {
         debug_assert!(!self.is_empty());
-        self.delete_entry_data::<DataKindKey>(memory);
-        self.delete_entry_data::<DataKindValue>(memory);
+        let freed = self.delete_entry_data::<DataKindKey>(memory) +
+                    self.delete_entry_data::<DataKindValue>(memory);
         fill_zero(&mut memory.get_bytes_mut(self.addr, C::ENTRY_SIZE));
         self.metadata = 0;
         debug_assert!(self.is_empty());
+        freed
+    }
+
+    // Like `clear()` but leaves the out-of-line data alone, for entries that
+    // have been copied to another table as they are.
+    fn forget(&mut self, memory: &Memory<S>) {
+        debug_assert!(!self.is_empty());
+        fill_zero(&mut memory.get_bytes_mut(self.addr, C::ENTRY_SIZE));
+        self.metadata = 0;
     }
 
     #[inline]
@@ -186,9 +890,16 @@ impl<C: HashTableConfig, S: Storage> Entry<C, S> {
         (self.metadata & ENTRY_META_IS_EMPTY_BIT) == 0
     }
 
-    // fn is_tombstone(self) -> bool {
-    //     (self.0 & ENTRY_META_IS_TOMBSTONE_BIT) != 0
-    // }
+    fn is_tombstone(&self) -> bool {
+        self.metadata == ENTRY_META_IS_TOMBSTONE_BIT
+    }
+
+    fn make_tombstone(&mut self, storage: &Memory<S>) {
+        debug_assert!(self.is_empty());
+        self.metadata = ENTRY_META_IS_TOMBSTONE_BIT;
+        self.metadata.write_at(storage, self.addr);
+        debug_assert!(self.is_empty() && self.is_tombstone());
+    }
 
     fn is_entry_data_inline<K: EntryDataKind>(&self) -> bool {
         (self.metadata & K::IS_INLINE_BIT) == 0
@@ -198,51 +909,120 @@ impl<C: HashTableConfig, S: Storage> Entry<C, S> {
         Size(((self.metadata >> K::INLINE_LEN_SHIFT) & ENTRY_META_INLINE_LEN_MASK) as u32)
     }
 
-    fn entry_data<'m, K: EntryDataKind>(&self, memory: &'m Memory<S>, ) -> MemRef<'m> {
+    fn entry_data<'m, K: EntryDataKind>(&self, memory: &'m Memory<S>, ) -> ValueRef<'m> {
         let data_addr = self.addr + K::offset_within_entry::<C>();
 
         if self.is_entry_data_inline::<K>() {
             let inline_data_len = self.inline_entry_data_len::<K>();
-            memory.get_bytes(data_addr, inline_data_len)
-        } else {
-            // Follow the indirection
-            let data_addr = Address::read_at(memory, data_addr);
-            let len = Size(memory.get_bytes(data_addr, Size(1))[0] as u32);
-            memory.get_bytes(data_addr + Size(1), len)
+            return ValueRef::Borrowed(memory.get_bytes(data_addr, inline_data_len))
         }
-    }
 
-    fn set_entry_data<K: EntryDataKind>(&mut self,
-                                            memory: &Memory<S>,
-                                            bytes: &[u8]) {
-        assert!(bytes.len() < 256);
+        // Follow the indirection
+        let data_addr = Address::read_at(memory, data_addr);
 
-        self.delete_entry_data::<K>(memory);
+        if K::chunk_size::<C>().is_none() {
+            let len = Size(memory.get_bytes(data_addr, INDIRECT_LEN_SIZE)[0] as u32);
+            debug_assert!(len > K::max_inline_size::<C>());
+            return ValueRef::Borrowed(memory.get_bytes(data_addr + INDIRECT_LEN_SIZE, len))
+        }
 
-        let max_inline_size = K::max_inline_size::<C>();
-        debug_assert!(!self.is_empty());
+        let (len, mut next) = read_chunk_header(memory, data_addr);
+        if next == NO_NEXT_CHUNK {
+            return ValueRef::Borrowed(memory.get_bytes(data_addr + CHUNK_HEADER_SIZE, len))
+        }
 
-        if bytes.len() <= max_inline_size.as_usize() {
-            {
-                let mut dest_bytes = memory.get_bytes_mut(self.addr + K::offset_within_entry::<C>(),
-                                                      max_inline_size);
-                dest_bytes[0 .. bytes.len()].copy_from_slice(bytes);
-                fill_zero(&mut dest_bytes[bytes.len() .. ]);
-            }
+        let mut bytes = memory.read_to_vec(data_addr + CHUNK_HEADER_SIZE, len);
+        while next != NO_NEXT_CHUNK {
+            let (len, next_block) = read_chunk_header(memory, next);
+            bytes.extend_from_slice(&memory.get_bytes(next + CHUNK_HEADER_SIZE, len));
+            next = next_block;
+        }
 
-            self.metadata &= K::IS_INLINE_CLEAR_MASK;
-            self.metadata &= K::INLINE_LEN_CLEAR_MASK;
-            self.metadata |= (bytes.len() as u64) << K::INLINE_LEN_SHIFT;
+        ValueRef::Assembled(bytes)
+    }
+
+    // The address of the entry data itself, i.e. behind the indirection and
+    // its length prefix if the data is not stored inline. Only used for keys,
+    // which are never split into chunks.
+    fn entry_data_addr<K: EntryDataKind>(&self, memory: &Memory<S>) -> Address {
+        debug_assert!(K::chunk_size::<C>().is_none());
+        let data_addr = self.addr + K::offset_within_entry::<C>();
+
+        if self.is_entry_data_inline::<K>() {
+            data_addr
+        } else {
+            Address::read_at(memory, data_addr) + INDIRECT_LEN_SIZE
+        }
+    }
+
+    // Like `&*self.entry_data::<K>(memory) == bytes` but lengths are compared
+    // first, using only the metadata where possible, so that the data itself
+    // is only read if the lengths match.
+    fn entry_data_equals<K: EntryDataKind>(&self, memory: &Memory<S>, bytes: &[u8]) -> bool {
+        let data_addr = self.addr + K::offset_within_entry::<C>();
+
+        if self.is_entry_data_inline::<K>() {
+            self.inline_entry_data_len::<K>().bytes() == bytes.len() &&
+                &*memory.get_bytes(data_addr, Size::from_bytes(bytes.len())) == bytes
+        } else {
+            // Indirect data is always longer than the inline maximum.
+            if bytes.len() <= K::max_inline_size::<C>().bytes() {
+                return false
+            }
+
+            let data_addr = Address::read_at(memory, data_addr);
+
+            if K::chunk_size::<C>().is_some() {
+                return chunks_equal(memory, data_addr, bytes)
+            }
+
+            let len = memory.get_bytes(data_addr, INDIRECT_LEN_SIZE)[0] as usize;
+            len == bytes.len() &&
+                &*memory.get_bytes(data_addr + INDIRECT_LEN_SIZE, Size::from_bytes(len)) == bytes
+        }
+    }
+
+    fn set_entry_data<K: EntryDataKind>(&mut self,
+                                            memory: &Memory<S>,
+                                            bytes: &[u8]) {
+        // Unless it is split into chunks, indirect data has a single length
+        // byte, which limits it to 255 bytes.
+        assert!(K::chunk_size::<C>().is_some() || bytes.len() < 256);
+
+        self.delete_entry_data::<K>(memory);
+
+        let max_inline_size = K::max_inline_size::<C>();
+        debug_assert!(!self.is_empty());
+
+        // Empty data is always stored inline, with an inline length of zero,
+        // so the indirect path never has to deal with a zero length prefix.
+        if bytes.len() <= max_inline_size.bytes() {
+            {
+                let mut dest_bytes = memory.get_bytes_mut(self.addr + K::offset_within_entry::<C>(),
+                                                      max_inline_size);
+                dest_bytes[0 .. bytes.len()].copy_from_slice(bytes);
+                fill_zero(&mut dest_bytes[bytes.len() .. ]);
+            }
+
+            self.metadata &= K::IS_INLINE_CLEAR_MASK;
+            self.metadata &= K::INLINE_LEN_CLEAR_MASK;
+            self.metadata |= (bytes.len() as u64) << K::INLINE_LEN_SHIFT;
 
             debug_assert_eq!(self.is_entry_data_inline::<K>(), true);
-            debug_assert_eq!(self.inline_entry_data_len::<K>(), Size::from_usize(bytes.len()));
+            debug_assert_eq!(self.inline_entry_data_len::<K>(), Size::from_bytes(bytes.len()));
         } else {
-            let addr = {
-                let allocation = memory.alloc(Size::from_usize(bytes.len() + 1));
-                let mut dest_bytes = memory.get_bytes_mut(allocation.addr, allocation.size);
-                dest_bytes[0] = bytes.len() as u8;
-                dest_bytes[1 ..].copy_from_slice(bytes);
-                allocation.addr
+            let addr = match K::chunk_size::<C>() {
+                Some(chunk_size) => write_chunks(memory, bytes, chunk_size),
+                None => {
+                    // With size classes, the allocation can be larger than
+                    // requested.
+                    let len = INDIRECT_LEN_SIZE + Size::from_bytes(bytes.len());
+                    let allocation = memory.alloc(len);
+                    let mut dest_bytes = memory.get_bytes_mut(allocation.addr, len);
+                    dest_bytes[0] = bytes.len() as u8;
+                    dest_bytes[1 ..].copy_from_slice(bytes);
+                    allocation.addr
+                }
             };
 
             const ADDRESS_SIZE: usize = mem::size_of::<Address>();
@@ -261,25 +1041,113 @@ impl<C: HashTableConfig, S: Storage> Entry<C, S> {
         debug_assert_eq!(&*self.entry_data::<K>(memory), bytes);
     }
 
-    // Don't use this directly, just a helper function for clear() and set_entry_data()
-    fn delete_entry_data<K: EntryDataKind>(&mut self, memory: &Memory<S>) {
+    // Don't use this directly, just a helper function for clear(),
+    // set_entry_data() and RawTable::delete_table()
+    fn delete_entry_data<K: EntryDataKind>(&mut self, memory: &Memory<S>) -> Size {
         let data_addr = self.addr + K::offset_within_entry::<C>();
 
-        if !self.is_entry_data_inline::<K>() {
-            // Follow the indirection
-            let data_addr = Address::read_at(memory, data_addr);
-            let len = Size(memory.get_bytes(data_addr, Size(1))[0] as u32);
+        if self.is_entry_data_inline::<K>() {
+            return Size(0)
+        }
+
+        // Follow the indirection
+        let data_addr = Address::read_at(memory, data_addr);
+
+        if K::chunk_size::<C>().is_some() {
+            return free_chunks(memory, data_addr)
+        }
 
-            let allocation = Allocation::new(data_addr, len + Size(1));
+        free_at(memory, data_addr)
+    }
+}
+
+// Writes `bytes` as a list of blocks holding at most `chunk_size` bytes each
+// and returns the address of the first block. The blocks are written back to
+// front, so that the address of the next block is always known.
+fn write_chunks<S: Storage>(memory: &Memory<S>, bytes: &[u8], chunk_size: Size) -> Address {
+    assert!(chunk_size > Size(0), "VALUE_CHUNK_SIZE must not be zero");
+
+    let mut next = NO_NEXT_CHUNK;
+
+    for chunk in bytes.chunks(chunk_size.bytes()).rev() {
+        let block_size = CHUNK_HEADER_SIZE + Size::from_bytes(chunk.len());
+        let block = memory.alloc(block_size).addr;
+        Size::from_bytes(chunk.len()).write_at(memory, block);
+        next.write_at(memory, block + Size(4));
+        memory.get_bytes_mut(block + CHUNK_HEADER_SIZE, Size::from_bytes(chunk.len()))
+              .copy_from_slice(chunk);
+        next = block;
+    }
+
+    next
+}
+
+// Returns the number of bytes freed.
+fn free_chunks<S: Storage>(memory: &Memory<S>, first_block: Address) -> Size {
+    let mut freed = Size(0);
+    let mut block = first_block;
+
+    while block != NO_NEXT_CHUNK {
+        let (_, next) = read_chunk_header(memory, block);
+        freed += free_at(memory, block);
+        block = next;
+    }
+
+    freed
+}
+
+// Frees the allocation starting at `addr`, which can be larger than the data
+// stored in it, and returns its size.
+fn free_at<S: Storage>(memory: &Memory<S>, addr: Address) -> Size {
+    let allocation = memory.allocator.lock().allocation_at(addr);
+    let allocation = allocation.unwrap_or_else(|| panic!("No allocation at {:?}", addr));
+    memory.free(allocation);
+    allocation.size
+}
+
+fn chunks_equal<S: Storage>(memory: &Memory<S>, first_block: Address, mut bytes: &[u8]) -> bool {
+    let mut block = first_block;
 
-            memory.free(allocation);
+    while block != NO_NEXT_CHUNK {
+        let (len, next) = read_chunk_header(memory, block);
+        if len.bytes() > bytes.len() ||
+           *memory.get_bytes(block + CHUNK_HEADER_SIZE, len) != bytes[.. len.bytes()] {
+            return false
         }
+
+        bytes = &bytes[len.bytes() ..];
+        block = next;
     }
+
+    bytes.is_empty()
 }
 
 impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
 
-    fn alloc_with_capacity(memory: &Memory<S>, capacity: Size) -> Allocation {
+    // The capacity a full table grows to.
+    fn grown_capacity(capacity: Size) -> Size {
+        let new_capacity = if capacity == Size(0) {
+            Size(8)
+        } else {
+            (capacity * 3u32) / 2u32
+        };
+        debug_assert!(new_capacity > Size(0));
+        new_capacity
+    }
+
+    // Applies `HashTableConfig::MIN_CAPACITY`. An empty table stays empty, it
+    // does not need any entries yet.
+    fn effective_capacity(capacity: Size) -> Size {
+        if capacity == Size(0) {
+            capacity
+        } else {
+            ::std::cmp::max(capacity, C::MIN_CAPACITY)
+        }
+    }
+
+    fn alloc_with_capacity(memory: &Memory<S>, capacity: Size, seed: u64) -> Allocation {
+        let capacity = Self::effective_capacity(capacity);
+
         let byte_count = Self::byte_count_for_capacity(capacity);
         let data = memory.alloc(byte_count);
 
@@ -290,74 +1158,200 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
 
         Self::set_len(memory, data, Size(0));
         Self::set_capacity(memory, data, capacity);
+        Self::set_tombstone_count(memory, data, Size(0));
+        seed.write_at(memory, data.addr + SEED_OFFSET);
         assert!((byte_count - HEADER_SIZE).as_u32() % C::ENTRY_SIZE.as_u32() == 0);
 
         data
     }
 
-    fn find<'m>(memory: &'m Memory<S>, table_data: Allocation, key: &[u8]) -> Option<MemRef<'m>> {
+    fn validate(memory: &Memory<S>, table_data: Allocation) -> Result<(), String> {
+        if table_data.size < HEADER_SIZE {
+            return Err(format!("Allocation {:?} too small for hash table header", table_data));
+        }
+
+        if memory.get_bytes(table_data.addr + MAGIC_HEADER_OFFSET, Size(4))[..] != MAGIC_HEADER {
+            return Err(format!("No hash table found at {:?}: magic header does not match",
+                               table_data.addr));
+        }
+
+        // The allocation can be larger than needed, e.g. with size classes.
+        let capacity = Self::capacity(memory, table_data);
+        if Self::byte_count_for_capacity(capacity) > table_data.size {
+            return Err(format!("Hash table capacity {:?} does not match allocation size {:?}",
+                               capacity,
+                               table_data.size));
+        }
+
+        Ok(())
+    }
+
+    fn find<'m>(memory: &'m Memory<S>, table_data: Allocation, key: &[u8]) -> Option<ValueRef<'m>> {
+        Self::find_slot(memory, table_data, key).map(|entry_index| {
+            Self::get_entry(memory, table_data, entry_index).entry_data::<DataKindValue>(memory)
+        })
+    }
+
+    fn contains(memory: &Memory<S>, table_data: Allocation, key: &[u8]) -> bool {
+        Self::find_slot(memory, table_data, key).is_some()
+    }
+
+    fn find_many<'m>(memory: &'m Memory<S>, table_data: Allocation, keys: &[&[u8]]) -> Vec<Option<ValueRef<'m>>> {
+        let mut results: Vec<Option<ValueRef<'m>>> = keys.iter().map(|_| None).collect();
+
+        if Self::len(memory, table_data) == Size(0) {
+            return results
+        }
+
         let table_size = Self::entry_array_len(memory, table_data);
-        let hash = hash_for(key);
-        let mut entry_index = index_in_table(hash, table_size);
+        let seed = Self::seed(memory, table_data);
 
-        loop {
+        let mut lookups: Vec<(u32, u64, usize)> = keys.iter().enumerate().map(|(key_index, key)| {
+            let hash = hash_for(seed, key);
+            (index_in_table(hash, table_size), hash, key_index)
+        }).collect();
+
+        lookups.sort_unstable_by_key(|&(home_index, _, _)| home_index);
+
+        for (home_index, hash, key_index) in lookups {
+            results[key_index] = Self::find_slot_from(memory, table_data, keys[key_index], hash, home_index)
+                .map(|entry_index| {
+                    Self::get_entry(memory, table_data, entry_index).entry_data::<DataKindValue>(memory)
+                });
+        }
+
+        results
+    }
+
+    // Returns the index of the entry for `key` in the entry array.
+    fn key_addr(memory: &Memory<S>, table_data: Allocation, key: &[u8]) -> Option<Address> {
+        Self::find_slot(memory, table_data, key).map(|entry_index| {
+            Self::get_entry(memory, table_data, entry_index).entry_data_addr::<DataKindKey>(memory)
+        })
+    }
+
+    fn find_slot(memory: &Memory<S>, table_data: Allocation, key: &[u8]) -> Option<u32> {
+        if Self::len(memory, table_data) == Size(0) {
+            return None
+        }
+
+        let table_size = Self::entry_array_len(memory, table_data);
+        let hash = hash_for(Self::seed(memory, table_data), key);
+        let home_index = index_in_table(hash, table_size);
+
+        Self::find_slot_from(memory, table_data, key, hash, home_index)
+    }
+
+    fn find_slot_from(memory: &Memory<S>,
+                      table_data: Allocation,
+                      key: &[u8],
+                      hash: u64,
+                      home_index: u32)
+                      -> Option<u32> {
+        let table_size = Self::entry_array_len(memory, table_data);
+
+        for attempt in 0 .. table_size {
+            count_probe_step();
+            let entry_index = probe_index::<C>(home_index, attempt, table_size);
             let entry = Self::get_entry(memory, table_data, entry_index);
 
-            if entry.is_empty() {
+            if entry.is_tombstone() {
+                continue
+            } else if entry.is_empty() {
                 return None
             } else if entry.hash_equal(hash) &&
-                      &*entry.entry_data::<DataKindKey>(memory) == key {
-                return Some(entry.entry_data::<DataKindValue>(memory))
+                      entry.entry_data_equals::<DataKindKey>(memory, key) {
+                return Some(entry_index)
             }
-
-            entry_index = advance_index(entry_index, table_size);
         }
+
+        None
     }
 
     pub fn insert(memory: &Memory<S>, table_data: &mut Allocation, key: &[u8], value: &[u8]) -> bool {
-        let initial_capacity = Self::capacity(memory, *table_data);
-        if Self::len(memory, *table_data) >= initial_capacity {
-            let new_capacity = if initial_capacity == Size(0) {
-                Size(8)
-            } else {
-                (initial_capacity * 3u32) / 2u32
-            };
-            debug_assert!(new_capacity > Size(0));
-            Self::resize(memory, table_data, new_capacity);
-        }
+        Self::insert_impl(memory, table_data, key, value, false).0
+    }
 
-        let table_size = Self::entry_array_len(memory, *table_data);
-        let hash = hash_for(key);
-        let mut entry_index = index_in_table(hash, table_size);
-        let mut key_added = false;
+    pub fn insert_replace(memory: &Memory<S>,
+                          table_data: &mut Allocation,
+                          key: &[u8],
+                          value: &[u8])
+                          -> Option<Vec<u8>> {
+        Self::insert_impl(memory, table_data, key, value, true).1
+    }
+
+    fn insert_impl(memory: &Memory<S>,
+                   table_data: &mut Allocation,
+                   key: &[u8],
+                   value: &[u8],
+                   copy_old_value: bool)
+                   -> (bool, Option<Vec<u8>>) {
+        Self::assert_not_iterating(memory, *table_data);
+        Self::reserve_entry(memory, table_data);
 
-        for _ in 0 .. table_size {
+        let table_size = Self::entry_array_len(memory, *table_data);
+        let hash = hash_for(Self::seed(memory, *table_data), key);
+        let home_index = index_in_table(hash, table_size);
+        let mut free_index = None;
+        let mut first_tombstone = None;
+        let mut old_value = None;
+        let mut key_found = false;
+
+        for attempt in 0 .. table_size {
+            count_probe_step();
+            let entry_index = probe_index::<C>(home_index, attempt, table_size);
             let mut entry = Self::get_entry(memory, *table_data, entry_index);
 
-            if entry.is_empty() {
-                entry.init_non_empty(memory, hash);
-                entry.set_entry_data::<DataKindKey>(memory, key);
-                entry.set_entry_data::<DataKindValue>(memory, value);
+            if entry.is_tombstone() {
+                // The key might still come later in the probe sequence, so
+                // only remember the tombstone for now.
+                first_tombstone = first_tombstone.or(Some(entry_index));
+                continue
+            }
 
-                let old_len = Self::len(memory, *table_data);
-                Self::set_len(memory, *table_data, old_len + Size(1));
-                debug_assert_eq!(Self::len(memory, *table_data), old_len + Size(1));
-                key_added = true;
+            if entry.is_empty() {
+                free_index = Some(entry_index);
                 break
             }
 
             if entry.hash_equal(hash) &&
-               &*entry.entry_data::<DataKindKey>(memory) == key {
+               entry.entry_data_equals::<DataKindKey>(memory, key) {
                 debug_assert!(!entry.is_empty());
+                if copy_old_value {
+                    // This has to happen before set_entry_data() frees the
+                    // old value's storage.
+                    old_value = Some(entry.entry_data::<DataKindValue>(memory).to_vec());
+                }
                 entry.set_entry_data::<DataKindValue>(memory, value);
+                key_found = true;
                 break
             }
+        }
 
-            entry_index = advance_index(entry_index, table_size);
+        if !key_found {
+            let entry_index = first_tombstone.or(free_index).expect("no free entry found");
+
+            let mut entry = Self::take_slot(memory, *table_data, entry_index);
+            entry.init_non_empty(memory, hash);
+            entry.set_entry_data::<DataKindKey>(memory, key);
+            entry.set_entry_data::<DataKindValue>(memory, value);
+
+            let old_len = Self::len(memory, *table_data);
+            Self::set_len(memory, *table_data, old_len + Size(1));
+            debug_assert_eq!(Self::len(memory, *table_data), old_len + Size(1));
         }
 
-        #[cfg(debug_assertions)]
+        let key_added = !key_found;
+
+        // These checks make every insert considerably slower, so they are
+        // opt-in even in debug builds.
+        #[cfg(feature = "hashtable-paranoid")]
         {
+            // The lookups below are not part of the insert's probe count.
+            #[cfg(feature = "hashtable-metrics")]
+            let probe_steps = PROBE_STEPS.with(|steps| steps.get());
+
+            let entry_index = Self::find_slot(memory, *table_data, key).unwrap();
             let actual_entry = Self::get_entry(memory, *table_data, entry_index);
             assert!(actual_entry.hash_equal(hash));
             assert!(!actual_entry.is_empty());
@@ -365,55 +1359,260 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
             assert_eq!(&*actual_entry.entry_data::<DataKindValue>(memory), value);
             assert_eq!(Self::find(memory, *table_data, key).as_ref().map(|x| &**x), Some(value));
             Self::sanity_check_entry(memory, *table_data, entry_index);
+
+            #[cfg(feature = "hashtable-metrics")]
+            PROBE_STEPS.with(|steps| steps.set(probe_steps));
         }
 
-        key_added
+        (key_added, old_value)
+    }
+
+    fn update<F>(memory: &Memory<S>, table_data: &mut Allocation, key: &[u8], f: F)
+        where F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>
+    {
+        Self::assert_not_iterating(memory, *table_data);
+
+        let hash = hash_for(Self::seed(memory, *table_data), key);
+        let (existing_entry, empty_entry) = Self::find_entry_or_empty_slot(memory, *table_data, key, hash);
+
+        let new_value = {
+            let old_value = existing_entry.map(|entry_index| {
+                Self::get_entry(memory, *table_data, entry_index).entry_data::<DataKindValue>(memory)
+            });
+            f(old_value.as_deref())
+        };
+
+        match (existing_entry, new_value) {
+            (Some(entry_index), Some(new_value)) => {
+                let mut entry = Self::get_entry(memory, *table_data, entry_index);
+                entry.set_entry_data::<DataKindValue>(memory, &new_value);
+            }
+            (Some(entry_index), None) => {
+                Self::remove_at(memory, *table_data, entry_index);
+            }
+            (None, Some(new_value)) => {
+                Self::insert_new_entry(memory, table_data, empty_entry, key, hash, &new_value);
+            }
+            (None, None) => {}
+        }
+    }
+
+    fn compare_and_swap(memory: &Memory<S>,
+                        table_data: &mut Allocation,
+                        key: &[u8],
+                        expected: Option<&[u8]>,
+                        new: &[u8])
+                        -> bool {
+        Self::assert_not_iterating(memory, *table_data);
+
+        let hash = hash_for(Self::seed(memory, *table_data), key);
+        let (existing_entry, empty_entry) = Self::find_entry_or_empty_slot(memory, *table_data, key, hash);
+
+        match (existing_entry, expected) {
+            (Some(entry_index), Some(expected)) => {
+                let mut entry = Self::get_entry(memory, *table_data, entry_index);
+
+                if !entry.entry_data_equals::<DataKindValue>(memory, expected) {
+                    return false
+                }
+
+                entry.set_entry_data::<DataKindValue>(memory, new);
+                true
+            }
+            (None, None) => {
+                Self::insert_new_entry(memory, table_data, empty_entry, key, hash, new);
+                true
+            }
+            (Some(_), None) | (None, Some(_)) => false,
+        }
+    }
+
+    // Finds either the entry for `key` or the first free slot (empty or
+    // tombstone) where it would go. Stops at the first empty slot.
+    fn find_entry_or_empty_slot(memory: &Memory<S>,
+                                table_data: Allocation,
+                                key: &[u8],
+                                hash: u64)
+                                -> (Option<u32>, Option<u32>) {
+        let table_size = Self::entry_array_len(memory, table_data);
+        let mut empty_entry = None;
+
+        if table_size > 0 {
+            let home_index = index_in_table(hash, table_size);
+
+            for attempt in 0 .. table_size {
+                count_probe_step();
+                let entry_index = probe_index::<C>(home_index, attempt, table_size);
+                let entry = Self::get_entry(memory, table_data, entry_index);
+
+                if entry.is_tombstone() {
+                    empty_entry = empty_entry.or(Some(entry_index));
+                    continue
+                }
+
+                if entry.is_empty() {
+                    empty_entry = empty_entry.or(Some(entry_index));
+                    break
+                }
+
+                if entry.hash_equal(hash) &&
+                   entry.entry_data_equals::<DataKindKey>(memory, key) {
+                    return (Some(entry_index), empty_entry)
+                }
+            }
+        }
+
+        (None, empty_entry)
+    }
+
+    // Adds an entry for `key`, which must not be in the table yet, using the
+    // slot found by `find_entry_or_empty_slot()` if there is still room.
+    fn insert_new_entry(memory: &Memory<S>,
+                        table_data: &mut Allocation,
+                        empty_entry: Option<u32>,
+                        key: &[u8],
+                        hash: u64,
+                        value: &[u8]) {
+        let len = Self::len(memory, *table_data);
+        let tombstone_count = Self::tombstone_count(memory, *table_data);
+
+        match empty_entry {
+            Some(entry_index) if len + tombstone_count < Self::capacity(memory, *table_data) => {
+                let mut entry = Self::take_slot(memory, *table_data, entry_index);
+                entry.init_non_empty(memory, hash);
+                entry.set_entry_data::<DataKindKey>(memory, key);
+                entry.set_entry_data::<DataKindValue>(memory, value);
+                Self::set_len(memory, *table_data, len + Size(1));
+            }
+            _ => {
+                // The table has to grow or be rebuilt first
+                Self::insert(memory, table_data, key, value);
+            }
+        }
+    }
+
+    // Makes room for one more entry. A full table grows. With tombstones
+    // taking up the rest of the capacity, the table is rebuilt at the same
+    // capacity instead, so that lookups don't have to probe ever longer
+    // sequences.
+    fn reserve_entry(memory: &Memory<S>, table_data: &mut Allocation) {
+        let len = Self::len(memory, *table_data);
+        let capacity = Self::capacity(memory, *table_data);
+
+        if len >= capacity {
+            Self::resize(memory, table_data, Self::grown_capacity(capacity));
+        } else if len + Self::tombstone_count(memory, *table_data) >= capacity {
+            Self::resize(memory, table_data, capacity);
+        }
+    }
+
+    // Returns the empty entry at `entry_index` for storing a new entry in it,
+    // keeping track of reused tombstones.
+    fn take_slot(memory: &Memory<S>, table_data: Allocation, entry_index: u32) -> Entry<C, S> {
+        let entry = Self::get_entry(memory, table_data, entry_index);
+        debug_assert!(entry.is_empty());
+
+        if entry.is_tombstone() {
+            let tombstone_count = Self::tombstone_count(memory, table_data);
+            Self::set_tombstone_count(memory, table_data, tombstone_count - Size(1));
+        }
+
+        entry
     }
 
     fn delete_table(memory: &Memory<S>, table_data: Allocation) {
+        Self::assert_not_iterating(memory, table_data);
+
         let table_size = Self::entry_array_len(memory, table_data);
 
+        // Only out-of-line data has to be freed entry by entry. The entries
+        // themselves don't need to be cleared since freeing the table
+        // overwrites the whole entry array anyway.
         for entry_index in 0 .. table_size {
             let mut entry = Self::get_entry(memory, table_data, entry_index);
             if !entry.is_empty() {
-                entry.clear(memory);
+                entry.delete_entry_data::<DataKindKey>(memory);
+                entry.delete_entry_data::<DataKindValue>(memory);
             }
         }
 
         memory.free(table_data);
     }
 
-    fn remove_entry(memory: &Memory<S>, table_data: Allocation, key: &[u8]) -> bool {
-        if Self::len(memory, table_data) == Size(0) {
-            return false
-        }
+    fn remove_entry(memory: &Memory<S>, table_data: Allocation, key: &[u8]) -> Option<Size> {
+        Self::find_slot(memory, table_data, key).map(|index| {
+            Self::remove_at(memory, table_data, index)
+        })
+    }
 
-        let table_size = Self::entry_array_len(memory, table_data);
-        let hash = hash_for(key);
-        let mut index = index_in_table(hash, table_size);
+    // Removes the (non-empty) entry at `index` and returns the number of
+    // out-of-line bytes that were freed.
+    fn remove_at(memory: &Memory<S>, table_data: Allocation, index: u32) -> Size {
+        Self::assert_not_iterating(memory, table_data);
 
-        loop {
-            let mut entry = Self::get_entry(memory, table_data, index);
+        let freed = Self::get_entry(memory, table_data, index).clear(memory);
+        Self::finish_removal_at(memory, table_data, index);
+        freed
+    }
 
-            if entry.is_empty() {
-                return false
-            } else if entry.hash_equal(hash) &&
-                      &*entry.entry_data::<DataKindKey>(memory) == key {
-                entry.clear(memory);
+    // Like `remove_at()` but for an entry that has been copied to another
+    // table, so its out-of-line data must not be freed.
+    fn detach_at(memory: &Memory<S>, table_data: Allocation, index: u32) {
+        Self::assert_not_iterating(memory, table_data);
+
+        Self::get_entry(memory, table_data, index).forget(memory);
+        Self::finish_removal_at(memory, table_data, index);
+    }
 
+    fn finish_removal_at(memory: &Memory<S>, table_data: Allocation, index: u32) {
+        match C::PROBE_SEQUENCE {
+            ProbeSequence::Linear => {
                 Self::repair_block_after_deletion(memory, table_data, index);
+            }
+            ProbeSequence::Triangular => {
+                // Moving entries back only works for linear probing. Leave a
+                // tombstone so that lookups don't stop at this slot.
+                Self::get_entry(memory, table_data, index).make_tombstone(memory);
+                let tombstone_count = Self::tombstone_count(memory, table_data);
+                Self::set_tombstone_count(memory, table_data, tombstone_count + Size(1));
+            }
+        }
 
-                let old_len = Self::len(memory, table_data);
-                Self::set_len(memory, table_data, old_len - Size(1));
+        let old_len = Self::len(memory, table_data);
+        Self::set_len(memory, table_data, old_len - Size(1));
+    }
 
-                return true
-            }
+    // Moves the entry at `index` of `old_data` to `new_data` as it is, i.e.
+    // without copying its out-of-line data. The key must not be present in
+    // `new_data` yet. Used for incremental rehashing.
+    fn migrate_entry(memory: &Memory<S>, old_data: Allocation, index: u32, new_data: &mut Allocation) {
+        let entry = Self::get_entry(memory, old_data, index);
+        debug_assert!(!entry.is_empty());
 
-            index = advance_index(index, table_size);
+        Self::reserve_entry(memory, new_data);
+        let len = Self::len(memory, *new_data);
+
+        let table_size = Self::entry_array_len(memory, *new_data);
+        let home_index = index_in_table(entry.hash(), table_size);
+
+        for attempt in 0 .. table_size {
+            let insertion_index = probe_index::<C>(home_index, attempt, table_size);
+            let new_entry = Self::get_entry(memory, *new_data, insertion_index);
+
+            if new_entry.is_empty() {
+                let new_entry = Self::take_slot(memory, *new_data, insertion_index);
+                memory.copy_nonoverlapping(entry.addr, new_entry.addr, C::ENTRY_SIZE);
+                Self::set_len(memory, *new_data, len + Size(1));
+                Self::detach_at(memory, old_data, index);
+                return
+            }
         }
+
+        panic!("no free entry found while migrating entry");
     }
 
     fn repair_block_after_deletion(memory: &Memory<S>, table_data: Allocation, deletion_index: u32) {
+        debug_assert_eq!(C::PROBE_SEQUENCE, ProbeSequence::Linear);
         let table_size = Self::entry_array_len(memory, table_data);
 
         let mut search_index = advance_index(deletion_index, table_size);
@@ -461,18 +1660,23 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
 
     fn assert_is_valid_entry_for_table(memory: &Memory<S>, table_data: Allocation, entry: &Entry<C, S>) {
         let entry_array_start = table_data.addr + HEADER_SIZE;
-        let last_valid_entry_addr = entry_array_start + C::ENTRY_SIZE * (Self::entry_array_len(memory, table_data) - 1);
-        debug_assert!(entry.addr >= entry_array_start && entry.addr <= last_valid_entry_addr);
+        let entry_array = Allocation::new(entry_array_start,
+                                          C::ENTRY_SIZE * Self::entry_array_len(memory, table_data));
+        debug_assert!(entry_array.contains_range(entry.addr, C::ENTRY_SIZE));
         debug_assert!((entry.addr.as_u32() - entry_array_start.as_u32()) % C::ENTRY_SIZE.as_u32() == 0,
             "misaligned entry addr");
     }
 
     fn resize(memory: &Memory<S>, table_data: &mut Allocation, new_capacity: Size) {
-        let new_table_data = Self::alloc_with_capacity(memory, new_capacity);
+        Self::assert_not_iterating(memory, *table_data);
+
+        let new_capacity = Self::effective_capacity(new_capacity);
+        let seed = Self::seed(memory, *table_data);
+        let new_table_data = Self::alloc_with_capacity(memory, new_capacity, seed);
         let new_table_size = Self::entry_array_len(memory, new_table_data);
-        debug_assert!(new_table_size > 0);
         assert_eq!(new_table_size, Self::entry_array_len_for_capacity(new_capacity));
         let len = Self::len(memory, *table_data);
+        debug_assert!(new_table_size > 0 || len == Size(0));
 
         let mut written = 0;
 
@@ -484,9 +1688,10 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
                 continue
             }
 
-            let mut insertion_index = index_in_table(read_entry.hash(), new_table_size);
+            let home_index = index_in_table(read_entry.hash(), new_table_size);
 
-            for _ in 0 .. new_table_size {
+            for attempt in 0 .. new_table_size {
+                let insertion_index = probe_index::<C>(home_index, attempt, new_table_size);
                 let new_entry = Self::get_entry(memory, new_table_data, insertion_index);
 
                 if new_entry.is_empty() {
@@ -495,25 +1700,23 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
                     // TODO: do some assertions
 
                     written += 1;
-                    debug_assert!(written <= len.as_usize(),
+                    debug_assert!(written <= len.bytes(),
                         "more non-null entries than len() in table. \
-                         written = {}, len={}", written, len.as_usize());
+                         written = {}, len={}", written, len.bytes());
                     continue 'outer
                 }
-
-                insertion_index = advance_index(insertion_index, new_table_size);
             }
 
             panic!("no free entry found? len={}, old_capacity={}, \
                     old_table_size={}, new_capacity={}, new_table_size={}",
-                len.as_usize(),
-                Self::capacity(memory, *table_data).as_usize(),
+                len.bytes(),
+                Self::capacity(memory, *table_data).bytes(),
                 Self::entry_array_len(memory, *table_data),
                 new_capacity.0,
                 new_table_size);
         }
 
-        debug_assert_eq!(written, len.as_usize());
+        debug_assert_eq!(written, len.bytes());
         Self::set_len(memory, new_table_data, len);
 
         memory.free(*table_data);
@@ -529,73 +1732,244 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
         let table_size = Self::entry_array_len(memory, table_data);
         let min_entry_index = index_in_table(entry.hash(), table_size);
 
-        let mut i = entry_index;
-        while i != min_entry_index {
-            assert!(!Self::get_entry(memory, table_data, i).is_empty(),
+        // Lookups stop at the first empty slot, so there must not be one
+        // between the home slot and the entry.
+        for attempt in 0 .. table_size {
+            let i = probe_index::<C>(min_entry_index, attempt, table_size);
+
+            if i == entry_index {
+                return
+            }
+
+            let probed_entry = Self::get_entry(memory, table_data, i);
+            assert!(!probed_entry.is_empty() || probed_entry.is_tombstone(),
             "table_size = {}, index = {}, min_entry_index={}, i={}",
             table_size,
             entry_index,
             min_entry_index,
             i);
-
-            if i == 0 {
-                i = table_size - 1;
-            } else {
-                i -= 1;
-            }
         }
+
+        panic!("entry {} cannot be reached from its home index {}", entry_index, min_entry_index);
     }
 
     fn sanity_check_table(memory: &Memory<S>, table_data: Allocation) {
+        let mut tombstone_count = 0;
+
         for index in 0 .. Self::entry_array_len(memory, table_data) {
             Self::sanity_check_entry(memory, table_data, index);
+
+            if Self::get_entry(memory, table_data, index).is_tombstone() {
+                tombstone_count += 1;
+            }
         }
+
+        assert_eq!(Self::tombstone_count(memory, table_data), Size(tombstone_count));
     }
 
-    fn iter<F: FnMut(&[u8], &[u8])>(memory: &Memory<S>, table_data: Allocation, mut f: F) {
-        let table_size = Self::entry_array_len(memory, table_data);
-        for index in 0 .. table_size {
-            let entry = Self::get_entry(memory, table_data, index);
+    fn recount(memory: &Memory<S>, table_data: Allocation) -> Size {
+        let len = (0 .. Self::entry_array_len(memory, table_data))
+            .filter(|&index| !Self::get_entry(memory, table_data, index).is_empty())
+            .count();
+        let tombstone_count = (0 .. Self::entry_array_len(memory, table_data))
+            .filter(|&index| Self::get_entry(memory, table_data, index).is_tombstone())
+            .count();
+
+        let len = Size::from_bytes(len);
+        Self::set_len(memory, table_data, len);
+        Self::set_tombstone_count(memory, table_data, Size::from_bytes(tombstone_count));
+        len
+    }
 
-            if !entry.is_empty() {
-                f(&*entry.entry_data::<DataKindKey>(memory),
-                  &*entry.entry_data::<DataKindValue>(memory));
-            }
+    fn entry_info(memory: &Memory<S>, table_data: Allocation, index: usize) -> Option<EntryInfo> {
+        if index >= Self::entry_array_len(memory, table_data) as usize {
+            return None
         }
-    }
 
-    #[inline]
-    fn get_entry(memory: &Memory<S>, table_data: Allocation, entry_index: u32) -> Entry<C, S> {
-        debug_assert!(entry_index < Self::entry_array_len(memory, table_data));
-        let entry_addr = Self::entry_addr(table_data, entry_index);
-        Entry {
-            metadata: u64::read_at(memory, entry_addr),
-            addr: entry_addr,
-            config: PhantomData,
-            storage: PhantomData,
+        let entry = Self::get_entry(memory, table_data, index as u32);
+
+        if entry.is_empty() {
+            return Some(EntryInfo {
+                is_empty: true,
+                hash: 0,
+                key_inline: false,
+                key_len: Size(0),
+                value_inline: false,
+                value_len: Size(0),
+            })
         }
-    }
 
-    #[inline]
-    fn set_len(storage: &Memory<S>, table_data: Allocation, len: Size) {
-        len.write_at(storage, table_data.addr + LEN_OFFSET);
+        Some(EntryInfo {
+            is_empty: false,
+            hash: entry.hash(),
+            key_inline: entry.is_entry_data_inline::<DataKindKey>(),
+            key_len: Size::from_bytes(entry.entry_data::<DataKindKey>(memory).len()),
+            value_inline: entry.is_entry_data_inline::<DataKindValue>(),
+            value_len: Size::from_bytes(entry.entry_data::<DataKindValue>(memory).len()),
+        })
     }
 
-    #[inline]
-    fn set_capacity(storage: &Memory<S>, table_data: Allocation, capacity: Size) {
-        capacity.write_at(storage, table_data.addr + CAPACITY_OFFSET);
-    }
+    fn dump(memory: &Memory<S>, table_data: Allocation) -> String {
+        use std::fmt::Write;
 
-    #[inline]
-    fn len(storage: &Memory<S>, table_data: Allocation) -> Size {
-        Size::read_at(storage, table_data.addr + LEN_OFFSET)
-    }
+        fn storage_kind(is_inline: bool) -> &'static str {
+            if is_inline { "inline" } else { "indirect" }
+        }
+
+        let table_size = Self::entry_array_len(memory, table_data);
+        let mut out = format!("len = {}, capacity = {}, slots = {}\n",
+                              Self::len(memory, table_data).as_u32(),
+                              Self::capacity(memory, table_data).as_u32(),
+                              table_size);
+
+        for index in 0 .. table_size {
+            let entry = Self::get_entry(memory, table_data, index);
+
+            if entry.is_tombstone() {
+                writeln!(out, "{}: tombstone", index).unwrap();
+                continue
+            }
+
+            if entry.is_empty() {
+                writeln!(out, "{}: empty", index).unwrap();
+                continue
+            }
+
+            // The distance is the number of the probing attempt that reaches
+            // this slot, which is not the offset from the home slot unless
+            // probing is linear.
+            let home_index = index_in_table(entry.hash(), table_size);
+            let probe_distance = (0 .. table_size)
+                .find(|&attempt| probe_index::<C>(home_index, attempt, table_size) == index)
+                .expect("slot is not on the probe sequence of its entry");
+
+            writeln!(out,
+                     "{}: hash = {:x}, home = {}, distance = {}, key = {}, value = {}",
+                     index,
+                     entry.hash(),
+                     home_index,
+                     probe_distance,
+                     storage_kind(entry.is_entry_data_inline::<DataKindKey>()),
+                     storage_kind(entry.is_entry_data_inline::<DataKindValue>())).unwrap();
+        }
+
+        out
+    }
+
+    fn iter<F: FnMut(&[u8], &[u8])>(memory: &Memory<S>, table_data: Allocation, mut f: F) {
+        Self::try_iter(memory, table_data, |key, value| {
+            f(key, value);
+            ControlFlow::Continue(())
+        });
+    }
+
+    fn try_iter<F>(memory: &Memory<S>, table_data: Allocation, mut f: F) -> bool
+        where F: FnMut(&[u8], &[u8]) -> ControlFlow<()>
+    {
+        #[cfg(debug_assertions)]
+        let _iteration_guard = Self::iteration_guard(memory, table_data);
+
+        let table_size = Self::entry_array_len(memory, table_data);
+        for index in 0 .. table_size {
+            let entry = Self::get_entry(memory, table_data, index);
+
+            if !entry.is_empty() {
+                let flow = f(&entry.entry_data::<DataKindKey>(memory),
+                             &entry.entry_data::<DataKindValue>(memory));
+
+                if flow.is_break() {
+                    return false
+                }
+            }
+        }
+
+        true
+    }
+
+    // While iterating, debug builds keep the table header borrowed, so that
+    // modifications through another handle to the same table are detected.
+    #[cfg(debug_assertions)]
+    fn iteration_guard(memory: &Memory<S>, table_data: Allocation) -> MemRef<'_> {
+        memory.get_bytes(table_data.addr, HEADER_SIZE)
+    }
+
+    #[inline]
+    fn assert_not_iterating(_memory: &Memory<S>, _table_data: Allocation) {
+        #[cfg(debug_assertions)]
+        assert!(!_memory.is_borrowed(_table_data.addr, HEADER_SIZE),
+                "Hash table modified while it is being iterated");
+    }
+
+    fn iter_sorted<F: FnMut(&[u8], &[u8])>(memory: &Memory<S>, table_data: Allocation, mut f: F) {
+        #[cfg(debug_assertions)]
+        let _iteration_guard = Self::iteration_guard(memory, table_data);
+
+        let table_size = Self::entry_array_len(memory, table_data);
+
+        let mut entries: Vec<Entry<C, S>> = (0 .. table_size)
+            .map(|index| Self::get_entry(memory, table_data, index))
+            .filter(|entry| !entry.is_empty())
+            .collect();
+
+        entries.sort_by(|a, b| {
+            let a = a.entry_data::<DataKindKey>(memory);
+            let b = b.entry_data::<DataKindKey>(memory);
+            (*a).cmp(&*b)
+        });
+
+        for entry in entries {
+            f(&entry.entry_data::<DataKindKey>(memory),
+              &entry.entry_data::<DataKindValue>(memory));
+        }
+    }
+
+    #[inline]
+    fn get_entry(memory: &Memory<S>, table_data: Allocation, entry_index: u32) -> Entry<C, S> {
+        debug_assert!(entry_index < Self::entry_array_len(memory, table_data));
+        let entry_addr = Self::entry_addr(table_data, entry_index);
+        Entry {
+            metadata: u64::read_at(memory, entry_addr),
+            addr: entry_addr,
+            config: PhantomData,
+            storage: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn set_len(storage: &Memory<S>, table_data: Allocation, len: Size) {
+        len.write_at(storage, table_data.addr + LEN_OFFSET);
+    }
+
+    #[inline]
+    fn set_capacity(storage: &Memory<S>, table_data: Allocation, capacity: Size) {
+        capacity.write_at(storage, table_data.addr + CAPACITY_OFFSET);
+    }
+
+    #[inline]
+    fn len(storage: &Memory<S>, table_data: Allocation) -> Size {
+        Size::read_at(storage, table_data.addr + LEN_OFFSET)
+    }
 
     #[inline]
     fn capacity(storage: &Memory<S>, table_data: Allocation) -> Size {
         Size::read_at(storage, table_data.addr + CAPACITY_OFFSET)
     }
 
+    #[inline]
+    fn set_tombstone_count(storage: &Memory<S>, table_data: Allocation, tombstone_count: Size) {
+        tombstone_count.write_at(storage, table_data.addr + TOMBSTONE_COUNT_OFFSET);
+    }
+
+    #[inline]
+    fn tombstone_count(storage: &Memory<S>, table_data: Allocation) -> Size {
+        Size::read_at(storage, table_data.addr + TOMBSTONE_COUNT_OFFSET)
+    }
+
+    #[inline]
+    fn seed(storage: &Memory<S>, table_data: Allocation) -> u64 {
+        u64::read_at(storage, table_data.addr + SEED_OFFSET)
+    }
+
     #[inline]
     fn entry_array_len(storage: &Memory<S>, table_data: Allocation) -> u32 {
         let capacity = Self::capacity(storage, table_data);
@@ -614,23 +1988,55 @@ impl<S: Storage, C: HashTableConfig> RawTable<S, C> {
 
     #[inline]
     fn entry_array_len_for_capacity(capacity: Size) -> u32 {
-        (capacity.as_u32() * 3) / 2
+        let len = (capacity.as_u32() * 3) / 2;
+
+        match C::PROBE_SEQUENCE {
+            ProbeSequence::Linear => len,
+            // Triangular probing only visits every slot if the number of
+            // slots is a power of two.
+            ProbeSequence::Triangular if len == 0 => 0,
+            ProbeSequence::Triangular => len.next_power_of_two(),
+        }
     }
 }
 
 
 #[inline]
-fn hash_for(key: &[u8]) -> u64 {
+fn hash_for(seed: u64, key: &[u8]) -> u64 {
     use metrohash::MetroHash;
     use std::hash::Hasher;
-    let mut hasher = MetroHash::default();
+    let mut hasher = MetroHash::with_seed(seed);
     hasher.write(key);
     hasher.finish() as u64
 }
 
+fn random_seed() -> u64 {
+    // RandomState is seeded from the OS, so this saves us a dependency on a
+    // random number generator.
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish()
+}
+
 #[inline]
 fn index_in_table(hash: u64, table_size: u32) -> u32 {
-    hash as u32 % table_size
+    // Entries only store the masked hash, so the home index has to be
+    // computed from the same bits in order to be stable across resizes.
+    ((hash & ENTRY_META_HASH_MASK) % table_size as u64) as u32
+}
+
+// The slot to try in the given attempt of a lookup that started at
+// `home_index`.
+#[inline]
+fn probe_index<C: HashTableConfig>(home_index: u32, attempt: u32, table_size: u32) -> u32 {
+    debug_assert!(home_index < table_size && attempt < table_size);
+
+    let offset = match C::PROBE_SEQUENCE {
+        ProbeSequence::Linear => attempt as u64,
+        ProbeSequence::Triangular => (attempt as u64 * (attempt as u64 + 1)) / 2,
+    };
+
+    ((home_index as u64 + offset) % table_size as u64) as u32
 }
 
 #[inline]
@@ -644,6 +2050,7 @@ fn advance_index(index: u32, table_size: u32) -> u32 {
 mod tests {
     use super::*;
     use MemStore;
+    use allocator::SizeClasses;
 
     fn create_memory(size: usize) -> Memory<MemStore> {
         let memory = Memory::new(MemStore::new(size));
@@ -672,4 +2079,1458 @@ mod tests {
 
         hash_table.sanity_check_table();
     }
+
+    #[test]
+    fn test_concurrent_find() {
+        use std::thread;
+
+        let mut memory = create_memory(100000);
+        let data = {
+            let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+            for i in 0u32 .. 100 {
+                let key = [i as u8, (i >> 8) as u8];
+                let value = [i as u8; 10];
+                hash_table.insert(&key, &value);
+            }
+
+            hash_table.allocation()
+        };
+
+        let memory = memory.shared_view();
+        let hash_table: ReadOnlyHashTable<_, DefaultHashTableConfig> =
+            HashTable::open_readonly(&memory, data).unwrap();
+        let hash_table = &hash_table;
+
+        thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                scope.spawn(move || {
+                    for i in 0u32 .. 100 {
+                        let key = [i as u8, (i >> 8) as u8];
+                        let value = [i as u8; 10];
+                        assert_eq!(hash_table.find(&key).as_ref().map(|x| &**x), Some(&value[..]));
+                    }
+                });
+            }
+        });
+    }
+
+    enum LargeInlineConfig {}
+    impl HashTableConfig for LargeInlineConfig {
+        const MAX_INLINE_KEY_LEN: Size = Size(16);
+        const MAX_INLINE_VALUE_LEN: Size = Size(32);
+    }
+
+    fn check_footprint<C: HashTableConfig>(capacity: Size) {
+        let memory = create_memory(100000);
+        let hash_table: HashTable<_, C> = HashTable::with_capacity(&memory, capacity);
+
+        assert_eq!(hash_table.footprint(), hash_table.data.size);
+        assert_eq!(hash_table.footprint(), HashTable::<MemStore, C>::bytes_for_capacity(capacity));
+    }
+
+    #[test]
+    fn test_footprint() {
+        for &capacity in &[0, 1, 8, 100, 1000] {
+            check_footprint::<DefaultHashTableConfig>(Size(capacity));
+            check_footprint::<LargeInlineConfig>(Size(capacity));
+        }
+    }
+
+    #[test]
+    fn test_iter_sorted() {
+        use rand::{thread_rng, Rng};
+
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        let mut rng = thread_rng();
+        let mut expected = vec![];
+
+        for i in 0 .. 200u32 {
+            let key_len = rng.gen_range(0, 10);
+            let key: Vec<u8> = (0 .. key_len).map(|_| rng.gen()).collect();
+            let value = [i as u8; 3];
+
+            if hash_table.insert(&key, &value) {
+                expected.push((key, value.to_vec()));
+            } else {
+                expected.iter_mut().find(|&&mut (ref k, _)| *k == key).unwrap().1 = value.to_vec();
+            }
+        }
+
+        expected.sort();
+
+        let mut actual = vec![];
+        hash_table.iter_sorted(|key, value| actual.push((key.to_vec(), value.to_vec())));
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_remove_and_report() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        hash_table.insert(b"abc", b"xyz");
+        hash_table.insert(b"key", &[7u8; 100]);
+        hash_table.insert(b"long key", &[8u8; 20]);
+
+        // One length byte is stored in front of each out-of-line key or value
+        assert_eq!(hash_table.remove_and_report(b"abc"), Some(Size(0)));
+        assert_eq!(hash_table.remove_and_report(b"key"), Some(Size(101)));
+        assert_eq!(hash_table.remove_and_report(b"long key"), Some(Size(9 + 21)));
+        assert_eq!(hash_table.remove_and_report(b"key"), None);
+        assert_eq!(hash_table.len(), 0);
+
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_insert_replace() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        assert_eq!(hash_table.insert_replace(b"a", b"1"), None);
+        assert_eq!(hash_table.insert_replace(b"a", &[2u8; 50]), Some(b"1".to_vec()));
+        assert_eq!(hash_table.insert_replace(b"a", b"3"), Some(vec![2u8; 50]));
+        assert_eq!(hash_table.find(b"a").as_ref().map(|x| &**x), Some(&b"3"[..]));
+        assert_eq!(hash_table.len(), 1);
+
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_dump() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::with_capacity(&memory, Size(2));
+
+        hash_table.insert(b"a", b"1");
+        hash_table.insert(b"long key", &[0u8; 10]);
+
+        let dump = hash_table.dump();
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines[0], "len = 2, capacity = 2, slots = 3");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines.iter().filter(|l| l.ends_with(": empty")).count(), 1);
+        assert!(lines.iter().any(|l| l.ends_with("key = inline, value = inline")));
+        assert!(lines.iter().any(|l| l.ends_with("key = indirect, value = indirect")));
+        assert!(lines[1 ..].iter().all(|l| l.contains("distance = ") || l.ends_with(": empty")));
+    }
+
+    #[test]
+    fn test_dump_triangular() {
+        enum TriangularConfig {}
+        impl HashTableConfig for TriangularConfig {
+            const PROBE_SEQUENCE: ProbeSequence = ProbeSequence::Triangular;
+        }
+
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, TriangularConfig> =
+            HashTable::with_capacity_and_seed(&memory, Size(80), 1);
+
+        for i in 0 .. 80u8 {
+            hash_table.insert(&[i], b"x");
+        }
+        for i in 0 .. 10u8 {
+            assert!(hash_table.remove(&[i]));
+        }
+
+        let dump = hash_table.dump();
+        let slots = RawTable::<MemStore, TriangularConfig>::entry_array_len(&memory, hash_table.data);
+        let field = |line: &str, name: &str| -> u32 {
+            line.split(name).nth(1).unwrap().split(',').next().unwrap().parse().unwrap()
+        };
+
+        assert_eq!(dump.lines().filter(|l| l.ends_with(": tombstone")).count(), 10);
+
+        let mut max_distance = 0;
+        for line in dump.lines().skip(1).filter(|l| l.contains("distance = ")) {
+            let index: u32 = line.split(':').next().unwrap().parse().unwrap();
+            let home = field(line, "home = ");
+            let distance = field(line, "distance = ");
+            assert_eq!(probe_index::<TriangularConfig>(home, distance, slots), index);
+            max_distance = max_distance.max(distance);
+        }
+        assert!(max_distance > 1);
+    }
+
+    #[test]
+    fn test_triangular_churn_clears_tombstones() {
+        enum TriangularConfig {}
+        impl HashTableConfig for TriangularConfig {
+            const PROBE_SEQUENCE: ProbeSequence = ProbeSequence::Triangular;
+        }
+
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, TriangularConfig> = HashTable::with_capacity(&memory, Size(32));
+
+        for i in 0 .. 16u32 {
+            hash_table.insert(&i.to_le_bytes(), b"x");
+        }
+
+        // Replace the entries over and over without changing their number.
+        let mut rebuilds = 0;
+        for i in 16 .. 2000u32 {
+            let data = hash_table.allocation();
+            assert!(hash_table.remove(&(i - 16).to_le_bytes()));
+            hash_table.insert(&i.to_le_bytes(), b"x");
+
+            if hash_table.allocation() != data {
+                rebuilds += 1;
+            }
+
+            let tombstones = RawTable::<MemStore, TriangularConfig>::tombstone_count(&memory, hash_table.data);
+            assert!(hash_table.len() + tombstones.bytes() <= hash_table.capacity());
+        }
+
+        assert!(rebuilds > 0);
+        assert_eq!(hash_table.len(), 16);
+        assert_eq!(hash_table.capacity(), 32);
+
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_from_existing() {
+        let memory = create_memory(10000);
+
+        let data = {
+            let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+            hash_table.insert(b"a", b"1");
+            hash_table.insert(b"long key", b"long value");
+            hash_table.allocation()
+        };
+
+        let hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::from_existing(&memory, data).unwrap();
+        assert_eq!(hash_table.len(), 2);
+        assert_eq!(hash_table.find(b"a").as_ref().map(|x| &**x), Some(&b"1"[..]));
+        assert_eq!(hash_table.find(b"long key").as_ref().map(|x| &**x), Some(&b"long value"[..]));
+
+        let other = memory.alloc(data.size);
+        let result: Result<HashTable<_, DefaultHashTableConfig>, _> = HashTable::from_existing(&memory, other);
+        assert!(result.err().unwrap().contains("magic"));
+    }
+
+    #[test]
+    fn test_seed() {
+        let memory = create_memory(100000);
+
+        let home_index = |seed| {
+            let mut hash_table: HashTable<_, DefaultHashTableConfig> =
+                HashTable::with_capacity_and_seed(&memory, Size(100), seed);
+            assert_eq!(hash_table.seed(), seed);
+            hash_table.insert(b"key", b"value");
+
+            let dump = hash_table.dump();
+            let line = dump.lines().find(|l| l.contains("home = ")).unwrap();
+            let home = line.split("home = ").nth(1).unwrap().split(',').next().unwrap().to_string();
+
+            // The seed has to survive resizing
+            for i in 0 .. 200u32 {
+                hash_table.insert(&[i as u8, (i >> 8) as u8], b"x");
+            }
+            assert_eq!(hash_table.seed(), seed);
+            assert_eq!(hash_table.find(b"key").as_ref().map(|x| &**x), Some(&b"value"[..]));
+            assert!(hash_table.remove(b"key"));
+
+            hash_table.delete_table();
+
+            home
+        };
+
+        assert_ne!(home_index(1), home_index(2));
+    }
+
+    #[test]
+    fn test_grow_observer() {
+        use std::sync::{Arc, Mutex};
+
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+        let events = Arc::new(Mutex::new(vec![]));
+
+        {
+            let events = events.clone();
+            hash_table.set_grow_observer(Box::new(move |event| events.lock().unwrap().push(event)));
+        }
+
+        for i in 0 .. 20u8 {
+            hash_table.insert(&[i], &[i]);
+        }
+
+        let bytes = |capacity| HashTable::<MemStore, DefaultHashTableConfig>::bytes_for_capacity(Size(capacity));
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            GrowEvent {
+                old_capacity: Size(0),
+                new_capacity: Size(8),
+                old_byte_size: bytes(0),
+                new_byte_size: bytes(8),
+            },
+            GrowEvent {
+                old_capacity: Size(8),
+                new_capacity: Size(12),
+                old_byte_size: bytes(8),
+                new_byte_size: bytes(12),
+            },
+            GrowEvent {
+                old_capacity: Size(12),
+                new_capacity: Size(18),
+                old_byte_size: bytes(12),
+                new_byte_size: bytes(18),
+            },
+            GrowEvent {
+                old_capacity: Size(18),
+                new_capacity: Size(27),
+                old_byte_size: bytes(18),
+                new_byte_size: bytes(27),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_empty_keys_and_values() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        assert!(hash_table.insert(b"key", b""));
+        assert!(hash_table.insert(b"", b"value"));
+        assert!(hash_table.insert(b"long key", b""));
+
+        assert_eq!(hash_table.find(b"key").as_ref().map(|x| &**x), Some(&b""[..]));
+        assert_eq!(hash_table.find(b"").as_ref().map(|x| &**x), Some(&b"value"[..]));
+        assert_eq!(hash_table.find(b"long key").as_ref().map(|x| &**x), Some(&b""[..]));
+        assert!(hash_table.find(b"missing").is_none());
+
+        // Both key and value empty
+        assert_eq!(hash_table.insert_replace(b"", b""), Some(b"value".to_vec()));
+        assert_eq!(hash_table.find(b"").as_ref().map(|x| &**x), Some(&b""[..]));
+
+        // Switching between empty and indirect values
+        assert_eq!(hash_table.insert_replace(b"key", b"a much longer value"), Some(vec![]));
+        assert_eq!(hash_table.insert_replace(b"key", b""), Some(b"a much longer value".to_vec()));
+        assert_eq!(hash_table.find(b"key").as_ref().map(|x| &**x), Some(&b""[..]));
+
+        let mut entries = vec![];
+        hash_table.iter_sorted(|key, value| entries.push((key.to_vec(), value.to_vec())));
+        assert_eq!(entries, vec![
+            (b"".to_vec(), b"".to_vec()),
+            (b"key".to_vec(), b"".to_vec()),
+            (b"long key".to_vec(), b"".to_vec()),
+        ]);
+
+        assert_eq!(hash_table.remove_and_report(b""), Some(Size(0)));
+        assert!(hash_table.find(b"").is_none());
+        assert_eq!(hash_table.len(), 2);
+
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_entries() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        for i in 0 .. 10u8 {
+            hash_table.insert(&[i], &[i * 2]);
+        }
+
+        let entries = hash_table.entries();
+        assert_eq!(entries.len(), 10);
+
+        let mut even_keys: Vec<u8> = entries.iter()
+                                            .filter(|&&(ref key, _)| key[0] % 2 == 0)
+                                            .map(|&(ref key, _)| key[0])
+                                            .collect();
+        even_keys.sort();
+        assert_eq!(even_keys, vec![0, 2, 4, 6, 8]);
+
+        assert!(entries.into_iter().all(|(key, value)| value[0] == key[0] * 2));
+    }
+
+    #[test]
+    fn test_index_in_table_uses_upper_bits() {
+        let table_size = 997;
+        let hashes: Vec<u64> = (0 .. 1000u64).map(|i| i << 32).collect();
+
+        let max_bucket_occupancy = |index_for: &dyn Fn(u64) -> u32| {
+            let mut buckets = vec![0; table_size as usize];
+            for &hash in &hashes {
+                buckets[index_for(hash) as usize] += 1;
+            }
+            *buckets.iter().max().unwrap()
+        };
+
+        // Truncating the hash to 32 bits first puts everything in one bucket
+        let truncating = max_bucket_occupancy(&|hash| hash as u32 % table_size);
+        let full = max_bucket_occupancy(&|hash| index_in_table(hash, table_size));
+
+        assert_eq!(truncating, 1000);
+        assert!(full <= 2);
+    }
+
+    #[test]
+    fn test_shrink() {
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        for i in 0 .. 100u32 {
+            hash_table.insert(&[i as u8, 1, 2, 3, 4], &[i as u8]);
+        }
+
+        for i in 0 .. 90u32 {
+            assert!(hash_table.remove(&[i as u8, 1, 2, 3, 4]));
+        }
+
+        hash_table.resize(Size(16));
+        assert_eq!(hash_table.capacity(), 16);
+        assert_eq!(hash_table.len(), 10);
+        hash_table.sanity_check_table();
+
+        for i in 90 .. 100u32 {
+            assert_eq!(hash_table.find(&[i as u8, 1, 2, 3, 4]).as_ref().map(|x| &**x), Some(&[i as u8][..]));
+        }
+
+        hash_table.shrink_to_fit();
+        assert_eq!(hash_table.capacity(), 10);
+        hash_table.sanity_check_table();
+
+        for i in 90 .. 100u32 {
+            assert_eq!(hash_table.find(&[i as u8, 1, 2, 3, 4]).as_ref().map(|x| &**x), Some(&[i as u8][..]));
+        }
+
+        // Growing again works as usual
+        hash_table.insert(b"new", b"entry");
+        assert_eq!(hash_table.len(), 11);
+        hash_table.sanity_check_table();
+
+        for i in 90 .. 100u32 {
+            assert!(hash_table.remove(&[i as u8, 1, 2, 3, 4]));
+        }
+        assert!(hash_table.remove(b"new"));
+
+        hash_table.shrink_to_fit();
+        assert_eq!(hash_table.capacity(), 0);
+        assert!(hash_table.find(b"new").is_none());
+        hash_table.insert(b"new", b"entry");
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_key_and_value_bytes() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        assert_eq!(hash_table.key_bytes(), Size(0));
+        assert_eq!(hash_table.value_bytes(), Size(0));
+
+        hash_table.insert(b"a", b"12");
+        hash_table.insert(b"long key", &[0u8; 100]);
+        hash_table.insert(b"b", b"");
+        hash_table.insert(b"c", b"123");
+
+        assert_eq!(hash_table.key_bytes(), Size(1 + 8 + 1 + 1));
+        assert_eq!(hash_table.value_bytes(), Size(2 + 100 + 0 + 3));
+
+        hash_table.remove(b"long key");
+        assert_eq!(hash_table.key_bytes(), Size(3));
+        assert_eq!(hash_table.value_bytes(), Size(5));
+    }
+
+    #[test]
+    fn test_entry_at() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::with_capacity_and_seed(&memory, Size(4), 0);
+
+        hash_table.insert(b"long key", b"abc");
+
+        let infos: Vec<EntryInfo> = (0 .. 6).map(|index| hash_table.entry_at(index).unwrap()).collect();
+        assert!(hash_table.entry_at(6).is_none());
+
+        let home_index = index_in_table(hash_for(0, b"long key"), 6) as usize;
+
+        assert_eq!(infos[home_index], EntryInfo {
+            is_empty: false,
+            hash: hash_for(0, b"long key") & ENTRY_META_HASH_MASK,
+            key_inline: false,
+            key_len: Size(8),
+            value_inline: true,
+            value_len: Size(3),
+        });
+
+        assert_eq!(infos.iter().filter(|info| info.is_empty).count(), 5);
+    }
+
+    // Run with `cargo test -- --ignored` and compare the timings with and
+    // without the `hashtable-paranoid` feature.
+    #[test]
+    #[ignore]
+    fn test_insert_stress() {
+        use std::time::Instant;
+
+        let memory = create_memory(64 * 1024 * 1024);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        let start = Instant::now();
+
+        for i in 0 .. 200_000u32 {
+            let mut key = [0u8; 4];
+            LittleEndian::write_u32(&mut key, i);
+            hash_table.insert(&key, &key);
+        }
+
+        println!("200000 inserts took {:?}", start.elapsed());
+        assert_eq!(hash_table.len(), 200_000);
+    }
+
+    #[test]
+    fn test_delete_table_writes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct WriteCountingStore {
+            store: MemStore,
+            bytes_written: AtomicUsize,
+        }
+
+        impl Storage for WriteCountingStore {
+            const IS_READONLY: bool = false;
+            type Threading = <MemStore as Storage>::Threading;
+
+            fn size(&self) -> Size {
+                self.store.size()
+            }
+
+            unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+                self.store.get_bytes(addr, len)
+            }
+
+            unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+                self.bytes_written.fetch_add(len.bytes(), Ordering::SeqCst);
+                self.store.get_bytes_mut(addr, len)
+            }
+
+            unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+                self.bytes_written.fetch_add(len.bytes(), Ordering::SeqCst);
+                self.store.copy_nonoverlapping_exclusive(src, dst, len);
+            }
+        }
+
+        let memory = Memory::new(WriteCountingStore {
+            store: MemStore::new(10000),
+            bytes_written: AtomicUsize::new(0),
+        });
+
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::with_capacity(&memory, Size(16));
+
+        for i in 0 .. 10u8 {
+            // Inline key, out-of-line value
+            hash_table.insert(&[i], b"a long value");
+        }
+
+        let table_bytes = hash_table.allocation().size.bytes();
+        let value_bytes = 10 * (b"a long value".len() + 1);
+
+        let before = memory.storage().bytes_written.load(Ordering::SeqCst);
+        hash_table.delete_table();
+        let after = memory.storage().bytes_written.load(Ordering::SeqCst);
+
+        // Every byte is written exactly once, when it is freed.
+        assert_eq!(after - before, table_bytes + value_bytes);
+        assert_eq!(memory.allocator.lock().allocation_count(), 0);
+    }
+
+    #[test]
+    fn test_update() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        fn increment(value: Option<&[u8]>) -> Option<Vec<u8>> {
+            match value {
+                None => Some(vec![1]),
+                Some(&[255]) => Some(vec![0, 1]),
+                Some(bytes) => {
+                    let mut bytes = bytes.to_vec();
+                    bytes[0] += 1;
+                    Some(bytes)
+                }
+            }
+        }
+
+        fn remove_if_zero(value: Option<&[u8]>) -> Option<Vec<u8>> {
+            match value {
+                Some(&[0]) | None => None,
+                Some(bytes) => Some(bytes.to_vec()),
+            }
+        }
+
+        // Increment or insert 1, also on an empty table
+        hash_table.update(b"a", increment);
+        assert_eq!(hash_table.find(b"a").as_deref(), Some(&[1][..]));
+        hash_table.update(b"a", increment);
+        assert_eq!(hash_table.find(b"a").as_deref(), Some(&[2][..]));
+
+        // The value can change its length
+        hash_table.insert(b"b", &[255]);
+        hash_table.update(b"b", increment);
+        assert_eq!(hash_table.find(b"b").as_deref(), Some(&[0, 1][..]));
+
+        // Enough inserts to make the table grow
+        for i in 0 .. 20u8 {
+            hash_table.update(&[i], increment);
+        }
+        assert_eq!(hash_table.len(), 22);
+
+        hash_table.insert(b"zero", &[0]);
+        hash_table.update(b"zero", remove_if_zero);
+        hash_table.update(b"a", remove_if_zero);
+        hash_table.update(b"missing", remove_if_zero);
+        assert!(hash_table.find(b"zero").is_none());
+        assert!(hash_table.find(b"missing").is_none());
+        assert_eq!(hash_table.find(b"a").as_deref(), Some(&[2][..]));
+        assert_eq!(hash_table.len(), 22);
+
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_get_or_empty() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        assert_eq!(&*hash_table.get_or_empty(b"absent"), b"");
+
+        hash_table.insert(b"present", b"value");
+        hash_table.insert(b"empty", b"");
+
+        assert_eq!(&*hash_table.get_or_empty(b"present"), b"value");
+        assert_eq!(&*hash_table.get_or_empty(b"empty"), b"");
+        assert_eq!(&*hash_table.get_or_empty(b"absent"), b"");
+    }
+
+    #[test]
+    fn test_find_slot() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::with_capacity_and_seed(&memory, Size(8), 0);
+
+        let keys: Vec<[u8; 1]> = (0 .. 8u8).map(|i| [i]).collect();
+        for key in &keys {
+            hash_table.insert(key, b"value");
+        }
+
+        for key in &keys {
+            let slot = RawTable::<MemStore, DefaultHashTableConfig>::find_slot(&memory, hash_table.data, key).unwrap();
+            let entry = RawTable::<MemStore, DefaultHashTableConfig>::get_entry(&memory, hash_table.data, slot);
+            assert_eq!(&*entry.entry_data::<DataKindKey>(&memory), &key[..]);
+            assert!(hash_table.contains_key(key));
+        }
+
+        assert!(!hash_table.contains_key(b"missing"));
+        assert!(RawTable::<MemStore, DefaultHashTableConfig>::find_slot(&memory, hash_table.data, b"missing").is_none());
+    }
+
+    #[test]
+    fn test_open_readonly() {
+        let memory = create_memory(10000);
+        let data = {
+            let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+            hash_table.insert(b"a", b"1");
+            hash_table.insert(b"long key", b"long value");
+            hash_table.allocation()
+        };
+
+        let memory = &memory;
+        let table1: ReadOnlyHashTable<_, DefaultHashTableConfig> = HashTable::open_readonly(memory, data).unwrap();
+        let table2: ReadOnlyHashTable<_, DefaultHashTableConfig> = HashTable::open_readonly(memory, data).unwrap();
+
+        let value1 = table1.find(b"long key").unwrap();
+        let value2 = table2.find(b"long key").unwrap();
+        assert_eq!(&*value1, b"long value");
+        assert_eq!(&*value2, b"long value");
+
+        assert_eq!(table1.len(), 2);
+        assert_eq!(table2.capacity(), table1.capacity());
+        assert!(table1.contains_key(b"a"));
+        assert!(!table2.contains_key(b"b"));
+
+        let mut count = 0;
+        table2.iter(|_, _| count += 1);
+        assert_eq!(count, 2);
+
+        assert!(HashTable::<_, DefaultHashTableConfig>::open_readonly(memory, Allocation::new(Address(0), Size(1))).is_err());
+    }
+
+    #[test]
+    fn test_chunked_values_in_fragmented_memory() {
+        enum ChunkedConfig {}
+        impl HashTableConfig for ChunkedConfig {
+            const VALUE_CHUNK_SIZE: Option<Size> = Some(Size(16 * 1024));
+        }
+
+        const VALUE_SIZE: usize = 1024 * 1024;
+        const HOLE_SIZE: Size = Size(64 * 1024);
+
+        let memory = create_memory(4 * VALUE_SIZE);
+        let mut hash_table: HashTable<_, ChunkedConfig> = HashTable::with_capacity(&memory, Size(8));
+
+        // Fill the memory and free every other block, which leaves plenty of
+        // free space but no contiguous 1 MB block.
+        let mut blocks = vec![];
+        while memory.can_alloc(HOLE_SIZE) {
+            blocks.push(memory.alloc(HOLE_SIZE));
+        }
+        for &block in blocks.iter().step_by(2) {
+            memory.free(block);
+        }
+
+        let allocations_before = memory.allocator.lock().allocation_count();
+        assert!(memory.allocator.lock().stats().free_bytes > Size::from_bytes(VALUE_SIZE * 2));
+        assert!(!memory.can_alloc(Size::from_bytes(VALUE_SIZE)));
+
+        let large: Vec<u8> = (0 .. VALUE_SIZE).map(|i| (i % 251) as u8).collect();
+        assert!(hash_table.insert(b"large", &large));
+        assert!(hash_table.insert(b"small", b"not inline"));
+
+        match hash_table.find(b"large") {
+            Some(ValueRef::Assembled(value)) => assert!(value == large),
+            _ => panic!("value was not reassembled"),
+        }
+        match hash_table.find(b"small") {
+            Some(ValueRef::Borrowed(value)) => assert_eq!(&*value, b"not inline"),
+            _ => panic!("single chunk was not borrowed"),
+        }
+
+        let mut found = 0;
+        hash_table.iter(|key, value| {
+            if key == b"large" {
+                assert!(value == &large[..]);
+                found += 1;
+            }
+        });
+        assert_eq!(found, 1);
+
+        let mut other = large.clone();
+        other[VALUE_SIZE - 1] ^= 1;
+        assert!(!hash_table.compare_and_swap(b"large", Some(&other), b"x"));
+        assert!(hash_table.compare_and_swap(b"large", Some(&large), &other));
+        assert!(hash_table.find(b"large").as_deref() == Some(&other[..]));
+
+        assert!(hash_table.remove(b"large"));
+        assert!(hash_table.remove(b"small"));
+        assert_eq!(memory.allocator.lock().allocation_count(), allocations_before);
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_size_classes() {
+        enum ChunkedConfig {}
+        impl HashTableConfig for ChunkedConfig {
+            const VALUE_CHUNK_SIZE: Option<Size> = Some(Size(40));
+        }
+
+        fn check<C: HashTableConfig>() {
+            let memory = create_memory(100000);
+            memory.allocator.lock().set_size_classes(SizeClasses::PowerOfTwo);
+            let allocations_before = memory.allocator.lock().allocation_count();
+
+            let data = {
+                let mut hash_table: HashTable<_, C> = HashTable::with_capacity(&memory, Size(8));
+                for i in 0 .. 100u8 {
+                    assert!(hash_table.insert(&[i], &vec![i; 17 + i as usize]));
+                }
+                hash_table.allocation()
+            };
+
+            // The table is smaller than its allocation.
+            let capacity = RawTable::<MemStore, C>::capacity(&memory, data);
+            assert!(RawTable::<MemStore, C>::byte_count_for_capacity(capacity) < data.size);
+
+            let mut hash_table: HashTable<_, C> = HashTable::from_existing(&memory, data).unwrap();
+            for i in 0 .. 100u8 {
+                assert_eq!(hash_table.find(&[i]).as_deref(), Some(&vec![i; 17 + i as usize][..]));
+            }
+
+            for i in 0 .. 100u8 {
+                assert!(hash_table.remove(&[i]));
+            }
+            hash_table.sanity_check_table();
+            hash_table.delete_table();
+
+            assert_eq!(memory.allocator.lock().allocation_count(), allocations_before);
+            memory.allocator.lock().verify().unwrap();
+        }
+
+        check::<DefaultHashTableConfig>();
+        check::<ChunkedConfig>();
+    }
+
+    #[test]
+    fn test_min_split_size() {
+        let memory = create_memory(100000);
+        memory.allocator.lock().set_min_split_size(Size(32));
+        let allocations_before = memory.allocator.lock().allocation_count();
+        let mut hash_table: HashTable<_> = HashTable::with_capacity(&memory, Size(200));
+
+        // A hole that is a bit too large for the next value gets used up
+        // completely.
+        let hole = memory.alloc(Size(40));
+        let barrier = memory.alloc(Size(100));
+        memory.free(hole);
+
+        assert!(hash_table.insert(b"key", &[1; 20]));
+        assert_eq!(memory.allocator.lock().allocation_at(hole.addr), Some(hole));
+
+        for i in 0 .. 100u8 {
+            assert!(hash_table.insert(&[i, 0, 0, 0, 0], &vec![i; 5 + i as usize % 50]));
+        }
+        for i in (0 .. 100u8).step_by(2) {
+            assert!(hash_table.remove(&[i, 0, 0, 0, 0]));
+        }
+        for i in (0 .. 100u8).step_by(2) {
+            assert!(hash_table.insert(&[i, 0, 0, 0, 0], &vec![i; 1 + i as usize % 30]));
+        }
+
+        let data = hash_table.allocation();
+        drop(hash_table);
+        let mut hash_table: HashTable<_> = HashTable::from_existing(&memory, data).unwrap();
+        assert_eq!(hash_table.find(b"key").as_deref(), Some(&[1; 20][..]));
+
+        assert!(hash_table.remove(b"key"));
+        for i in 0 .. 100u8 {
+            assert!(hash_table.remove(&[i, 0, 0, 0, 0]));
+        }
+        hash_table.sanity_check_table();
+        hash_table.delete_table();
+        memory.free(barrier);
+
+        assert_eq!(memory.allocator.lock().allocation_count(), allocations_before);
+        memory.allocator.lock().verify().unwrap();
+    }
+
+    #[test]
+    fn test_value_changes_between_inline_and_indirect() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::with_capacity(&memory, Size(8));
+
+        // Values are limited to 255 bytes, so 200 bytes is used as the large
+        // value here.
+        let small = [1u8, 2];
+        let large = [7u8; 200];
+
+        hash_table.insert(b"key", &small);
+        let allocated_before = memory.allocator.lock().stats().allocated_bytes;
+
+        // inline -> indirect
+        assert!(!hash_table.insert(b"key", &large));
+        assert_eq!(hash_table.find(b"key").as_deref(), Some(&large[..]));
+        assert_eq!(memory.allocator.lock().stats().allocated_bytes,
+                   allocated_before + Size::from_bytes(large.len() + 1));
+
+        // indirect -> indirect
+        assert!(!hash_table.insert(b"key", &large[.. 100]));
+        assert_eq!(hash_table.find(b"key").as_deref(), Some(&large[.. 100]));
+        assert_eq!(memory.allocator.lock().stats().allocated_bytes,
+                   allocated_before + Size(101));
+
+        // indirect -> inline
+        assert!(!hash_table.insert(b"key", &small));
+        assert_eq!(hash_table.find(b"key").as_deref(), Some(&small[..]));
+        assert_eq!(memory.allocator.lock().stats().allocated_bytes, allocated_before);
+
+        assert_eq!(hash_table.len(), 1);
+        assert_eq!(memory.allocator.lock().verify(), Ok(()));
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_try_iter() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        for i in 0 .. 10u8 {
+            hash_table.insert(&[i], &[i]);
+        }
+
+        let mut call_count = 0;
+        let completed = hash_table.try_iter(|_, _| {
+            call_count += 1;
+            if call_count == 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert!(!completed);
+        assert_eq!(call_count, 3);
+
+        let mut call_count = 0;
+        assert!(hash_table.try_iter(|_, _| {
+            call_count += 1;
+            ControlFlow::Continue(())
+        }));
+        assert_eq!(call_count, 10);
+    }
+
+    #[test]
+    fn test_triangular_probing() {
+        use rand::{thread_rng, Rng};
+        use std::collections::HashMap;
+
+        enum TriangularConfig {}
+        impl HashTableConfig for TriangularConfig {
+            const PROBE_SEQUENCE: ProbeSequence = ProbeSequence::Triangular;
+        }
+
+        let memory = create_memory(1000000);
+        let mut hash_table: HashTable<_, TriangularConfig> = HashTable::new(&memory);
+        let mut reference = HashMap::new();
+        let mut rng = thread_rng();
+
+        for _ in 0 .. 3000 {
+            let key = [rng.gen_range(0, 200u8)];
+
+            if rng.gen_range(0, 10) < 6 {
+                let value: Vec<u8> = (0 .. rng.gen_range(0, 8)).map(|_| rng.gen()).collect();
+                assert_eq!(hash_table.insert(&key, &value),
+                           reference.insert(key.to_vec(), value).is_none());
+            } else {
+                assert_eq!(hash_table.remove(&key), reference.remove(&key[..]).is_some());
+            }
+
+            hash_table.sanity_check_table();
+            assert_eq!(hash_table.len(), reference.len());
+            assert!(hash_table.capacity() == 0 || hash_table.footprint() ==
+                    HashTable::<MemStore, TriangularConfig>::bytes_for_capacity(Size::from_bytes(hash_table.capacity())));
+        }
+
+        for (key, value) in reference.iter() {
+            assert_eq!(hash_table.find(key).as_deref(), Some(&value[..]));
+        }
+
+        let mut entries = hash_table.entries();
+        entries.sort();
+        let mut expected: Vec<_> = reference.into_iter().collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        let entry_array_len = RawTable::<MemStore, TriangularConfig>::entry_array_len(&memory, hash_table.data);
+        assert!(entry_array_len.is_power_of_two());
+    }
+
+    #[test]
+    fn test_entry_data_equals_reads() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct ReadCountingStore {
+            store: MemStore,
+            reads: AtomicUsize,
+        }
+
+        impl Storage for ReadCountingStore {
+            const IS_READONLY: bool = false;
+            type Threading = <MemStore as Storage>::Threading;
+
+            fn size(&self) -> Size {
+                self.store.size()
+            }
+
+            unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.store.get_bytes(addr, len)
+            }
+
+            unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+                self.store.get_bytes_mut(addr, len)
+            }
+
+            unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+                self.store.copy_nonoverlapping_exclusive(src, dst, len);
+            }
+        }
+
+        let memory = Memory::new(ReadCountingStore {
+            store: MemStore::new(10000),
+            reads: AtomicUsize::new(0),
+        });
+
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+        hash_table.insert(b"a long key", b"value");
+
+        let index = RawTable::<ReadCountingStore, DefaultHashTableConfig>::find_slot(&memory, hash_table.data, b"a long key").unwrap();
+        let entry = RawTable::<ReadCountingStore, DefaultHashTableConfig>::get_entry(&memory, hash_table.data, index);
+        assert!(!entry.is_entry_data_inline::<DataKindKey>());
+
+        let reads_for = |key: &[u8]| {
+            let before = memory.storage().reads.load(Ordering::SeqCst);
+            let equal = entry.entry_data_equals::<DataKindKey>(&memory, key);
+            (equal, memory.storage().reads.load(Ordering::SeqCst) - before)
+        };
+
+        // Short keys are rejected without touching storage at all.
+        assert_eq!(reads_for(b"abc"), (false, 0));
+        // Only the indirection and the length prefix are read if the lengths
+        // differ.
+        assert_eq!(reads_for(b"a longer key"), (false, 2));
+        // The full comparison needs the data too.
+        assert_eq!(reads_for(b"a long kez"), (false, 3));
+        assert_eq!(reads_for(b"a long key"), (true, 3));
+
+        let before = memory.storage().reads.load(Ordering::SeqCst);
+        let _ = entry.entry_data::<DataKindKey>(&memory);
+        assert_eq!(memory.storage().reads.load(Ordering::SeqCst) - before, 3);
+    }
+
+    #[test]
+    fn test_recount() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+        assert_eq!(hash_table.recount(), 0);
+
+        for i in 0 .. 20u8 {
+            hash_table.insert(&[i], &[i; 6]);
+        }
+
+        RawTable::<MemStore, DefaultHashTableConfig>::set_len(&memory, hash_table.data, Size(3));
+        assert_eq!(hash_table.len(), 3);
+
+        assert_eq!(hash_table.recount(), 20);
+        assert_eq!(hash_table.len(), 20);
+
+        for i in 20 .. 40u8 {
+            assert!(hash_table.insert(&[i], &[i; 6]));
+        }
+
+        assert!(hash_table.remove(&[0]));
+        assert_eq!(hash_table.len(), 39);
+        hash_table.sanity_check_table();
+
+        for i in 1 .. 40u8 {
+            assert_eq!(hash_table.find(&[i]).as_deref(), Some(&[i; 6][..]));
+        }
+
+        assert_eq!(hash_table.recount(), 39);
+    }
+
+    #[test]
+    fn test_find_many() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        assert!(hash_table.find_many(&[b"a", b"b"]).iter().all(|value| value.is_none()));
+        assert!(hash_table.find_many(&[]).is_empty());
+
+        for i in 0 .. 50u8 {
+            hash_table.insert(&[i, i], &[i; 9]);
+        }
+
+        let keys: Vec<Vec<u8>> = (0 .. 100u8).rev().map(|i| vec![i, i]).collect();
+        let mut key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        key_refs.push(b"");
+        key_refs.push(&keys[70]);
+
+        let values = hash_table.find_many(&key_refs);
+        assert_eq!(values.len(), key_refs.len());
+
+        for (key, value) in key_refs.iter().zip(values.iter()) {
+            assert_eq!(value.as_deref(), hash_table.find(key).as_deref());
+        }
+
+        assert_eq!(values.iter().filter(|value| value.is_some()).count(), 51);
+    }
+
+    #[test]
+    #[cfg(feature = "hashtable-metrics")]
+    fn test_probe_metrics() {
+        let memory = create_memory(10000);
+        let seed = 42;
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> =
+            HashTable::with_capacity_and_seed(&memory, Size(10), seed);
+        let table_size = RawTable::<MemStore, DefaultHashTableConfig>::entry_array_len(&memory, hash_table.data);
+
+        // Find four keys with the same home slot
+        let home_index = |key: &[u8]| index_in_table(hash_for(seed, key), table_size);
+        let colliding: Vec<[u8; 2]> = (0 .. u16::MAX)
+            .map(|i| [i as u8, (i >> 8) as u8])
+            .filter(|key| home_index(key) == home_index(&[0, 0]))
+            .take(4)
+            .collect();
+
+        for key in &colliding[.. 3] {
+            assert!(hash_table.insert(key, b"v"));
+        }
+
+        // 1 + 2 + 3 slots looked at for the three inserts
+        assert_eq!(hash_table.probe_metrics(), ProbeMetrics {
+            operation_count: 3,
+            total_probe_steps: 6,
+            max_probe_steps: 3,
+        });
+
+        hash_table.reset_probe_metrics();
+        assert_eq!(hash_table.probe_metrics(), ProbeMetrics::default());
+
+        assert!(hash_table.find(&colliding[2]).is_some());
+        // A miss has to look at the empty slot after the collision chain too
+        assert!(hash_table.find(&colliding[3]).is_none());
+        assert!(hash_table.remove(&colliding[0]));
+
+        let metrics = hash_table.probe_metrics();
+        assert_eq!(metrics, ProbeMetrics {
+            operation_count: 3,
+            total_probe_steps: 3 + 4 + 1,
+            max_probe_steps: 4,
+        });
+        assert_eq!(metrics.average_probe_steps(), 8.0 / 3.0);
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        use rand::{thread_rng, Rng};
+
+        let memory = create_memory(100000);
+
+        let keys: Vec<Vec<u8>> = (0 .. 100u32).map(|i| format!("key{}", i).into_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0 .. 100u32).map(|i| vec![i as u8; (i % 10) as usize]).collect();
+        let pairs: Vec<(&[u8], &[u8])> = keys.iter().zip(values.iter())
+                                             .map(|(key, value)| (&key[..], &value[..]))
+                                             .collect();
+
+        let allocations_before = memory.allocator.lock().allocation_count();
+        let hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::from_pairs(&memory, &pairs);
+
+        assert_eq!(hash_table.len(), 100);
+        assert_eq!(hash_table.capacity(), 100);
+        hash_table.sanity_check_table();
+
+        // The entry array was allocated exactly once; the only other
+        // allocations hold the out-of-line keys and values.
+        let out_of_line = pairs.iter()
+            .map(|&(key, value)| (key.len() > 4) as usize + (value.len() > 4) as usize)
+            .sum::<usize>();
+        assert_eq!(memory.allocator.lock().allocation_count() - allocations_before, 1 + out_of_line);
+
+        let mut rng = thread_rng();
+        for _ in 0 .. 20 {
+            let i = rng.gen_range(0, pairs.len());
+            assert_eq!(hash_table.find(&keys[i]).as_deref(), Some(&values[i][..]));
+        }
+
+        let empty: HashTable<_, DefaultHashTableConfig> = HashTable::from_pairs(&memory, &[]);
+        assert_eq!(empty.len(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Hash table modified while it is being iterated")]
+    fn test_insert_during_iteration() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+
+        for i in 0 .. 10u8 {
+            hash_table.insert(&[i], &[i]);
+        }
+
+        let data = hash_table.allocation();
+        hash_table.iter(|_, _| {
+            let mut alias: HashTable<_, DefaultHashTableConfig> = HashTable::from_existing(&memory, data).unwrap();
+            alias.insert(b"new", b"value");
+        });
+    }
+
+    #[test]
+    fn test_modify_after_iteration() {
+        let memory = create_memory(10000);
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+        hash_table.insert(b"a", b"1");
+
+        let data = hash_table.allocation();
+        let mut lookups = 0;
+        hash_table.iter(|_, _| {
+            // Reading through another handle is fine
+            let alias: HashTable<_, DefaultHashTableConfig> = HashTable::from_existing(&memory, data).unwrap();
+            assert!(alias.find(b"a").is_some());
+            lookups += 1;
+        });
+        assert_eq!(lookups, 1);
+
+        // The guard is gone once iteration has finished
+        assert!(hash_table.insert(b"b", b"2"));
+        assert!(hash_table.remove(b"a"));
+    }
+
+    #[test]
+    fn test_min_capacity() {
+        enum LargeConfig {}
+        impl HashTableConfig for LargeConfig {
+            const MIN_CAPACITY: Size = Size(64);
+        }
+
+        let memory = create_memory(100000);
+
+        let mut hash_table: HashTable<_, LargeConfig> = HashTable::new(&memory);
+        assert_eq!(hash_table.capacity(), 0);
+
+        hash_table.insert(b"a", b"1");
+        assert_eq!(hash_table.capacity(), 64);
+        assert_eq!(hash_table.footprint(), HashTable::<MemStore, LargeConfig>::bytes_for_capacity(Size(64)));
+
+        for i in 0 .. 64u8 {
+            hash_table.insert(&[i], &[i]);
+        }
+        assert_eq!(hash_table.capacity(), 96);
+
+        let hash_table: HashTable<_, LargeConfig> = HashTable::with_capacity(&memory, Size(10));
+        assert_eq!(hash_table.capacity(), 64);
+
+        let mut hash_table: HashTable<_, DefaultHashTableConfig> = HashTable::new(&memory);
+        hash_table.insert(b"a", b"1");
+        assert_eq!(hash_table.capacity(), 8);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let memory = create_memory(100000);
+        let mut table: HashTable<_> = HashTable::new(&memory);
+
+        // Insert if absent
+        assert!(table.compare_and_swap(b"key", None, b"a"));
+        assert_eq!(&*table.find(b"key").unwrap(), b"a");
+        assert!(!table.compare_and_swap(b"key", None, b"b"));
+        assert_eq!(&*table.find(b"key").unwrap(), b"a");
+
+        // Mismatch
+        assert!(!table.compare_and_swap(b"key", Some(b"x"), b"b"));
+        assert!(!table.compare_and_swap(b"missing", Some(b"a"), b"b"));
+        assert_eq!(&*table.find(b"key").unwrap(), b"a");
+        assert!(!table.contains_key(b"missing"));
+
+        // Success, also across inline and indirect values
+        let long_value = [7u8; 100];
+        assert!(table.compare_and_swap(b"key", Some(b"a"), &long_value));
+        assert_eq!(&*table.find(b"key").unwrap(), &long_value[..]);
+        assert!(!table.compare_and_swap(b"key", Some(&long_value[.. 99]), b"c"));
+        assert!(table.compare_and_swap(b"key", Some(&long_value), b"c"));
+        assert_eq!(&*table.find(b"key").unwrap(), b"c");
+
+        // Insert if absent has to be able to grow the table
+        for i in 0 .. 100u32 {
+            assert!(table.compare_and_swap(&i.to_le_bytes(), None, &i.to_le_bytes()));
+        }
+
+        assert_eq!(table.len(), 101);
+        for i in 0 .. 100u32 {
+            assert_eq!(&*table.find(&i.to_le_bytes()).unwrap(), &i.to_le_bytes());
+        }
+
+        table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_stable_key_ref() {
+        let memory = create_memory(100000);
+        let mut table: HashTable<_> = HashTable::new(&memory);
+
+        let long_key = [3u8; 100];
+        let keys: Vec<Vec<u8>> = (0 .. 20u8).map(|i| vec![i; 1 + i as usize])
+                                            .chain(Some(long_key.to_vec()))
+                                            .collect();
+
+        for key in &keys {
+            table.insert(key, b"value");
+        }
+
+        for key in &keys {
+            let addr = table.stable_key_ref(key).unwrap();
+            assert_eq!(&*memory.get_bytes(addr, Size::from_bytes(key.len())), &key[..]);
+
+            let slot = RawTable::<MemStore, DefaultHashTableConfig>::find_slot(&memory, table.data, key).unwrap();
+            let entry = RawTable::<MemStore, DefaultHashTableConfig>::get_entry(&memory, table.data, slot);
+            assert_eq!(&*entry.entry_data::<DataKindKey>(&memory), &*memory.get_bytes(addr, Size::from_bytes(key.len())));
+        }
+
+        assert_eq!(table.stable_key_ref(b"missing"), None);
+    }
+
+    enum IncrementalConfig {}
+    impl HashTableConfig for IncrementalConfig {
+        const INCREMENTAL_REHASH: bool = true;
+    }
+
+    enum IncrementalTriangularConfig {}
+    impl HashTableConfig for IncrementalTriangularConfig {
+        const PROBE_SEQUENCE: ProbeSequence = ProbeSequence::Triangular;
+        const INCREMENTAL_REHASH: bool = true;
+    }
+
+    #[test]
+    fn test_incremental_rehash_partial() {
+        let memory = create_memory(1000000);
+        let allocations_before = memory.allocator.lock().allocation_count();
+        let mut hash_table: HashTable<_, IncrementalConfig> = HashTable::with_capacity(&memory, Size(64));
+
+        // Long values are stored out-of-line and must survive the migration.
+        let value_for = |i: u32| -> Vec<u8> {
+            let len = if i % 3 == 0 { 40 } else { 4 };
+            (0 .. len).map(|j| (i + j) as u8).collect()
+        };
+
+        for i in 0 .. 64u32 {
+            hash_table.insert(&i.to_le_bytes(), &value_for(i));
+        }
+
+        assert!(!hash_table.is_migrating());
+        let old_allocation = hash_table.allocation();
+
+        // The table is full, so the next insert starts the migration.
+        hash_table.insert(&64u32.to_le_bytes(), &value_for(64));
+        assert!(hash_table.is_migrating());
+        assert_eq!(hash_table.len(), 65);
+        assert_eq!(hash_table.capacity(), 96);
+        assert!(memory.allocator.lock().allocation_at(old_allocation.addr).is_some());
+
+        let check = |hash_table: &HashTable<_, IncrementalConfig>, removed: &[u32], count: u32| {
+            for i in 0 .. count {
+                let key = i.to_le_bytes();
+                if removed.contains(&i) {
+                    assert!(hash_table.find(&key).is_none());
+                    assert!(!hash_table.contains_key(&key));
+                } else {
+                    assert_eq!(hash_table.find(&key).as_deref(), Some(&value_for(i)[..]));
+                    assert!(hash_table.contains_key(&key));
+                }
+            }
+
+            assert_eq!(hash_table.len(), count as usize - removed.len());
+            assert_eq!(hash_table.entries().len(), hash_table.len());
+            hash_table.sanity_check_table();
+        };
+
+        check(&hash_table, &[], 65);
+
+        // Modify a few entries while the migration is only partially done.
+        assert!(hash_table.remove(&63u32.to_le_bytes()));
+        assert_eq!(hash_table.insert_replace(&62u32.to_le_bytes(), &value_for(62)),
+                   Some(value_for(62)));
+        assert!(hash_table.compare_and_swap(&61u32.to_le_bytes(), Some(&value_for(61)), &value_for(61)));
+        assert!(hash_table.is_migrating());
+        check(&hash_table, &[63], 65);
+
+        let keys: Vec<[u8; 4]> = (0 .. 65u32).map(|i| i.to_le_bytes()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|k| &k[..]).collect();
+        let found = hash_table.find_many(&key_refs);
+        assert_eq!(found.iter().filter(|v| v.is_some()).count(), 64);
+        drop(found);
+
+        let mut sorted_keys = vec![];
+        hash_table.iter_sorted(|key, _| sorted_keys.push(key.to_vec()));
+        assert_eq!(sorted_keys.len(), 64);
+        assert!(sorted_keys.windows(2).all(|w| w[0] < w[1]));
+
+        // Keep modifying until the old entry array is drained.
+        let mut count = 65u32;
+        while hash_table.is_migrating() {
+            hash_table.insert(&count.to_le_bytes(), &value_for(count));
+            count += 1;
+        }
+
+        assert!(count < 96);
+        // The old entry array has been freed (its space may have been reused).
+        assert_ne!(memory.allocator.lock().allocation_at(old_allocation.addr), Some(old_allocation));
+        check(&hash_table, &[63], count);
+        assert_eq!(hash_table.footprint(), HashTable::<MemStore, IncrementalConfig>::bytes_for_capacity(Size(96)));
+
+        hash_table.delete_table();
+        assert_eq!(memory.allocator.lock().allocation_count(), allocations_before);
+    }
+
+    #[test]
+    fn test_incremental_rehash_drop() {
+        let memory = create_memory(1000000);
+        let mut hash_table: HashTable<_, IncrementalConfig> = HashTable::with_capacity(&memory, Size(64));
+
+        for i in 0 .. 65u32 {
+            hash_table.insert(&i.to_le_bytes(), &[i as u8; 10]);
+        }
+
+        assert!(hash_table.is_migrating());
+        let data = hash_table.data;
+        let allocations = memory.allocator.lock().allocation_count();
+        drop(hash_table);
+
+        // The old entry array has been freed
+        assert_eq!(memory.allocator.lock().allocation_count(), allocations - 1);
+
+        let hash_table: HashTable<_, IncrementalConfig> = HashTable::from_existing(&memory, data).unwrap();
+        assert!(!hash_table.is_migrating());
+        assert_eq!(hash_table.len(), 65);
+        for i in 0 .. 65u32 {
+            assert_eq!(hash_table.find(&i.to_le_bytes()).as_deref(), Some(&[i as u8; 10][..]));
+        }
+        hash_table.sanity_check_table();
+    }
+
+    #[test]
+    fn test_incremental_rehash_stress() {
+        fn run<C: HashTableConfig>() {
+            use rand::{thread_rng, Rng};
+            use std::collections::HashMap;
+
+            let memory = create_memory(1000000);
+            let allocations_before = memory.allocator.lock().allocation_count();
+            let mut hash_table: HashTable<_, C> = HashTable::new(&memory);
+            let mut reference = HashMap::new();
+            let mut rng = thread_rng();
+            let mut saw_migration = false;
+
+            for _ in 0 .. 3000 {
+                let key = [rng.gen_range(0, 200u8)];
+
+                if rng.gen_range(0, 10) < 7 {
+                    let value: Vec<u8> = (0 .. rng.gen_range(0, 12)).map(|_| rng.gen()).collect();
+                    assert_eq!(hash_table.insert(&key, &value),
+                               reference.insert(key.to_vec(), value).is_none());
+                } else {
+                    assert_eq!(hash_table.remove(&key), reference.remove(&key[..]).is_some());
+                }
+
+                saw_migration |= hash_table.is_migrating();
+                assert_eq!(hash_table.len(), reference.len());
+
+                let key = [rng.gen_range(0, 200u8)];
+                assert_eq!(hash_table.find(&key).as_deref(), reference.get(&key[..]).map(|v| &v[..]));
+            }
+
+            assert!(saw_migration);
+            hash_table.sanity_check_table();
+
+            let mut entries = hash_table.entries();
+            entries.sort();
+            let mut expected: Vec<_> = reference.into_iter().collect();
+            expected.sort();
+            assert_eq!(entries, expected);
+
+            hash_table.finish_migration();
+            assert!(!hash_table.is_migrating());
+            hash_table.delete_table();
+            assert_eq!(memory.allocator.lock().allocation_count(), allocations_before);
+        }
+
+        run::<IncrementalConfig>();
+        run::<IncrementalTriangularConfig>();
+    }
+
+    #[test]
+    #[should_panic(expected = "finish_migration")]
+    fn test_incremental_rehash_allocation() {
+        let memory = create_memory(100000);
+        let mut hash_table: HashTable<_, IncrementalConfig> = HashTable::with_capacity(&memory, Size(8));
+
+        for i in 0 .. 9u32 {
+            hash_table.insert(&i.to_le_bytes(), b"value");
+        }
+
+        assert!(hash_table.is_migrating());
+        hash_table.allocation();
+    }
 }
@@ -21,13 +21,19 @@ pub struct Header {
     footer_addr: Address,
 }
 
+impl Header {
+    pub fn footer_addr(&self) -> Address {
+        self.footer_addr
+    }
+}
+
 pub fn read_header<S: Storage>(storage: &S) -> Result<Header, String> {
-    if storage.size() < Size::from_usize(mem::size_of::<Header>()) {
+    if storage.size() < Size::from_bytes(mem::size_of::<Header>()) {
         return Err("File too small".to_string());
     }
 
     let header_bytes = unsafe {
-        storage.get_bytes(Address(0), Size::from_usize(mem::size_of::<Header>()))
+        storage.get_bytes(Address(0), Size::from_bytes(mem::size_of::<Header>()))
     };
 
     if &header_bytes[0 .. 4] != FILE_MAGIC {
@@ -75,7 +81,7 @@ pub fn write_header<S: Storage>(storage: &S,
     }
 
     let header_bytes = unsafe {
-        storage.get_bytes_mut(Address(0), Size::from_usize(mem::size_of::<Header>()))
+        storage.get_bytes_mut(Address(0), Size::from_bytes(mem::size_of::<Header>()))
     };
 
     header_bytes[0..4].copy_from_slice(&FILE_MAGIC);
@@ -84,9 +90,15 @@ pub fn write_header<S: Storage>(storage: &S,
     LittleEndian::write_u32(&mut header_bytes[12 .. 16], footer_addr.as_u32());
 }
 
+#[inline]
+pub fn header_size() -> Size {
+    Size::from_bytes(mem::size_of::<Header>())
+}
+
 pub fn reserve_header<S: Storage>(memory: &mut Memory<S>) {
-    let header_size = Size::from_usize(mem::size_of::<Header>());
+    let header_size = header_size();
     let alloc = memory.alloc(header_size);
     assert_eq!(alloc.addr, Address::from_u32(0));
-    assert_eq!(alloc.size, header_size);
+    // Size classes can round the allocation up.
+    assert!(alloc.size >= header_size);
 }
@@ -9,23 +9,36 @@ extern crate bitflags;
 #[cfg(test)]
 extern crate rand;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use byteorder::{ByteOrder, LittleEndian};
 
 mod allocator;
 mod buffer;
+mod diff;
 mod footer;
 mod hashtable;
 mod header;
 mod memory;
 mod persist;
 mod record;
+mod wal;
 
-pub use allocator::{Allocator, Allocation};
+pub use allocator::{Allocator, AllocatorStats, Allocation, AllocStrategy, SizeClasses};
 pub use buffer::{Buffer, BufferProvider};
-pub use hashtable::{HashTable, HashTableConfig, DefaultHashTableConfig};
+pub use diff::{diff, apply_patches, Patch};
+pub use footer::{StructureKind, StructureRef};
+pub use hashtable::{HashTable, HashTableConfig, DefaultHashTableConfig, EntryInfo, ProbeSequence, ReadOnlyHashTable, ValueRef};
+#[cfg(feature = "hashtable-metrics")]
+pub use hashtable::ProbeMetrics;
 pub use memory::*;
-use record::{Record, RecordId, RuntimeRecordTable, RecordTableMut};
+pub use persist::{Serialize, Deserialize, FixedSizeSerialize, StorageWriter, StorageReader, TypedArray};
+pub use record::{RecordId, RecordTableFull};
+pub use wal::WalStorage;
+use record::{Record, RecordTable, RuntimeRecordTable, RecordTableMut};
 
 pub struct Encoder<'buf, 'db, S: Storage + 'db> {
     db: &'db mut Database<S>,
@@ -43,7 +56,15 @@ impl<'buf, 'db, S: Storage + 'db> Encoder<'buf, 'db, S> {
     pub fn write_record<W>(&mut self, write: W) -> RecordId
         where W: FnOnce(&mut Encoder<'_, '_, S>, &mut CurrentRecordId)
     {
-        let record_id = self.db.alloc_record();
+        self.try_write_record(write).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `write_record()` but returns an error instead of panicking if
+    /// there is no `RecordId` left for the new record.
+    pub fn try_write_record<W>(&mut self, write: W) -> Result<RecordId, RecordTableFull>
+        where W: FnOnce(&mut Encoder<'_, '_, S>, &mut CurrentRecordId)
+    {
+        let record_id = self.db.alloc_record()?;
 
         let mut encoder = Encoder {
             db: self.db,
@@ -56,44 +77,318 @@ impl<'buf, 'db, S: Storage + 'db> Encoder<'buf, 'db, S> {
             was_accessed: false,
         };
 
+        let header_offset = if encoder.db.record_headers {
+            let offset = encoder.buffer.len().bytes();
+            encoder.buffer.write_bytes(&[0; RECORD_HEADER_SIZE]);
+            Some(offset)
+        } else {
+            None
+        };
+
         write(&mut encoder, &mut current_record_id);
 
+        if let Some(offset) = header_offset {
+            let len = encoder.buffer.len().bytes() - offset - RECORD_HEADER_SIZE;
+            assert!(len <= u32::MAX as usize, "record too large");
+            let header = &mut encoder.buffer.bytes_mut()[offset .. offset + RECORD_HEADER_SIZE];
+            LittleEndian::write_u32(&mut header[.. 4], RECORD_MAGIC);
+            LittleEndian::write_u32(&mut header[4 ..], len as u32);
+        }
+
         let Encoder {
             db,
-            buffer,
-            // TODO: implement GC
-            referenced_records: _
+            mut buffer,
+            referenced_records,
         } = encoder;
 
+        let payload_size = buffer.len();
+        write_reference_list(&mut buffer, referenced_records);
+
+        // With size classes, the allocation can be larger than the record.
+        // The record table has to store the exact size, so that the reference
+        // count is found at the end of the record.
         let record_size = buffer.len();
-        let allocation = db.memory.alloc(record_size);
+        let allocation = db.memory.alloc_uninit(record_size);
 
-        db.memory.get_bytes_mut(allocation.addr, Size::from_usize(buffer.bytes().len()))
+        db.memory.get_bytes_mut(allocation.addr, record_size)
                  .copy_from_slice(buffer.bytes());
 
-        {
-            let record = &mut db.records[record_id.idx()];
-            record.addr = allocation.addr;
-            record.size = allocation.size;
-            // db.record_table.with_mut(&db.memory, |record_table| {
-            //     record_table.set_record(record_id, Record {
-            //         addr: allocation.addr,
-            //         size: allocation.size,
-            //         ref_count
-            //     })
-            // });
+        let record_slot = db.record_table.with_mut(&db.memory, |record_table| {
+            record_table.set_record_data(record_id, allocation.addr, record_size,
+                                         allocation.addr + payload_size);
+            record_table.record_slot(record_id)
+        });
+
+        let storage = &mut db.memory.storage;
+        let sync_result = storage.sync_range(allocation.addr, record_size)
+            .and_then(|()| storage.sync_range(record_slot.addr, record_slot.size));
+
+        if let Err(err) = sync_result {
+            panic!("Could not sync storage: {}", err);
         }
 
-        record_id
+        Ok(record_id)
+    }
+
+    /// Writes whatever `write` encodes directly into the current record,
+    /// prefixed with its length, instead of into a record of its own. Read it
+    /// back with `Decoder::read_inline()`. Record ids written by `write` are
+    /// references of the current record.
+    pub fn write_inline<W>(&mut self, write: W)
+        where W: FnOnce(&mut Encoder<'buf, 'db, S>)
+    {
+        let len_offset = self.buffer.len().bytes();
+        self.buffer.write_bytes(&[0; INLINE_LEN_SIZE]);
+
+        write(self);
+
+        let len = self.buffer.len().bytes() - len_offset - INLINE_LEN_SIZE;
+        assert!(len <= u32::MAX as usize, "inline data too large");
+        LittleEndian::write_u32(&mut self.buffer.bytes_mut()[len_offset .. len_offset + INLINE_LEN_SIZE],
+                                len as u32);
     }
 
+    /// Records that the current record references `id`. The reference count
+    /// of `id` is incremented once, no matter how often it is referenced.
     #[inline]
     pub fn write_record_id(&mut self, id: RecordId) {
-        self.db.records[id.idx()].ref_count += 1;
-        self.referenced_records.insert(id);
+        if self.referenced_records.insert(id) {
+            let db = &mut *self.db;
+            db.record_table.with_mut(&db.memory, |record_table| {
+                record_table.inc_ref_count(id);
+            });
+        }
+    }
+}
+
+// Every record ends with the list of records it references, so that the
+// reference graph can be reconstructed from the record data alone:
+//
+// payload: [u8; N]
+// references: [RecordId; reference_count]
+// reference_count: u32
+const REFERENCE_ID_SIZE: Size = Size(4);
+const REFERENCE_COUNT_SIZE: Size = Size(4);
+
+// The parts of a record as described above, derived from the address of the
+// reference list, which the record table stores next to the record's address
+// and size.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct RecordMeta {
+    payload_size: Size,
+    ref_list_addr: Address,
+    reference_count: Size,
+}
+
+fn write_reference_list(buffer: &mut Buffer, referenced_records: HashSet<RecordId>) {
+    let mut referenced_records: Vec<_> = referenced_records.into_iter().collect();
+    referenced_records.sort();
+
+    let mut bytes = [0u8; 4];
+
+    for record_id in referenced_records.iter() {
+        LittleEndian::write_u32(&mut bytes, record_id.idx() as u32);
+        buffer.write_bytes(&bytes);
+    }
+
+    LittleEndian::write_u32(&mut bytes, referenced_records.len() as u32);
+    buffer.write_bytes(&bytes);
+}
+
+
+const INLINE_LEN_SIZE: usize = 4;
+
+// Optionally, see `Database::write_record_headers()`, the payload of a record
+// starts with a header that allows `Decoder::new_checked()` to detect that it
+// is pointed at something that isn't a record:
+//
+// magic: u32 (RECORD_MAGIC)
+// payload_len: u32 (without the header)
+const RECORD_MAGIC: u32 = 0xD1B5_EC0D;
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// Reads back the payload of a record written via an `Encoder`, or any
+/// other sequence of bytes.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Set if the bytes come from a `Memory`, keeps them borrowed.
+    _mem_ref: Option<MemRef<'a>>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            bytes,
+            pos: 0,
+            _mem_ref: None,
+        }
+    }
+
+    /// Creates a decoder over the contents of any allocation, not just over
+    /// a record.
+    pub fn at<S: Storage>(memory: &'a Memory<S>, allocation: Allocation) -> Decoder<'a> {
+        let mem_ref = memory.get_bytes(allocation.addr, allocation.size);
+
+        Decoder {
+            bytes: mem_ref.slice(),
+            pos: 0,
+            _mem_ref: Some(mem_ref),
+        }
+    }
+
+    /// Like `new()` but for records written with record headers enabled, see
+    /// `Database::write_record_headers()`. Fails if `bytes` don't start with
+    /// a valid header. The decoder only covers the length given in the header.
+    pub fn new_checked(bytes: &'a [u8]) -> Result<Decoder<'a>, String> {
+        let range = check_record_header(bytes)?;
+        Ok(Decoder::new(&bytes[range]))
+    }
+
+    /// Like `at()` but checks for a record header, like `new_checked()`.
+    pub fn at_checked<S: Storage>(memory: &'a Memory<S>, allocation: Allocation) -> Result<Decoder<'a>, String> {
+        let mem_ref = memory.get_bytes(allocation.addr, allocation.size);
+        let range = check_record_header(mem_ref.slice())?;
+
+        Ok(Decoder {
+            bytes: &mem_ref.slice()[range],
+            pos: 0,
+            _mem_ref: Some(mem_ref),
+        })
+    }
+
+    /// The bytes that have not been read yet.
+    #[inline]
+    pub fn remaining(&self) -> &[u8] {
+        &self.bytes[self.pos ..]
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> &[u8] {
+        assert!(len <= self.remaining().len(), "read beyond the end of the data");
+        let bytes = &self.bytes[self.pos .. self.pos + len];
+        self.pos += len;
+        bytes
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> u32 {
+        LittleEndian::read_u32(self.read_bytes(4))
+    }
+
+    #[inline]
+    pub fn read_u64(&mut self) -> u64 {
+        LittleEndian::read_u64(self.read_bytes(8))
+    }
+
+    /// Reads data written by `Encoder::write_inline()`. `read` gets a
+    /// `Decoder` for exactly the inline data, whatever it leaves unread is
+    /// skipped.
+    pub fn read_inline<R, F>(&mut self, read: F) -> R
+        where F: FnOnce(&mut Decoder) -> R
+    {
+        let len = self.read_u32() as usize;
+        read(&mut Decoder::new(self.read_bytes(len)))
+    }
+}
+
+/// Writes the payload of a single record directly into its final location,
+/// without buffering it first. Created via `Database::write_record_streaming()`.
+/// Streamed records cannot reference other records. They also never get the
+/// header of `Database::write_record_headers()`, so `Decoder::new_checked()`
+/// rejects them.
+pub struct StreamWriter<'db, S: Storage + 'db> {
+    db: &'db mut Database<S>,
+    // Taken by `finish()`, freed on drop otherwise.
+    allocation: Option<Allocation>,
+    payload_size: Size,
+    written: Size,
+}
+
+impl<'db, S: Storage + 'db> StreamWriter<'db, S> {
+
+    /// The number of payload bytes that still have to be written.
+    #[inline]
+    pub fn remaining(&self) -> Size {
+        self.payload_size - self.written
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) {
+        let len = Size::from_bytes(chunk.len());
+        assert!(len <= self.remaining(), "write exceeds the size hint of the record");
+
+        let addr = self.allocation.unwrap().addr + self.written;
+        self.db.memory.get_bytes_mut(addr, len).copy_from_slice(chunk);
+        self.written += len;
+    }
+
+    /// Makes the record visible. Fails if fewer bytes have been written than
+    /// announced via the size hint, in which case nothing is stored.
+    pub fn finish(mut self) -> Result<RecordId, String> {
+        if self.remaining() != Size(0) {
+            return Err(format!("Streamed {} bytes but the size hint was {}",
+                               self.written.bytes(),
+                               self.payload_size.bytes()))
+        }
+
+        let allocation = self.allocation.unwrap();
+        let record_id = self.db.alloc_record().map_err(|err| err.to_string())?;
+        self.allocation = None;
+
+        let db = &mut *self.db;
+        let record_size = self.payload_size + REFERENCE_COUNT_SIZE;
+        let ref_list_addr = allocation.addr + self.payload_size;
+        Size(0).write_at(&db.memory, ref_list_addr);
+
+        let record_slot = db.record_table.with_mut(&db.memory, |record_table| {
+            record_table.set_record_data(record_id, allocation.addr, record_size, ref_list_addr);
+            record_table.record_slot(record_id)
+        });
+
+        let storage = &mut db.memory.storage;
+        let sync_result = storage.sync_range(allocation.addr, record_size)
+            .and_then(|()| storage.sync_range(record_slot.addr, record_slot.size));
+
+        if let Err(err) = sync_result {
+            panic!("Could not sync storage: {}", err);
+        }
+
+        Ok(record_id)
+    }
+}
+
+impl<'db, S: Storage + 'db> Drop for StreamWriter<'db, S> {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            self.db.memory.free(allocation);
+        }
     }
 }
 
+// Returns the range of the payload following the record header at the start
+// of `bytes`.
+fn check_record_header(bytes: &[u8]) -> Result<::std::ops::Range<usize>, String> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return Err("Data too short for a record header".to_string())
+    }
+
+    let magic = LittleEndian::read_u32(&bytes[.. 4]);
+    if magic != RECORD_MAGIC {
+        return Err(format!("Invalid record magic {:#010x}", magic))
+    }
+
+    let len = LittleEndian::read_u32(&bytes[4 .. RECORD_HEADER_SIZE]) as usize;
+    if len > bytes.len() - RECORD_HEADER_SIZE {
+        return Err(format!("Record length {} exceeds the available {} bytes",
+                           len, bytes.len() - RECORD_HEADER_SIZE))
+    }
+
+    Ok(RECORD_HEADER_SIZE .. RECORD_HEADER_SIZE + len)
+}
 
 pub struct CurrentRecordId {
     record_id: RecordId,
@@ -109,41 +404,495 @@ impl CurrentRecordId {
     }
 }
 
+/// A value that can be stored as a record of its own via
+/// `Database::write_typed` and read back via `Database::read_typed`.
+pub trait DbRecord: Serialize + Deserialize {
+    /// The exact number of bytes that `Serialize::write` will produce.
+    fn serialized_size(&self) -> Size;
+}
+
+/// Describes a data structure outgrowing its allocation and being moved to a
+/// larger one. Capacities are given in items, byte sizes include headers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct GrowEvent {
+    pub old_capacity: Size,
+    pub new_capacity: Size,
+    pub old_byte_size: Size,
+    pub new_byte_size: Size,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DatabaseStats {
+    pub record_count: usize,
+    /// The number of bytes occupied by live records, including their
+    /// reference lists.
+    pub record_bytes: Size,
+    /// The number of unused slots in the record table.
+    pub free_record_ids: usize,
+    pub allocator: AllocatorStats,
+}
+
+pub type GrowObserver<'a> = Box<dyn FnMut(GrowEvent) + Send + Sync + 'a>;
+
 pub struct Database<S: Storage> {
     memory: Memory<S>,
     record_table: RuntimeRecordTable<S>,
     buffer_providers: Vec<BufferProvider>,
+    grow_observer: Option<GrowObserver<'static>>,
+    // Only maintained if enabled via `track_insertion_order()`.
+    insertion_order: Option<Vec<RecordId>>,
+    append_only: bool,
+    // Set via `write_record_headers()`, not persisted.
+    record_headers: bool,
+    // The hash table backing `sequence()`, created on first use.
+    sequence_table: Option<Allocation>,
+    // Registered via `register_structure()`.
+    structures: Vec<StructureRef>,
 }
 
 impl<S: Storage> Database<S> {
 
-    pub fn init(mut memory: Memory<S>) -> Database<S> {
+    /// Like `init()` but returns an error instead of panicking if `memory`
+    /// cannot hold an empty database.
+    pub fn try_init(memory: Memory<S>) -> Result<Database<S>, String> {
+        let required_size = header::header_size() + record::record_table_alloc_size_for(0);
+
+        if memory.size() < required_size {
+            return Err(format!("Storage too small for a database. Size is {} bytes, \
+                                at least {} bytes are needed.",
+                               memory.size().as_u32(),
+                               required_size.as_u32()));
+        }
+
+        if memory.allocator.lock().allocation_count() != 0 {
+            return Err("Memory already contains allocations".to_string());
+        }
+
+        Ok(Database::init(memory))
+    }
+
+    pub fn init(memory: Memory<S>) -> Database<S> {
+        Database::init_with_capacity(memory, 0)
+    }
+
+    /// Like `init()` but makes room for `record_capacity` records up front, so
+    /// that the record table does not have to grow until that many records
+    /// have been written.
+    pub fn init_with_capacity(mut memory: Memory<S>, record_capacity: usize) -> Database<S> {
         header::reserve_header(&mut memory);
 
-        let record_table = RuntimeRecordTable::from(RecordTableMut::alloc(&memory, &[]));
+        let record_table = {
+            let mut record_table = RecordTableMut::alloc(&memory, &[]);
+            record_table.reserve(record_capacity);
+            RuntimeRecordTable::from(record_table)
+        };
 
         Database {
             memory,
             record_table,
             buffer_providers: Vec::new(),
+            grow_observer: None,
+            insertion_order: None,
+            append_only: false,
+            record_headers: false,
+            sequence_table: None,
+            structures: Vec::new(),
         }
     }
 
-    fn alloc_record(&mut self) -> RecordId {
-        self.record_table.with_mut(&self.memory, |record_table| {
+    /// Creates a database that records can only be added to. Deleting records
+    /// is an error and memory is allocated with `AllocStrategy::Bump`, so
+    /// records are laid out in the order they are written. The mode is
+    /// persisted, a database opened again is still append-only.
+    pub fn init_append_only(memory: Memory<S>) -> Database<S> {
+        memory.allocator.lock().set_strategy(AllocStrategy::Bump);
+
+        let mut db = Database::init(memory);
+        db.append_only = true;
+        db
+    }
+
+    /// Opens a database that has previously been persisted to `storage`.
+    pub fn open(storage: S) -> Result<Database<S>, String> {
+        let (memory, roots) = Database::load(storage)?;
+        Ok(Database::from_loaded(memory, roots))
+    }
+
+    /// Like `open()` but also checks the structure of the loaded database,
+    /// verifies the checksum stored in the footer, and turns any panic caused
+    /// by corrupt data into an error, so that opening an untrusted file cannot
+    /// bring down the host process.
+    ///
+    /// Reading the footer and the allocator state returns errors directly,
+    /// the later checks rely on `catch_unwind`. That does not help when
+    /// building with `panic = "abort"`, and the panic hook still prints the
+    /// message of a caught panic to stderr.
+    pub fn open_checked(storage: S) -> Result<Database<S>, String> {
+        // Everything is checked before the `Database` is constructed because
+        // dropping it would write to the (corrupt) storage.
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            let (memory, roots) = Database::load(storage)?;
+            check_structure(&memory, &roots)?;
+            check_checksum(&memory, &roots)?;
+            Ok(Database::from_loaded(memory, roots))
+        }));
+
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                    message.to_string()
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    message.clone()
+                } else {
+                    "unknown error".to_string()
+                };
+
+                Err(format!("Corrupt database: {}", message))
+            }
+        }
+    }
+
+    /// Loads the memory of a database that has previously been persisted to
+    /// `storage`, without opening the database. Its allocator state is the
+    /// persisted one, i.e. it includes the header and the footer, as needed
+    /// by `diff()`.
+    pub fn load_memory(storage: S) -> Result<Memory<S>, String> {
+        Database::load_persisted(storage).map(|(memory, _, _)| memory)
+    }
+
+    fn load(storage: S) -> Result<(Memory<S>, footer::Roots), String> {
+        let (memory, roots, footer_allocation) = Database::load_persisted(storage)?;
+
+        if !S::IS_READONLY {
+            // The footer is rewritten when the database is persisted again.
+            memory.free(footer_allocation);
+        }
+
+        Ok((memory, roots))
+    }
+
+    fn load_persisted(storage: S) -> Result<(Memory<S>, footer::Roots, Allocation), String> {
+        let header = header::read_header(&storage)?;
+
+        // Until the actual allocator state is known, treat the whole storage
+        // as allocated so that the footer can be read.
+        let mut bootstrap_allocator = Allocator::new(storage.size());
+        bootstrap_allocator.alloc(storage.size());
+        let memory = Memory::new_with_allocator(storage, bootstrap_allocator);
+
+        let footer = footer::read_footer(&memory, header.footer_addr())?;
+        footer.allocator.verify()?;
+
+        let footer_allocation = match footer.allocator.allocation_at(header.footer_addr()) {
+            Some(allocation) => allocation,
+            None => return Err("Footer is not allocated".to_string()),
+        };
+
+        let memory = Memory::new_with_allocator(memory.storage, footer.allocator);
+        Ok((memory, footer.roots, footer_allocation))
+    }
+
+    /// Returns a read-only handle to this database that can be shared between
+    /// threads, e.g. for calling `get_record()` concurrently. The database
+    /// cannot be modified while the handle is alive.
+    pub fn shared_view(&mut self) -> Database<SharedView<'_, S>> {
+        Database {
+            memory: self.memory.shared_view(),
+            record_table: RuntimeRecordTable::at(self.record_table.data()),
+            buffer_providers: Vec::new(),
+            grow_observer: None,
+            insertion_order: self.insertion_order.clone(),
+            append_only: self.append_only,
+            record_headers: self.record_headers,
+            sequence_table: self.sequence_table,
+            structures: self.structures.clone(),
+        }
+    }
+
+    fn from_loaded(memory: Memory<S>, roots: footer::Roots) -> Database<S> {
+        Database {
+            memory,
+            record_table: RuntimeRecordTable::at(roots.record_table),
+            buffer_providers: Vec::new(),
+            grow_observer: None,
+            insertion_order: None,
+            append_only: roots.append_only,
+            record_headers: false,
+            sequence_table: roots.sequence_table,
+            structures: roots.structures,
+        }
+    }
+
+    /// The memory the database lives in. Structures that are allocated in it
+    /// directly can be registered via `register_structure()`.
+    #[inline]
+    pub fn memory(&self) -> &Memory<S> {
+        &self.memory
+    }
+
+    /// Adds `allocation` to the directory of top-level structures that is
+    /// persisted with the database, see `structures()`. If the structure
+    /// moves, e.g. because a hash table grows, it has to be registered again
+    /// under its new allocation.
+    pub fn register_structure(&mut self, kind: StructureKind, allocation: Allocation) {
+        assert!(kind != StructureKind::RecordTable,
+                "The record table of a database is always part of structures()");
+        assert!(!self.structures.iter().any(|s| s.allocation == allocation),
+                "{:?} is already registered", allocation);
+
+        self.structures.push(StructureRef { kind, allocation });
+    }
+
+    /// Removes `allocation` from the directory of top-level structures.
+    /// Returns whether it was registered.
+    pub fn unregister_structure(&mut self, allocation: Allocation) -> bool {
+        let len = self.structures.len();
+        self.structures.retain(|s| s.allocation != allocation);
+        self.structures.len() != len
+    }
+
+    /// Lists the top-level structures of the database: its record table, the
+    /// hash table backing `sequence()` if there is one, and everything
+    /// registered via `register_structure()`.
+    pub fn structures(&self) -> Vec<StructureRef> {
+        let mut structures = vec![StructureRef {
+            kind: StructureKind::RecordTable,
+            allocation: self.record_table.data(),
+        }];
+
+        if let Some(sequence_table) = self.sequence_table {
+            structures.push(StructureRef {
+                kind: StructureKind::HashTable,
+                allocation: sequence_table,
+            });
+        }
+
+        structures.extend(self.structures.iter().cloned());
+        structures
+    }
+
+    pub fn stats(&self) -> DatabaseStats {
+        let (record_count, array_len, record_bytes) = self.record_table.with(&self.memory, |record_table| {
+            let mut record_bytes = Size(0);
+            record_table.iter_live(|_, record| record_bytes += record.size);
+            (record_table.item_count(), record_table.array_len(), record_bytes)
+        });
+
+        DatabaseStats {
+            record_count: record_count.bytes(),
+            record_bytes,
+            free_record_ids: (array_len - Size(1) - record_count).bytes(),
+            allocator: self.memory.allocator.lock().stats(),
+        }
+    }
+
+    /// Registers a callback that is invoked whenever the record table has to
+    /// grow.
+    pub fn set_grow_observer(&mut self, observer: GrowObserver<'static>) {
+        self.grow_observer = Some(observer);
+    }
+
+    /// Starts remembering the order in which records are written, so that
+    /// they can be visited in that order by `iter_records_ordered()`. Only
+    /// records written after this call are tracked and the order is not
+    /// persisted.
+    pub fn track_insertion_order(&mut self) {
+        if self.insertion_order.is_none() {
+            self.insertion_order = Some(Vec::new());
+        }
+    }
+
+    /// Starts prefixing the payload of every record written via an `Encoder`
+    /// with a header that `Decoder::new_checked()` verifies, so that reads
+    /// from a wrong address are caught. The header is part of the payload as
+    /// returned by `get_record()`. Only records written after this call get a
+    /// header and the setting is not persisted.
+    pub fn write_record_headers(&mut self) {
+        self.record_headers = true;
+    }
+
+    fn alloc_record(&mut self) -> Result<RecordId, RecordTableFull> {
+        let old_byte_size = self.record_table.data().size;
+        let old_capacity = self.record_table_capacity();
+
+        let record_id = self.record_table.with_mut(&self.memory, |record_table| {
             record_table.alloc_record()
+        })?;
+
+        let new_byte_size = self.record_table.data().size;
+
+        if new_byte_size != old_byte_size {
+            let new_capacity = self.record_table_capacity();
+
+            if let Some(ref mut observer) = self.grow_observer {
+                observer(GrowEvent {
+                    old_capacity,
+                    new_capacity,
+                    old_byte_size,
+                    new_byte_size,
+                });
+            }
+        }
+
+        if let Some(ref mut insertion_order) = self.insertion_order {
+            insertion_order.push(record_id);
+        }
+
+        Ok(record_id)
+    }
+
+    /// Makes room for at least `additional` more records in the record table,
+    /// so that bulk loads don't have to grow it repeatedly.
+    pub fn reserve_records(&mut self, additional: usize) {
+        self.record_table.with_mut(&self.memory, |record_table| {
+            record_table.reserve(additional)
         })
     }
 
-    pub fn get_record(&self, record_id: RecordId) -> MemRef {
-        let record = self.record_table.with(&self.memory, |record_table| {
+    fn record_table_capacity(&self) -> Size {
+        self.record_table.with(&self.memory, |record_table| {
+            record_table.array_len() - Size(1)
+        })
+    }
+
+    /// Whether `record_id` refers to a live record, i.e. one that has been
+    /// written and not deleted since. Out-of-range ids are fine too.
+    pub fn contains_record(&self, record_id: RecordId) -> bool {
+        self.record_table.with(&self.memory, |record_table| {
+            record_table.try_get_record(record_id).is_some()
+        })
+    }
+
+    fn record(&self, record_id: RecordId) -> Record {
+        self.record_table.with(&self.memory, |record_table| {
             record_table.get_record(record_id)
+        })
+    }
+
+    fn record_meta(&self, record: Record) -> RecordMeta {
+        let count_addr = record.addr + (record.size - REFERENCE_COUNT_SIZE);
+        let ref_list_size = Size::from_bytes(count_addr.offset() - record.ref_list_addr.offset());
+        let reference_count = ref_list_size / REFERENCE_ID_SIZE.as_u32();
+        debug_assert_eq!(reference_count, Size::read_at(&self.memory, count_addr));
+
+        RecordMeta {
+            payload_size: Size::from_bytes(record.ref_list_addr.offset() - record.addr.offset()),
+            ref_list_addr: record.ref_list_addr,
+            reference_count,
+        }
+    }
+
+    fn payload_size(&self, record: Record) -> Size {
+        self.record_meta(record).payload_size
+    }
+
+    pub fn get_record(&self, record_id: RecordId) -> MemRef<'_> {
+        let record = self.record(record_id);
+        self.memory.get_bytes(record.addr, self.payload_size(record))
+    }
+
+    /// Like `get_record()` but returns `None` instead of panicking if there
+    /// is no record with the given id, e.g. because it has been deleted.
+    pub fn try_get_record(&self, record_id: RecordId) -> Option<MemRef<'_>> {
+        let record = self.record_table.with(&self.memory, |record_table| {
+            record_table.try_get_record(record_id)
+        })?;
+
+        Some(self.memory.get_bytes(record.addr, self.payload_size(record)))
+    }
+
+    /// Calls `f` with the id and payload of every record in the database.
+    pub fn iter_records<F: FnMut(RecordId, &[u8])>(&self, mut f: F) {
+        self.record_table.with(&self.memory, |record_table| {
+            record_table.iter_live(|record_id, record| {
+                f(record_id, &self.memory.get_bytes(record.addr, self.payload_size(record)));
+            });
+        })
+    }
+
+    /// Like `iter_records()` but visits records in the order in which they
+    /// were written. Panics if `track_insertion_order()` has not been called.
+    pub fn iter_records_ordered<F: FnMut(RecordId, &[u8])>(&self, mut f: F) {
+        let insertion_order = self.insertion_order.as_ref().expect(
+            "iter_records_ordered() requires track_insertion_order()");
+
+        for &record_id in insertion_order {
+            f(record_id, &self.get_record(record_id));
+        }
+    }
+
+    /// Returns the records directly referenced by the given record, in
+    /// ascending order. Only the reference list at the end of the record is
+    /// read, not the payload.
+    pub fn referenced_by(&self, record_id: RecordId) -> Vec<RecordId> {
+        let meta = self.record_meta(self.record(record_id));
+        let mut reader = StorageReader::new(&self.memory, meta.ref_list_addr);
+        (0 .. meta.reference_count.as_u32()).map(|_| RecordId::read(&mut reader)).collect()
+    }
+
+    /// Calls `f` once for `root` and every record that is transitively
+    /// referenced by it. Cycles in the reference graph are handled gracefully.
+    pub fn walk_references<F: FnMut(RecordId)>(&self, root: RecordId, f: F) {
+        self.walk_references_from(&[root], f);
+    }
+
+    /// Returns the set of records that are transitively referenced by any of
+    /// `roots`, including the roots themselves.
+    pub fn reachable_records(&self, roots: &[RecordId]) -> HashSet<RecordId> {
+        let mut reachable = HashSet::new();
+        self.walk_references_from(roots, |record_id| {
+            reachable.insert(record_id);
+        });
+        reachable
+    }
+
+    /// Returns the live records that cannot be reached from any of `roots`.
+    /// Nothing is deleted; this is meant for finding leaked records.
+    pub fn unreachable_records(&self, roots: &[RecordId]) -> HashSet<RecordId> {
+        let reachable = self.reachable_records(roots);
+        let mut unreachable = HashSet::new();
+
+        self.record_table.with(&self.memory, |record_table| {
+            record_table.iter_live(|record_id, _| {
+                if !reachable.contains(&record_id) {
+                    unreachable.insert(record_id);
+                }
+            });
         });
-        self.memory.get_bytes(record.addr, record.size)
+
+        unreachable
+    }
+
+    fn walk_references_from<F: FnMut(RecordId)>(&self, roots: &[RecordId], mut f: F) {
+        let mut visited = HashSet::new();
+        let mut stack: Vec<RecordId> = roots.iter().rev().cloned().collect();
+
+        while let Some(record_id) = stack.pop() {
+            if !visited.insert(record_id) {
+                continue
+            }
+
+            f(record_id);
+
+            for referenced in self.referenced_by(record_id) {
+                if !visited.contains(&referenced) {
+                    stack.push(referenced);
+                }
+            }
+        }
     }
 
     pub fn write_record<W>(&mut self, w: W) -> RecordId
         where W: FnOnce(&mut Encoder<'_, '_, S>, &mut CurrentRecordId)
+    {
+        self.try_write_record(w).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `write_record()` but returns an error instead of panicking if
+    /// the record table cannot grow any further.
+    pub fn try_write_record<W>(&mut self, w: W) -> Result<RecordId, RecordTableFull>
+        where W: FnOnce(&mut Encoder<'_, '_, S>, &mut CurrentRecordId)
     {
         let mut buffer_provider = self.buffer_providers
                                       .pop()
@@ -155,7 +904,7 @@ impl<S: Storage> Database<S> {
                 referenced_records: HashSet::new(),
             };
 
-            encoder.write_record(w)
+            encoder.try_write_record(w)
         };
 
         self.buffer_providers.push(buffer_provider);
@@ -163,41 +912,1631 @@ impl<S: Storage> Database<S> {
         record_id
     }
 
-    pub fn delete_record(&mut self, record_id: RecordId) {
-        // let record = self.records[record_id.idx()];
-        let record = self.record_table.with_mut(&self.memory, |record_table| {
-            record_table.delete_record(record_id)
-        });
+    /// Starts writing a record with a payload of exactly `size_hint` bytes.
+    /// The space is allocated up front and the payload is written straight
+    /// into it, so that large records don't have to be buffered in memory.
+    pub fn write_record_streaming(&mut self, size_hint: Size) -> StreamWriter<'_, S> {
+        let allocation = self.memory.alloc_uninit(size_hint + REFERENCE_COUNT_SIZE);
 
-        self.memory.free(Allocation::new(record.addr, record.size));
+        StreamWriter {
+            db: self,
+            allocation: Some(allocation),
+            payload_size: size_hint,
+            written: Size(0),
+        }
     }
 
-    pub fn persist(self) {
-        mem::drop(self);
-    }
-}
+    pub fn write_typed<R: DbRecord>(&mut self, value: &R) -> RecordId {
+        let record_id = self.alloc_record().unwrap_or_else(|err| panic!("{}", err));
+        let record_size = value.serialized_size() + REFERENCE_COUNT_SIZE;
+        let allocation = self.memory.alloc_uninit(record_size);
 
-impl<S: Storage> Drop for Database<S> {
-    fn drop(&mut self) {
-        if S::IS_READONLY {
-            return
+        {
+            // Don't trust serialized_size(), a wrong value must not lead to
+            // overwriting neighboring allocations. The allocation itself can
+            // be larger than requested.
+            let record_end = allocation.addr + record_size;
+            let mut writer = StorageWriter::with_limit(&self.memory, allocation.addr, record_end);
+            value.write(&mut writer);
+            // Typed records don't reference other records.
+            writer.write_u32(0);
+            assert_eq!(writer.addr(), record_end,
+                "serialized_size() does not match number of bytes written");
         }
 
-        // let record_table_addr = record::persist_record_table(&self.memory,
-        //                                                      self.records,
-        //                                                      self.record_id_free_list);
+        self.record_table.with_mut(&self.memory, |record_table| {
+            record_table.set_record_data(record_id, allocation.addr, record_size,
+                                         allocation.addr + (record_size - REFERENCE_COUNT_SIZE));
+        });
 
-        // Find footer address
-        let footer_addr = self.memory.allocator.lock().max_addr();
+        record_id
+    }
 
-        // Write footer
-        // footer::write_footer(&mut self.memory.storage, footer_addr, &self.memory.allocator);
+    pub fn read_typed<R: DbRecord>(&self, record_id: RecordId) -> R {
+        let record = self.record(record_id);
 
-        header::write_header(&mut self.memory.storage, false, footer_addr);
+        let mut reader = StorageReader::new(&self.memory, record.addr);
+        let value = R::read(&mut reader);
+        assert!(reader.addr() <= record.addr + self.payload_size(record),
+            "read_typed() read beyond the end of the record");
+        value
     }
-}
 
+    pub fn delete_record(&mut self, record_id: RecordId) {
+        if let Err(err) = self.try_delete_record(record_id) {
+            panic!("{}", err);
+        }
+    }
 
+    /// Like `delete_record()` but returns an error instead of panicking if
+    /// the database does not allow deleting records.
+    pub fn try_delete_record(&mut self, record_id: RecordId) -> Result<(), String> {
+        if self.append_only {
+            return Err("Cannot delete records from an append-only database".to_string())
+        }
+
+        // Deleting a record twice would free its memory twice and put its id
+        // on the free list twice.
+        if !self.contains_record(record_id) {
+            return Err(format!("{:?} does not refer to a live record", record_id))
+        }
+
+        let record = self.record_table.with_mut(&self.memory, |record_table| {
+            record_table.delete_record(record_id)
+        });
+
+        // The allocation can be larger than the record, e.g. with size
+        // classes, which aren't known anymore after reopening the database.
+        let allocation = self.memory.allocator.lock().allocation_at(record.addr);
+        self.memory.free(allocation.expect("Record is not allocated"));
+
+        if let Some(ref mut insertion_order) = self.insertion_order {
+            if let Some(index) = insertion_order.iter().position(|&id| id == record_id) {
+                insertion_order.remove(index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves all live records to the front of a new record table so that the
+    /// slots of deleted records don't take up space anymore. This changes
+    /// record ids: the references stored in records are updated, the returned
+    /// map from old to new ids must be used for any ids held elsewhere.
+    pub fn compact_records(&mut self) -> HashMap<RecordId, RecordId> {
+        let (remap, records) = self.record_table.with_mut(&self.memory, |record_table| {
+            let remap = record_table.compact();
+            let records: Vec<_> = remap.values()
+                                       .map(|&record_id| record_table.get_record(record_id))
+                                       .collect();
+            (remap, records)
+        });
+
+        // Ids keep their relative order, so reference lists stay sorted.
+        for record in records {
+            let meta = self.record_meta(record);
+            for index in 0 .. meta.reference_count.as_u32() {
+                let addr = meta.ref_list_addr + REFERENCE_ID_SIZE * index;
+                let old_id = RecordId::read_at(&self.memory, addr);
+                remap[&old_id].write_at(&self.memory, addr);
+            }
+        }
+
+        if let Some(ref mut insertion_order) = self.insertion_order {
+            for record_id in insertion_order.iter_mut() {
+                *record_id = remap[record_id];
+            }
+        }
+
+        remap
+    }
+
+    /// Returns the next value of the sequence called `name`, starting at zero.
+    /// Sequences are persisted together with the database.
+    pub fn sequence(&mut self, name: &[u8]) -> u64 {
+        let mut value = 0;
+
+        let sequence_table = {
+            let mut table: HashTable<_> = match self.sequence_table {
+                Some(data) => HashTable::from_existing(&self.memory, data).unwrap(),
+                None => HashTable::new(&self.memory),
+            };
+
+            table.update(name, |current| {
+                if let Some(current) = current {
+                    value = LittleEndian::read_u64(current);
+                }
+
+                let mut next = [0u8; 8];
+                LittleEndian::write_u64(&mut next, value + 1);
+                Some(next.to_vec())
+            });
+
+            table.allocation()
+        };
+
+        self.sequence_table = Some(sequence_table);
+        value
+    }
+
+    /// Copies every live record into `dst` and returns the id that each
+    /// record got there. References between the copied records are rewritten
+    /// to the new ids. Sequences are not copied.
+    pub fn export_to<S2: Storage>(&self, dst: &mut Database<S2>) -> HashMap<RecordId, RecordId> {
+        let mut record_ids = vec![];
+        self.record_table.with(&self.memory, |record_table| {
+            record_table.iter_live(|record_id, _| record_ids.push(record_id));
+        });
+
+        // All ids are allocated up front because references can point to
+        // records that have not been copied yet.
+        let remap: HashMap<RecordId, RecordId> = record_ids.iter().map(|&old_id| {
+            (old_id, dst.alloc_record().unwrap_or_else(|err| panic!("{}", err)))
+        }).collect();
+
+        let mut buffer_provider = BufferProvider::new();
+
+        for &old_id in &record_ids {
+            let new_id = remap[&old_id];
+            let references: HashSet<RecordId> = self.referenced_by(old_id)
+                                                    .into_iter()
+                                                    .map(|id| remap[&id])
+                                                    .collect();
+
+            dst.record_table.with_mut(&dst.memory, |record_table| {
+                for &id in &references {
+                    record_table.inc_ref_count(id);
+                }
+            });
+
+            let mut buffer = buffer_provider.get_buffer();
+            buffer.write_bytes(&self.get_record(old_id));
+            let payload_size = buffer.len();
+            write_reference_list(&mut buffer, references);
+
+            let record_size = buffer.len();
+            let allocation = dst.memory.alloc_uninit(record_size);
+            dst.memory.get_bytes_mut(allocation.addr, record_size)
+                      .copy_from_slice(buffer.bytes());
+
+            dst.record_table.with_mut(&dst.memory, |record_table| {
+                record_table.set_record_data(new_id, allocation.addr, record_size,
+                                             allocation.addr + payload_size);
+            });
+        }
+
+        remap
+    }
+
+    /// Writes the footer and header and hands back the underlying storage,
+    /// which can later be passed to `Database::open`.
+    pub fn persist(mut self) -> S {
+        if !S::IS_READONLY {
+            if let Err(err) = self.write_footer_and_header() {
+                panic!("Could not flush storage: {}", err);
+            }
+        }
+
+        // Move the memory out without running our Drop impl, which would
+        // write another footer. The pattern lists every field, so that fields
+        // added later can't be leaked by accident.
+        let this = mem::ManuallyDrop::new(self);
+        let Database {
+            ref memory,
+            ref record_table,
+            ref buffer_providers,
+            ref grow_observer,
+            ref insertion_order,
+            append_only: _,
+            record_headers: _,
+            sequence_table: _,
+            ref structures,
+        } = *this;
+
+        unsafe {
+            mem::drop((ptr::read(record_table),
+                       ptr::read(buffer_providers),
+                       ptr::read(grow_observer),
+                       ptr::read(insertion_order),
+                       ptr::read(structures)));
+            ptr::read(memory).storage
+        }
+    }
+
+    fn write_footer_and_header(&mut self) -> io::Result<()> {
+        let roots = footer::Roots {
+            record_table: self.record_table.data(),
+            sequence_table: self.sequence_table,
+            structures: self.structures.clone(),
+            checksum: footer::content_checksum(&self.memory, None),
+            append_only: self.append_only,
+        };
+
+        let footer = footer::write_footer(&self.memory, &roots);
+        header::write_header(&self.memory.storage, false, footer.addr);
+        self.memory.storage.flush()
+    }
+}
+
+// Checks that everything reachable from the footer of a freshly loaded
+// database refers to valid allocations, so that later accesses don't run into
+// garbage.
+fn check_structure<S: Storage>(memory: &Memory<S>, roots: &footer::Roots) -> Result<(), String> {
+    let allocator = memory.allocator.lock().clone();
+    let record_table = roots.record_table;
+
+    if allocator.allocation_at(record_table.addr) != Some(record_table) {
+        return Err(format!("Record table {:?} is not allocated", record_table))
+    }
+
+    let record_table = RecordTable::at(memory, record_table.addr, record_table.size);
+    record_table.verify()?;
+
+    let mut records = vec![];
+    record_table.iter_live(|id, record| records.push((id, record)));
+
+    for (id, record) in records {
+        match allocator.allocation_at(record.addr) {
+            Some(allocation) if record.size <= allocation.size => {}
+            _ => return Err(format!("{:?} is not allocated", id)),
+        }
 
+        if record.size < REFERENCE_COUNT_SIZE {
+            return Err(format!("{:?} is too small", id))
+        }
+
+        let count_addr = record.addr + (record.size - REFERENCE_COUNT_SIZE);
+        if record.ref_list_addr < record.addr || record.ref_list_addr > count_addr {
+            return Err(format!("{:?} has an invalid reference list address", id))
+        }
+
+        let reference_count = Size::read_at(memory, count_addr);
+        let ref_list_size = Size::from_bytes(count_addr.offset() - record.ref_list_addr.offset());
+
+        let list_count = ref_list_size / REFERENCE_ID_SIZE.as_u32();
+        if REFERENCE_ID_SIZE * list_count != ref_list_size || list_count != reference_count {
+            return Err(format!("{:?} has an invalid reference count", id))
+        }
+
+        let mut reader = StorageReader::new(memory, record.ref_list_addr);
+        for _ in 0 .. reference_count.as_u32() {
+            let referenced = RecordId::read(&mut reader);
+            if record_table.try_get_record(referenced).is_none() {
+                return Err(format!("{:?} references missing {:?}", id, referenced))
+            }
+        }
+    }
+
+    for structure in &roots.structures {
+        if allocator.allocation_at(structure.allocation.addr) != Some(structure.allocation) {
+            return Err(format!("{:?} is not allocated", structure))
+        }
+    }
+
+    if let Some(sequence_table) = roots.sequence_table {
+        if allocator.allocation_at(sequence_table.addr) != Some(sequence_table) {
+            return Err("Sequence table is not allocated".to_string())
+        }
+
+        HashTable::<S>::from_existing(memory, sequence_table)?;
+    }
+
+    Ok(())
+}
+
+// The footer has already been freed unless the storage is read-only, in which
+// case it has to be excluded explicitly.
+fn check_checksum<S: Storage>(memory: &Memory<S>, roots: &footer::Roots) -> Result<(), String> {
+    let footer_addr = header::read_header(memory.storage())?.footer_addr();
 
+    if footer::content_checksum(memory, Some(footer_addr)) != roots.checksum {
+        return Err("Checksum does not match".to_string())
+    }
+
+    Ok(())
+}
 
+impl<S: Storage> Drop for Database<S> {
+    fn drop(&mut self) {
+        if S::IS_READONLY {
+            return
+        }
+
+        // There's no way to report an error from here. Use `persist()` if
+        // that matters.
+        let _ = self.write_footer_and_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_database() -> Database<MemStore> {
+        Database::init(Memory::new(MemStore::new(100000)))
+    }
+
+    #[test]
+    fn concurrent_get_record() {
+        use std::thread;
+
+        let mut db = create_database();
+
+        let record_ids: Vec<_> = (0u8 .. 20).map(|i| {
+            db.write_record(|encoder, _| {
+                encoder.buffer().write_bytes(&[i; 7]);
+            })
+        }).collect();
+
+        let db = &db.shared_view();
+        let record_ids = &record_ids;
+
+        thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                scope.spawn(move || {
+                    for (i, &record_id) in record_ids.iter().enumerate() {
+                        assert_eq!(&*db.get_record(record_id), &[i as u8; 7][..]);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn single_threaded_memory() {
+        let mut db = Database::init(Memory::new(MemStore::new_single_threaded(100000)));
+
+        let record_ids: Vec<_> = (0u8 .. 20).map(|i| {
+            db.write_record(|encoder, _| {
+                encoder.buffer().write_bytes(&[i; 7]);
+            })
+        }).collect();
+
+        for &record_id in record_ids.iter().step_by(2) {
+            db.delete_record(record_id);
+        }
+
+        for (i, &record_id) in record_ids.iter().enumerate().skip(1).step_by(2) {
+            assert_eq!(&*db.get_record(record_id), &[i as u8; 7][..]);
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: u32,
+        y: u64,
+    }
+
+    impl Serialize for Point {
+        fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+            self.x.write(writer);
+            self.y.write(writer);
+        }
+    }
+
+    impl Deserialize for Point {
+        fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Point {
+            let x = u32::read(reader);
+            let y = u64::read(reader);
+            Point { x, y }
+        }
+    }
+
+    impl DbRecord for Point {
+        fn serialized_size(&self) -> Size {
+            Size(12)
+        }
+    }
+
+    #[test]
+    fn typed_records() {
+        let mut db = create_database();
+
+        let points: Vec<_> = (0 .. 10).map(|i| Point { x: i, y: (i as u64) << 40 }).collect();
+        let record_ids: Vec<_> = points.iter().map(|point| db.write_typed(point)).collect();
+
+        for (point, &record_id) in points.iter().zip(record_ids.iter()) {
+            assert_eq!(&db.read_typed::<Point>(record_id), point);
+            assert_eq!(db.get_record(record_id).len(), 12);
+        }
+    }
+
+    struct LyingPoint(Point);
+
+    impl Serialize for LyingPoint {
+        fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+            self.0.write(writer);
+        }
+    }
+
+    impl Deserialize for LyingPoint {
+        fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> LyingPoint {
+            LyingPoint(Point::read(reader))
+        }
+    }
+
+    impl DbRecord for LyingPoint {
+        fn serialized_size(&self) -> Size {
+            Size(4)
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the limit")]
+    fn typed_record_size_mismatch() {
+        let mut db = create_database();
+        db.write_typed(&LyingPoint(Point { x: 1, y: 2 }));
+    }
+
+    fn walk(db: &Database<MemStore>, root: RecordId) -> Vec<RecordId> {
+        let mut visited = vec![];
+        db.walk_references(root, |record_id| visited.push(record_id));
+        visited
+    }
+
+    #[test]
+    fn walk_references_with_cycle() {
+        let mut db = create_database();
+
+        let mut b = None;
+        let a = db.write_record(|encoder, current_record_id| {
+            let a = current_record_id.get();
+
+            let record_b = encoder.write_record(|encoder, _| {
+                encoder.buffer().write_bytes(b"b");
+                encoder.write_record_id(a);
+            });
+
+            encoder.buffer().write_bytes(b"a");
+            encoder.write_record_id(record_b);
+            b = Some(record_b);
+        });
+        let b = b.unwrap();
+
+        assert_eq!(&*db.get_record(a), b"a");
+        assert_eq!(&*db.get_record(b), b"b");
+        assert_eq!(db.referenced_by(a), vec![b]);
+        assert_eq!(db.referenced_by(b), vec![a]);
+        assert_eq!(db.record(a).ref_count, 1);
+        assert_eq!(db.record(b).ref_count, 1);
+
+        assert_eq!(walk(&db, a), vec![a, b]);
+        assert_eq!(walk(&db, b), vec![b, a]);
+    }
+
+    #[test]
+    fn walk_references_shared() {
+        let mut db = create_database();
+
+        let leaf = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"leaf");
+        });
+
+        let root = db.write_record(|encoder, _| {
+            let left = encoder.write_record(|encoder, _| encoder.write_record_id(leaf));
+            let right = encoder.write_record(|encoder, _| encoder.write_record_id(leaf));
+            encoder.write_record_id(left);
+            encoder.write_record_id(right);
+            encoder.write_record_id(left);
+        });
+
+        assert_eq!(db.record(leaf).ref_count, 2);
+
+        let mut visited = walk(&db, root);
+        assert_eq!(visited.len(), 4);
+        visited.sort();
+        visited.dedup();
+        assert_eq!(visited.len(), 4);
+
+        assert_eq!(walk(&db, leaf), vec![leaf]);
+    }
+
+    #[test]
+    fn compact_records() {
+        let mut db = create_database();
+        db.track_insertion_order();
+
+        let leaves: Vec<_> = (0 .. 10u8).map(|i| {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(&[i]))
+        }).collect();
+
+        for &leaf in leaves.iter().step_by(2) {
+            db.delete_record(leaf);
+        }
+
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            for &leaf in leaves.iter().skip(1).step_by(2) {
+                encoder.write_record_id(leaf);
+            }
+        });
+
+        let remap = db.compact_records();
+        assert_eq!(remap.len(), 6);
+
+        let root = remap[&root];
+        let leaves: Vec<_> = leaves.iter().skip(1).step_by(2).map(|leaf| remap[leaf]).collect();
+        assert_eq!(&db.get_record(root)[..4], b"root");
+        assert_eq!(db.referenced_by(root), leaves);
+
+        let mut visited = walk(&db, root);
+        visited.sort();
+        assert_eq!(visited, (1 .. 7).map(RecordId::from_usize).collect::<Vec<_>>());
+
+        let mut order = vec![];
+        db.iter_records_ordered(|record_id, _| order.push(record_id));
+        assert_eq!(order[5], root);
+        assert_eq!(order[.. 5], leaves[..]);
+
+        let db = Database::open(db.persist()).unwrap();
+        for (index, &leaf) in leaves.iter().enumerate() {
+            assert_eq!(&db.get_record(leaf)[..1], &[index as u8 * 2 + 1]);
+            assert_eq!(db.record(leaf).ref_count, 1);
+        }
+        assert_eq!(db.referenced_by(root), leaves);
+    }
+
+    #[test]
+    fn iter_records() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"b");
+            encoder.write_record_id(a);
+        });
+
+        let mut records = vec![];
+        db.iter_records(|id, bytes| records.push((id, bytes.to_vec())));
+        records.sort();
+
+        assert_eq!(records, vec![(a, b"a".to_vec()), (b, b"b".to_vec())]);
+    }
+
+    #[test]
+    fn try_get_record() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        db.delete_record(b);
+
+        assert_eq!(db.try_get_record(a).as_ref().map(|x| &**x), Some(&b"a"[..]));
+        assert!(db.try_get_record(b).is_none());
+        assert!(db.try_get_record(RecordId::from_usize(0)).is_none());
+        assert!(db.try_get_record(RecordId::from_usize(1000)).is_none());
+    }
+
+    #[test]
+    fn iter_records_ordered() {
+        let mut db = create_database();
+        db.track_insertion_order();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"c"));
+        db.delete_record(a);
+        // Reuses the id of `a`.
+        let d = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"d"));
+
+        let mut records = vec![];
+        db.iter_records_ordered(|id, bytes| records.push((id, bytes.to_vec())));
+
+        assert_eq!(records, vec![
+            (b, b"b".to_vec()),
+            (c, b"c".to_vec()),
+            (d, b"d".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn referenced_by() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"c"));
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_record_id(c);
+            encoder.write_record_id(a);
+            encoder.write_record_id(b);
+            encoder.write_record_id(a);
+        });
+
+        assert_eq!(db.referenced_by(root), vec![a, b, c]);
+        assert_eq!(db.referenced_by(a), vec![]);
+        assert_eq!(&*db.get_record(root), b"root");
+    }
+
+    #[test]
+    fn append_only() {
+        let mut db = Database::init_append_only(Memory::new(MemStore::new(10000)));
+
+        let mut last_addr = Address(0);
+        let mut ids = vec![];
+
+        // Enough records to make the record table grow a few times
+        for i in 0 .. 40u8 {
+            let id = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[i; 3]));
+            let addr = db.record(id).addr;
+            assert!(addr > last_addr);
+            last_addr = addr;
+            ids.push(id);
+        }
+
+        assert!(db.try_delete_record(ids[0]).is_err());
+        assert_eq!(&*db.get_record(ids[0]), &[0; 3]);
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+    }
+
+    #[test]
+    fn persist_keeps_append_only() {
+        let mut db = Database::init_append_only(Memory::new(MemStore::new(10000)));
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+
+        let mut db = Database::open(db.persist()).unwrap();
+        assert_eq!(db.memory.allocator.lock().strategy(), AllocStrategy::Bump);
+        assert!(db.try_delete_record(a).is_err());
+
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        assert!(db.record(b).addr > db.record(a).addr);
+
+        // Regular databases stay regular.
+        let mut db = Database::init(Memory::new(MemStore::new(10000)));
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let mut db = Database::open(db.persist()).unwrap();
+        assert!(db.try_delete_record(a).is_ok());
+    }
+
+    #[test]
+    fn size_classes() {
+        let memory = Memory::new(MemStore::new(100000));
+        memory.allocator.lock().set_size_classes(SizeClasses::PowerOfTwo);
+        let mut db = Database::init(memory);
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"bbb");
+            encoder.write_record_id(a);
+        });
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[7; 100]));
+
+        assert_eq!(&*db.get_record(a), b"a");
+        assert_eq!(&*db.get_record(b), b"bbb");
+        assert_eq!(db.referenced_by(b), vec![a]);
+
+        db.delete_record(b);
+
+        let mut db = Database::open(db.persist()).unwrap();
+        assert_eq!(&*db.get_record(a), b"a");
+        assert_eq!(&*db.get_record(c), &[7; 100][..]);
+
+        db.delete_record(c);
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+    }
+
+    #[test]
+    fn size_classes_sequences() {
+        for size_classes in vec![SizeClasses::PowerOfTwo, SizeClasses::Table(vec![Size(8), Size(24)])] {
+            let memory = Memory::new(MemStore::new(100000));
+            memory.allocator.lock().set_size_classes(size_classes.clone());
+            let mut db = Database::init(memory);
+
+            for i in 0 .. 50u64 {
+                assert_eq!(db.sequence(b"ids"), i);
+                assert_eq!(db.sequence(format!("sequence {}", i).as_bytes()), 0);
+            }
+
+            // The size classes are persisted, so the values of the sequence
+            // table can still be freed after reopening.
+            let mut db = Database::open(db.persist()).unwrap();
+            assert_eq!(db.memory.allocator.lock().size_classes(), &size_classes);
+            assert_eq!(db.sequence(b"ids"), 50);
+
+            let mut table = HashTable::<_>::from_existing(&db.memory, db.sequence_table.unwrap()).unwrap();
+            for i in 0 .. 50u64 {
+                assert!(table.remove(format!("sequence {}", i).as_bytes()));
+            }
+            assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn min_split_size() {
+        let memory = Memory::new(MemStore::new(100000));
+        memory.allocator.lock().set_min_split_size(Size(64));
+        let mut db = Database::init(memory);
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[1; 40]));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[2; 10]));
+        // Keep the space of `a` from being merged with the free tail.
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(&[0; 100]));
+        db.delete_record(a);
+
+        // This fits into the space freed by `a` without leaving enough room
+        // to split off the rest.
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[3; 5]));
+        let record = db.record(c);
+        let allocation = db.memory.allocator.lock().allocation_at(record.addr).unwrap();
+        assert!(allocation.size > record.size);
+
+        db.delete_record(c);
+        let d = db.write_typed(&Point { x: 4, y: 5 });
+        let record = db.record(d);
+        let allocation = db.memory.allocator.lock().allocation_at(record.addr).unwrap();
+        assert!(allocation.size > record.size);
+
+        assert_eq!(&*db.get_record(b), &[2; 10][..]);
+        assert_eq!(db.read_typed::<Point>(d), Point { x: 4, y: 5 });
+        assert_eq!(db.get_record(d).len(), 12);
+
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(&[3; 5]));
+        assert_eq!(&*db.get_record(c), &[3; 5][..]);
+
+        db.delete_record(d);
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+
+        // Sequences store their keys and values out of line, which must
+        // work with blocks larger than requested, also after reopening.
+        for i in 0 .. 50u64 {
+            assert_eq!(db.sequence(format!("sequence {}", i % 7).as_bytes()), i / 7);
+        }
+
+        let db = Database::open(db.persist()).unwrap();
+        assert_eq!(db.memory.allocator.lock().min_split_size(), Size(64));
+
+        let mut table = HashTable::<_>::from_existing(&db.memory, db.sequence_table.unwrap()).unwrap();
+        for i in 0 .. 7u64 {
+            assert!(table.remove(format!("sequence {}", i).as_bytes()));
+        }
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+    }
+
+    #[test]
+    fn try_init() {
+        let result = Database::try_init(Memory::new(MemStore::new(4)));
+        assert!(result.err().unwrap().contains("too small"));
+
+        let memory = Memory::new(MemStore::new(1000));
+        memory.alloc(Size(10));
+        assert!(Database::try_init(memory).is_err());
+
+        let mut db = Database::try_init(Memory::new(MemStore::new(1000))).unwrap();
+        let id = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"abc"));
+        assert_eq!(&*db.get_record(id), b"abc");
+    }
+
+    #[test]
+    fn stats() {
+        let mut db = create_database();
+
+        let records: Vec<_> = (0 .. 10u8).map(|i| {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(&[i; 6]))
+        }).collect();
+
+        // 6 payload bytes plus the reference count
+        let record_size = Size(10);
+
+        let before = db.stats();
+        assert_eq!(before.record_count, 10);
+        assert_eq!(before.record_bytes, record_size * 10u32);
+        assert_eq!(before.free_record_ids, 6);
+
+        for &record_id in &records[.. 3] {
+            db.delete_record(record_id);
+        }
+
+        let after = db.stats();
+        assert_eq!(after.record_count, 7);
+        assert_eq!(after.record_bytes, record_size * 7u32);
+        assert_eq!(after.free_record_ids, 9);
+        assert_eq!(after.allocator.free_bytes, before.allocator.free_bytes + record_size * 3u32);
+        assert_eq!(after.allocator.allocation_count, before.allocator.allocation_count - 3);
+        assert_eq!(after.allocator.allocated_bytes + after.allocator.free_bytes,
+                   after.allocator.total_size);
+    }
+
+    #[test]
+    fn grow_observer() {
+        use std::sync::{Arc, Mutex};
+
+        let mut db = create_database();
+        let events = Arc::new(Mutex::new(vec![]));
+
+        {
+            let events = events.clone();
+            db.set_grow_observer(Box::new(move |event| events.lock().unwrap().push(event)));
+        }
+
+        for _ in 0 .. 20 {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(b"x"));
+        }
+
+        let table_size = |capacity: u32| Size(12) + Size(16) * (capacity + 1);
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            GrowEvent {
+                old_capacity: Size(0),
+                new_capacity: Size(8),
+                old_byte_size: table_size(0),
+                new_byte_size: table_size(8),
+            },
+            GrowEvent {
+                old_capacity: Size(8),
+                new_capacity: Size(16),
+                old_byte_size: table_size(8),
+                new_byte_size: table_size(16),
+            },
+            GrowEvent {
+                old_capacity: Size(16),
+                new_capacity: Size(32),
+                old_byte_size: table_size(16),
+                new_byte_size: table_size(32),
+            },
+        ]);
+    }
+
+    #[test]
+    fn init_with_capacity() {
+        use std::sync::{Arc, Mutex};
+
+        let mut db = Database::init_with_capacity(Memory::new(MemStore::new(10000)), 20);
+        assert_eq!(db.stats().free_record_ids, 20);
+
+        let grow_count = Arc::new(Mutex::new(0));
+
+        {
+            let grow_count = grow_count.clone();
+            db.set_grow_observer(Box::new(move |_| *grow_count.lock().unwrap() += 1));
+        }
+
+        for _ in 0 .. 20 {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(b"x"));
+        }
+        assert_eq!(*grow_count.lock().unwrap(), 0);
+
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(b"x"));
+        assert_eq!(*grow_count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn persist_and_open() {
+        let mut db = create_database();
+
+        let leaf1 = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf1"));
+        let leaf2 = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf2"));
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_record_id(leaf2);
+            encoder.write_record_id(leaf1);
+        });
+
+        let storage = db.persist();
+        let mut db = Database::open(storage).unwrap();
+
+        assert_eq!(&*db.get_record(root), b"root");
+        assert_eq!(&*db.get_record(leaf1), b"leaf1");
+        assert_eq!(&*db.get_record(leaf2), b"leaf2");
+        assert_eq!(db.referenced_by(root), vec![leaf1, leaf2]);
+        assert_eq!(db.referenced_by(leaf1), vec![]);
+        assert_eq!(db.record(leaf1).ref_count, 1);
+        assert_eq!(db.record(leaf2).ref_count, 1);
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+
+        // The reopened database can be modified and persisted again
+        let leaf3 = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf3"));
+        db.delete_record(leaf1);
+
+        let db = Database::open(db.persist()).unwrap();
+        assert_eq!(&*db.get_record(root), b"root");
+        assert_eq!(&*db.get_record(leaf3), b"leaf3");
+    }
+
+    #[test]
+    fn persist_preserves_free_record_ids() {
+        let mut db = create_database();
+
+        let ids: Vec<_> = (0 .. 4u8).map(|i| {
+            db.write_record(|encoder, _| encoder.buffer().write_bytes(&[i]))
+        }).collect();
+
+        db.delete_record(ids[1]);
+        db.delete_record(ids[2]);
+
+        let expected_next_id = db.record_table.with_mut(&db.memory, |record_table| {
+            record_table.first_free()
+        });
+        assert!(expected_next_id == ids[1] || expected_next_id == ids[2]);
+
+        let mut db = Database::open(db.persist()).unwrap();
+        assert_eq!(db.stats().record_count, 2);
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        assert_eq!(a, expected_next_id);
+
+        let mut reused = vec![a, b];
+        reused.sort();
+        assert_eq!(reused, vec![ids[1], ids[2]]);
+        assert_eq!(&*db.get_record(ids[0]), &[0]);
+        assert_eq!(&*db.get_record(ids[3]), &[3]);
+    }
+
+    #[test]
+    fn sequence() {
+        let mut db = create_database();
+
+        assert_eq!(db.sequence(b"a"), 0);
+        assert_eq!(db.sequence(b"a"), 1);
+        assert_eq!(db.sequence(b"b"), 0);
+        assert_eq!(db.sequence(b"a"), 2);
+
+        let mut db = Database::open(db.persist()).unwrap();
+        assert_eq!(db.sequence(b"a"), 3);
+        assert_eq!(db.sequence(b"b"), 1);
+        assert_eq!(db.sequence(b"c"), 0);
+        assert_eq!(db.memory.allocator.lock().verify(), Ok(()));
+
+        // Databases without sequences can still be persisted and opened
+        let db = Database::open(create_database().persist()).unwrap();
+        assert!(db.sequence_table.is_none());
+    }
+
+    #[test]
+    fn open_invalid() {
+        assert!(Database::open(MemStore::new(4)).is_err());
+        assert!(Database::open(MemStore::new(1000)).is_err());
+    }
+
+    struct FlushCountingStore {
+        store: MemStore,
+        flush_count: usize,
+        synced_ranges: Vec<(Address, Size)>,
+    }
+
+    impl Storage for FlushCountingStore {
+        const IS_READONLY: bool = false;
+        type Threading = MultiThreaded;
+
+        fn size(&self) -> Size {
+            self.store.size()
+        }
+
+        unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+            self.store.get_bytes(addr, len)
+        }
+
+        unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+            self.store.get_bytes_mut(addr, len)
+        }
+
+        unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+            self.store.copy_nonoverlapping_exclusive(src, dst, len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flush_count += 1;
+            Ok(())
+        }
+
+        fn sync_range(&mut self, addr: Address, len: Size) -> io::Result<()> {
+            self.synced_ranges.push((addr, len));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn persist_flushes_storage() {
+        let storage = FlushCountingStore {
+            store: MemStore::new(10000),
+            flush_count: 0,
+            synced_ranges: vec![],
+        };
+
+        let mut db = Database::init(Memory::new(storage));
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(b"data"));
+
+        let storage = db.persist();
+        assert_eq!(storage.flush_count, 1);
+    }
+
+    #[test]
+    fn write_record_syncs_range() {
+        let storage = FlushCountingStore {
+            store: MemStore::new(10000),
+            flush_count: 0,
+            synced_ranges: vec![],
+        };
+
+        let mut db = Database::init(Memory::new(storage));
+        let id = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"data"));
+
+        let record = db.record(id);
+        let record_slot = db.record_table.with_mut(&db.memory, |record_table| {
+            record_table.record_slot(id)
+        });
+
+        assert_eq!(db.memory.storage.synced_ranges, vec![
+            (record.addr, Size(8)),
+            (record_slot.addr, Size(16)),
+        ]);
+        assert_eq!(db.memory.storage.flush_count, 0);
+    }
+
+    #[test]
+    fn unreachable_records() {
+        let mut db = create_database();
+
+        let leaf = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf"));
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_record_id(leaf);
+        });
+
+        // A cycle that nothing else refers to
+        let mut detached = vec![];
+        db.write_record(|encoder, current_record_id| {
+            let a = current_record_id.get();
+            let b = encoder.write_record(|encoder, _| encoder.write_record_id(a));
+            encoder.write_record_id(b);
+            detached.push(a);
+            detached.push(b);
+        });
+
+        let expected_reachable: HashSet<_> = vec![root, leaf].into_iter().collect();
+        let expected_unreachable: HashSet<_> = detached.iter().cloned().collect();
+
+        assert_eq!(db.reachable_records(&[root]), expected_reachable);
+        assert_eq!(db.unreachable_records(&[root]), expected_unreachable);
+        assert_eq!(db.reachable_records(&[leaf]), vec![leaf].into_iter().collect());
+        assert_eq!(db.unreachable_records(&[root, detached[1]]), HashSet::new());
+        assert_eq!(db.reachable_records(&[]), HashSet::new());
+    }
+
+    #[test]
+    fn write_inline() {
+        let mut db = create_database();
+
+        let leaf = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf"));
+        let record_count = db.stats().record_count;
+
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_inline(|encoder| {
+                encoder.buffer().write_bytes(b"child");
+                encoder.write_inline(|encoder| encoder.buffer().write_bytes(b"grandchild"));
+                encoder.write_record_id(leaf);
+            });
+            encoder.write_inline(|_| {});
+            encoder.buffer().write_bytes(b"!");
+        });
+
+        // The inline data does not get records of its own
+        assert_eq!(db.stats().record_count, record_count + 1);
+        assert_eq!(db.referenced_by(root), vec![leaf]);
+
+        let record = db.get_record(root);
+        let mut decoder = Decoder::new(&record);
+        assert_eq!(decoder.read_bytes(4), b"root");
+
+        let grandchild = decoder.read_inline(|decoder| {
+            assert_eq!(decoder.read_bytes(5), b"child");
+            decoder.read_inline(|decoder| decoder.remaining().to_vec())
+        });
+        assert_eq!(grandchild, b"grandchild");
+
+        // Unread inline data is skipped
+        decoder.read_inline(|decoder| assert!(decoder.is_empty()));
+        assert_eq!(decoder.read_bytes(1), b"!");
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn export_to() {
+        let mut src = create_database();
+
+        let deleted = src.write_record(|encoder, _| encoder.buffer().write_bytes(b"deleted"));
+        let leaf = src.write_record(|encoder, _| encoder.buffer().write_bytes(b"leaf"));
+        src.delete_record(deleted);
+
+        let mut cycle = vec![];
+        let root = src.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_record_id(leaf);
+
+            let a = encoder.write_record(|encoder, current_record_id| {
+                let a = current_record_id.get();
+                let b = encoder.write_record(|encoder, _| {
+                    encoder.buffer().write_bytes(b"b");
+                    encoder.write_record_id(a);
+                    encoder.write_record_id(leaf);
+                });
+                encoder.buffer().write_bytes(b"a");
+                encoder.write_record_id(b);
+                cycle.push(b);
+            });
+            encoder.write_record_id(a);
+            cycle.insert(0, a);
+        });
+
+        let mut dst = Database::init(Memory::new(MemStore::new(10000)));
+        let existing = dst.write_record(|encoder, _| encoder.buffer().write_bytes(b"existing"));
+
+        let remap = src.export_to(&mut dst);
+
+        let (a, b) = (cycle[0], cycle[1]);
+
+        let mut exported: Vec<_> = remap.keys().cloned().collect();
+        exported.sort();
+        let mut expected_exported = vec![leaf, root, a, b];
+        expected_exported.sort();
+        assert_eq!(exported, expected_exported);
+
+        assert!(!remap.values().any(|&id| id == existing));
+        assert_eq!(&*dst.get_record(existing), b"existing");
+
+        for &old_id in &[leaf, root, a, b] {
+            let new_id = remap[&old_id];
+            assert_eq!(&*dst.get_record(new_id), &*src.get_record(old_id));
+            assert_eq!(dst.record(new_id).ref_count, src.record(old_id).ref_count);
+
+            let mut expected: Vec<_> = src.referenced_by(old_id).iter().map(|id| remap[id]).collect();
+            expected.sort();
+            assert_eq!(dst.referenced_by(new_id), expected);
+        }
+
+        assert_eq!(dst.record(remap[&leaf]).ref_count, 2);
+        assert_eq!(dst.stats().record_count, 5);
+    }
+
+    #[test]
+    fn decoder_at() {
+        let memory = Memory::new(MemStore::new(1000));
+        let allocation = memory.alloc(Size(4 + 8 + 3));
+
+        {
+            let mut writer = StorageWriter::new(&memory, allocation.addr);
+            writer.write_u32(17);
+            writer.write_u64(0x0123_4567_89ab_cdef);
+            memory.get_bytes_mut(writer.addr(), Size(3)).copy_from_slice(b"abc");
+        }
+
+        let mut decoder = Decoder::at(&memory, allocation);
+        assert_eq!(decoder.read_u32(), 17);
+        assert_eq!(decoder.read_u64(), 0x0123_4567_89ab_cdef);
+        assert_eq!(decoder.read_bytes(3), b"abc");
+        assert!(decoder.is_empty());
+
+        #[cfg(debug_assertions)]
+        assert!(memory.allocator.lock().has_live_mem_refs());
+
+        drop(decoder);
+
+        #[cfg(debug_assertions)]
+        assert!(!memory.allocator.lock().has_live_mem_refs());
+    }
+
+    #[test]
+    fn double_delete() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+
+        db.delete_record(a);
+        let stats = db.stats();
+
+        assert!(db.try_delete_record(a).is_err());
+        assert_eq!(db.stats(), stats);
+        db.memory.allocator.lock().verify().unwrap();
+
+        // The id is on the free list only once
+        let c = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"c"));
+        let d = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"d"));
+        assert!(c != d);
+        assert_eq!(&*db.get_record(b), b"b");
+        assert_eq!(&*db.get_record(c), b"c");
+        assert_eq!(&*db.get_record(d), b"d");
+    }
+
+    #[test]
+    #[should_panic(expected = "does not refer to a live record")]
+    fn double_delete_panics() {
+        let mut db = create_database();
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        db.delete_record(a);
+        db.delete_record(a);
+    }
+
+    #[test]
+    fn contains_record() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        assert!(db.contains_record(a));
+        assert!(db.contains_record(b));
+
+        db.delete_record(a);
+        assert!(!db.contains_record(a));
+        assert!(db.contains_record(b));
+
+        assert!(!db.contains_record(RecordId::from_usize(0)));
+        assert!(!db.contains_record(RecordId::from_usize(1_000_000)));
+    }
+
+    #[test]
+    fn persist_drops_fields() {
+        use std::sync::Arc;
+
+        let observed = Arc::new(());
+        let mut db = create_database();
+        let observer_ref = observed.clone();
+        db.set_grow_observer(Box::new(move |_| { let _ = &observer_ref; }));
+        db.track_insertion_order();
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+
+        let storage = db.persist();
+        assert_eq!(Arc::strong_count(&observed), 1);
+        assert_eq!(Database::open(storage).unwrap().stats().record_count, 1);
+    }
+
+    #[test]
+    fn record_meta() {
+        let mut db = create_database();
+
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let b = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"b"));
+        let root = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"root");
+            encoder.write_record_id(b);
+            encoder.write_record_id(a);
+        });
+
+        let typed = db.write_typed(&Point { x: 1, y: 2 });
+        let streamed = {
+            let mut writer = db.write_record_streaming(Size(3));
+            writer.write(b"abc");
+            writer.finish().unwrap()
+        };
+
+        let mut db = Database::open(db.persist()).unwrap();
+
+        // The reference list address is stored in the record table.
+        let record = db.record(root);
+        assert_eq!(record.ref_list_addr, record.addr + Size(4));
+        assert_eq!(db.record(typed).ref_list_addr, db.record(typed).addr + Size(12));
+        assert_eq!(db.record(streamed).ref_list_addr, db.record(streamed).addr + Size(3));
+
+        let meta = db.record_meta(record);
+        assert_eq!(meta.payload_size, Size(4));
+        assert_eq!(meta.reference_count, Size(2));
+        assert_eq!(meta.ref_list_addr, record.ref_list_addr);
+        assert_eq!(meta.ref_list_addr + REFERENCE_ID_SIZE * 2u32 + REFERENCE_COUNT_SIZE, record.addr + record.size);
+
+        let mut reader = StorageReader::new(&db.memory, record.ref_list_addr);
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(RecordId::read(&mut reader), expected[0]);
+        assert_eq!(RecordId::read(&mut reader), expected[1]);
+        assert_eq!(u32::read(&mut reader), 2);
+
+        let leaf_meta = db.record_meta(db.record(a));
+        assert_eq!(leaf_meta.reference_count, Size(0));
+        assert_eq!(leaf_meta.ref_list_addr, db.record(a).addr + Size(1));
+
+        // A reference list address that doesn't match the reference count is
+        // detected when opening the database.
+        db.record_table.with_mut(&db.memory, |record_table| {
+            let mut record = record_table.get_record(root);
+            record.ref_list_addr = record.ref_list_addr + REFERENCE_ID_SIZE;
+            record_table.set_record(root, record);
+        });
+
+        match Database::open_checked(db.persist()) {
+            Err(message) => assert!(message.contains("invalid reference count")),
+            Ok(_) => panic!("Corruption was not detected"),
+        }
+    }
+
+    #[test]
+    fn open_checked_garbage() {
+        use rand::{Rng, SeedableRng};
+        use rand::prng::XorShiftRng;
+
+        let mut rng = XorShiftRng::from_seed([1; 16]);
+
+        for _ in 0 .. 100 {
+            let size = rng.gen_range(0, 4096);
+            let storage = MemStore::new(size);
+            for i in 0 .. size {
+                unsafe { storage.get_bytes_mut(Address::from_offset(i), Size(1))[0] = rng.gen() };
+            }
+
+            assert!(Database::open_checked(storage).is_err());
+        }
+    }
+
+    #[test]
+    fn open_checked_corrupted() {
+        use rand::{Rng, SeedableRng};
+        use rand::prng::XorShiftRng;
+
+        let mut rng = XorShiftRng::from_seed([2; 16]);
+
+        for _ in 0 .. 200 {
+            let mut db = Database::init(Memory::new(MemStore::new(4096)));
+            let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+            db.write_record(|encoder, _| {
+                encoder.buffer().write_bytes(b"b");
+                encoder.write_record_id(a);
+            });
+            db.sequence(b"seq");
+
+            let storage = db.persist();
+            let footer_end = header::read_header(&storage).unwrap().footer_addr().offset() + 64;
+
+            for _ in 0 .. rng.gen_range(1, 8) {
+                let addr = Address::from_offset(rng.gen_range(0, footer_end));
+                unsafe { storage.get_bytes_mut(addr, Size(1))[0] = rng.gen() };
+            }
+
+            // The corruption may hit a payload, so the database might still
+            // open fine. What matters is that nothing panics.
+            if let Ok(db) = Database::open_checked(storage) {
+                db.stats();
+            }
+        }
+    }
+
+    // Corrupt footers are reported by `load()` itself, without relying on
+    // `catch_unwind()`.
+    #[test]
+    fn load_corrupt_footer() {
+        const STRUCTURES_OFFSET: Size = Size(4 + 8 + 8);
+
+        let persisted = || {
+            let mut db = create_database();
+            let blob = db.memory().alloc(Size(16));
+            db.register_structure(StructureKind::UserBlob, blob);
+            let storage = db.persist();
+            let footer_addr = header::read_header(&storage).unwrap().footer_addr();
+            (storage, footer_addr)
+        };
+
+        let load_error = |storage| match Database::load(storage) {
+            Err(message) => message,
+            Ok(_) => panic!("Corruption was not detected"),
+        };
+
+        // Structure count far beyond the end of the storage
+        let (storage, footer_addr) = persisted();
+        unsafe { LittleEndian::write_u32(&mut storage.get_bytes_mut(footer_addr + STRUCTURES_OFFSET, Size(4)), !0) };
+        assert!(load_error(storage).contains("exceeds the storage size"));
+
+        // Unknown structure kind
+        let (storage, footer_addr) = persisted();
+        unsafe { storage.get_bytes_mut(footer_addr + STRUCTURES_OFFSET + Size(4), Size(1))[0] = 7 };
+        assert!(load_error(storage).contains("Invalid structure kind"));
+
+        // Allocation count of the allocator state beyond the end
+        let (storage, footer_addr) = persisted();
+        let allocator_addr = footer_addr + STRUCTURES_OFFSET + Size(4 + 12 + 8 + 4);
+        unsafe { LittleEndian::write_u32(&mut storage.get_bytes_mut(allocator_addr, Size(4)), 1 << 30) };
+        assert!(load_error(storage).contains("exceeds the storage size"));
+
+        // Unknown allocation strategy. Persisting is deterministic, so the
+        // footer is at the same address as before.
+        let (storage, _) = persisted();
+        let read_u32 = |addr| unsafe { LittleEndian::read_u32(storage.get_bytes(addr, Size(4))) };
+        let allocation_count = read_u32(allocator_addr);
+        let free_blocks_addr = allocator_addr + Size(4 + 8 * allocation_count);
+        let free_block_count = read_u32(free_blocks_addr);
+        let strategy_addr = free_blocks_addr + Size((4 + 8 * free_block_count) * 2 + 4);
+        unsafe { storage.get_bytes_mut(strategy_addr, Size(1))[0] = 9 };
+        assert!(load_error(storage).contains("Invalid allocation strategy"));
+    }
+
+    #[test]
+    fn open_checked_valid() {
+        let mut db = create_database();
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        db.sequence(b"seq");
+
+        let db = Database::open_checked(db.persist()).unwrap();
+        assert_eq!(&db.get_record(a)[..], b"a");
+    }
+
+    #[test]
+    fn open_checked_checksum() {
+        let mut db = create_database();
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"abcd"));
+        let addr = db.record(a).addr;
+
+        let storage = db.persist();
+        unsafe { storage.get_bytes_mut(addr, Size(1))[0] = b'x' };
+
+        // The record is still structurally valid, only its contents changed.
+        match Database::open_checked(storage) {
+            Err(message) => assert!(message.contains("Checksum")),
+            Ok(_) => panic!("Corruption was not detected"),
+        }
+    }
+
+    #[test]
+    fn write_record_streaming() {
+        const PAYLOAD_SIZE: usize = 10 * 1024 * 1024;
+
+        let mut db = Database::init(Memory::new(MemStore::new(PAYLOAD_SIZE + 1024 * 1024)));
+        let small = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"small"));
+
+        let byte_at = |i: usize| (i % 251) as u8;
+
+        let record_id = {
+            let mut writer = db.write_record_streaming(Size::from_bytes(PAYLOAD_SIZE));
+            let mut chunk = vec![0u8; 64 * 1024];
+            let mut offset = 0;
+
+            while writer.remaining() != Size(0) {
+                let len = ::std::cmp::min(chunk.len(), writer.remaining().bytes());
+                for (i, b) in chunk[.. len].iter_mut().enumerate() {
+                    *b = byte_at(offset + i);
+                }
+                writer.write(&chunk[.. len]);
+                offset += len;
+            }
+
+            writer.finish().unwrap()
+        };
+
+        let check = |db: &Database<MemStore>| {
+            let payload = db.get_record(record_id);
+            assert_eq!(payload.len(), PAYLOAD_SIZE);
+            assert!(payload.iter().enumerate().all(|(i, &b)| b == byte_at(i)));
+            assert_eq!(&db.get_record(small)[..], b"small");
+        };
+
+        check(&db);
+        let db = Database::open(db.persist()).unwrap();
+        check(&db);
+    }
+
+    #[test]
+    fn write_record_streaming_size_mismatch() {
+        let mut db = create_database();
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+        let stats_before = db.stats();
+
+        {
+            let mut writer = db.write_record_streaming(Size(10));
+            writer.write(b"12345");
+            assert!(writer.finish().is_err());
+        }
+
+        {
+            // Dropped without finishing
+            let mut writer = db.write_record_streaming(Size(10));
+            writer.write(b"12345");
+        }
+
+        let stats_after = db.stats();
+        assert_eq!(stats_after.record_count, stats_before.record_count);
+        assert_eq!(stats_after.allocator.allocated_bytes, stats_before.allocator.allocated_bytes);
+
+        let record_id = {
+            let mut writer = db.write_record_streaming(Size(10));
+            writer.write(b"12345");
+            writer.write(b"67890");
+            writer.finish().unwrap()
+        };
+
+        assert_eq!(&db.get_record(record_id)[..], b"1234567890");
+        db.delete_record(record_id);
+        assert_eq!(db.stats().allocator.allocated_bytes, stats_before.allocator.allocated_bytes);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the size hint")]
+    fn write_record_streaming_overflow() {
+        let mut db = create_database();
+        let mut writer = db.write_record_streaming(Size(4));
+        writer.write(b"12345");
+    }
+
+    #[test]
+    fn record_headers() {
+        let mut db = create_database();
+        let plain = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"plain"));
+
+        db.write_record_headers();
+        let a = db.write_record(|encoder, _| encoder.buffer().write_bytes(b"payload a"));
+        let b = db.write_record(|encoder, _| {
+            encoder.buffer().write_bytes(b"b");
+            encoder.write_record_id(a);
+        });
+
+        {
+            let record = db.get_record(a);
+            let mut decoder = Decoder::new_checked(&record).unwrap();
+            assert_eq!(decoder.read_bytes(9), b"payload a");
+            assert!(decoder.is_empty());
+        }
+
+        {
+            // The decoder is bounded by the length in the header, the
+            // reference list is not part of it.
+            let record = db.record(b);
+            let mut decoder = Decoder::at_checked(&db.memory, Allocation::new(record.addr, record.size)).unwrap();
+            assert_eq!(decoder.remaining(), b"b");
+            assert_eq!(decoder.read_bytes(1), b"b");
+        }
+
+        // A decoder pointed at the wrong address doesn't find the magic.
+        let record = db.record(a);
+        let misdirected = Allocation::new(record.addr + Size(4), record.size - Size(4));
+        let result = Decoder::at_checked(&db.memory, misdirected).map(|_| ());
+        assert!(result.unwrap_err().contains("magic"));
+
+        // Records without a header are rejected too.
+        assert!(Decoder::new_checked(&db.get_record(plain)).is_err());
+        assert!(Decoder::new_checked(b"abc").is_err());
+
+        // So are headers claiming more data than there is.
+        let mut bytes = vec![0; RECORD_HEADER_SIZE + 2];
+        LittleEndian::write_u32(&mut bytes[.. 4], RECORD_MAGIC);
+        LittleEndian::write_u32(&mut bytes[4 .. 8], 3);
+        let result = Decoder::new_checked(&bytes).map(|_| ());
+        assert!(result.unwrap_err().contains("exceeds"));
+    }
+
+    #[test]
+    fn structures() {
+        let mut db = create_database();
+        db.write_record(|encoder, _| encoder.buffer().write_bytes(b"a"));
+
+        let (table1, table2) = {
+            let mut table1: HashTable<_> = HashTable::new(db.memory());
+            table1.insert(b"key1", b"value1");
+            let mut table2: HashTable<_> = HashTable::new(db.memory());
+            table2.insert(b"key2", b"value2");
+            (table1.allocation(), table2.allocation())
+        };
+
+        let blob = db.memory().alloc(Size(16));
+
+        db.register_structure(StructureKind::HashTable, table1);
+        db.register_structure(StructureKind::HashTable, table2);
+        db.register_structure(StructureKind::UserBlob, blob);
+        assert!(db.unregister_structure(blob));
+        assert!(!db.unregister_structure(blob));
+
+        let record_table = db.record_table.data();
+        let db = Database::open_checked(db.persist()).unwrap();
+
+        assert_eq!(db.structures(), vec![
+            StructureRef { kind: StructureKind::RecordTable, allocation: record_table },
+            StructureRef { kind: StructureKind::HashTable, allocation: table1 },
+            StructureRef { kind: StructureKind::HashTable, allocation: table2 },
+        ]);
+
+        let table: HashTable<_> = HashTable::from_existing(db.memory(), db.structures()[2].allocation).unwrap();
+        assert_eq!(&*table.find(b"key2").unwrap(), b"value2");
+    }
+
+    #[test]
+    fn structures_include_sequence_table() {
+        let mut db = create_database();
+        assert_eq!(db.structures().len(), 1);
+        assert_eq!(db.structures()[0].kind, StructureKind::RecordTable);
+
+        db.sequence(b"seq");
+        let db = Database::open(db.persist()).unwrap();
+        let structures = db.structures();
+        assert_eq!(structures.len(), 2);
+        assert_eq!(structures[1], StructureRef {
+            kind: StructureKind::HashTable,
+            allocation: db.sequence_table.unwrap(),
+        });
+    }
+}
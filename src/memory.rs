@@ -1,11 +1,16 @@
 
+use std::fmt;
+use std::io;
 use std::mem;
 use std::slice;
 use std::cmp::Ordering;
+use std::cell::{RefCell, RefMut};
+use std::marker::PhantomData;
 use allocator::{Allocator, Allocation, LiveMemRef};
 use std::ops::{Add, AddAssign, Sub, Mul, Div, Deref, DerefMut};
-use persist::{Serialize, Deserialize, StorageWriter, StorageReader};
-use parking_lot::Mutex;
+use persist::{Serialize, Deserialize, FixedSizeSerialize, StorageWriter, StorageReader, TypedArray};
+use byteorder::ByteOrder;
+use parking_lot::{Mutex, MutexGuard};
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Address(pub u32);
@@ -18,7 +23,7 @@ pub struct MemRef<'m> {
     slice: &'m [u8],
 
     #[cfg(debug_assertions)]
-    allocator: &'m Mutex<Allocator>,
+    allocator: &'m dyn AllocatorCell,
     #[cfg(debug_assertions)]
     mem_ref: LiveMemRef,
 }
@@ -39,6 +44,16 @@ impl<'m1, 'm2> PartialOrd<MemRef<'m1>> for MemRef<'m2> {
 }
 
 
+impl<'m> MemRef<'m> {
+    // The referenced bytes, for lifetime `'m` rather than for as long as
+    // `self` is borrowed. Callers have to keep `self` alive for as long as
+    // they use the bytes, otherwise debug builds cannot track the borrow.
+    #[inline]
+    pub(crate) fn slice(&self) -> &'m [u8] {
+        self.slice
+    }
+}
+
 impl<'m> Deref for MemRef<'m> {
     type Target = [u8];
 
@@ -64,11 +79,47 @@ pub struct MemRefMut<'a> {
     slice: &'a mut [u8],
 
     #[cfg(debug_assertions)]
-    allocator: &'a Mutex<Allocator>,
+    allocator: &'a dyn AllocatorCell,
     #[cfg(debug_assertions)]
     mem_ref: LiveMemRef,
 }
 
+impl<'m> MemRefMut<'m> {
+    /// Divides the borrowed bytes into `[0, mid)` and `[mid, len)`, like
+    /// `slice::split_at_mut()`.
+    pub fn split_at_mut(mut self, mid: usize) -> (MemRefMut<'m>, MemRefMut<'m>) {
+        let slice = mem::take(&mut self.slice);
+        let (first, second) = slice.split_at_mut(mid);
+
+        #[cfg(debug_assertions)]
+        {
+            let allocator = self.allocator;
+            let start = self.mem_ref.start();
+
+            // Replace the registration of the whole range with one for each
+            // half.
+            let (first_ref, second_ref) = {
+                let mut allocator = allocator.lock();
+                allocator.unregister_mem_ref(self.mem_ref);
+                let mid = Size::from_bytes(mid);
+                let len = Size::from_bytes(first.len() + second.len());
+                (allocator.register_mem_ref(start, mid, true),
+                 allocator.register_mem_ref(start + mid, len - mid, true))
+            };
+
+            mem::forget(self);
+
+            (MemRefMut { slice: first, allocator, mem_ref: first_ref },
+             MemRefMut { slice: second, allocator, mem_ref: second_ref })
+        }
+
+        #[cfg(not(debug_assertions))]
+        {
+            (MemRefMut { slice: first }, MemRefMut { slice: second })
+        }
+    }
+}
+
 impl<'m, 'g> Deref for MemRefMut<'m> {
     type Target = [u8];
 
@@ -96,17 +147,151 @@ impl<'m> Drop for MemRefMut<'m> {
     }
 }
 
+#[cfg(debug_assertions)]
+pub const FREED_MEMORY_POISON: u8 = 0xDE;
+
+#[cfg(debug_assertions)]
+pub const UNINITIALIZED_MEMORY_POISON: u8 = 0xCD;
+
 pub trait Storage {
     const IS_READONLY: bool;
+    type Threading: Threading;
     fn size(&self) -> Size;
     unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8];
     unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8];
     unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size);
+
+    /// Shrinks the storage to `size` bytes, discarding everything beyond.
+    /// Storages that cannot be shrunk return an error.
+    fn truncate(&mut self, _size: Size) -> Result<(), String> {
+        Err("Storage does not support truncation".to_string())
+    }
+
+    /// Makes sure all data written so far has reached its final destination.
+    /// Storages that are not backed by anything persistent don't need to do
+    /// anything here.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Like `flush()` but only the given range has to reach its final
+    /// destination. Storages that cannot do better flush everything.
+    fn sync_range(&mut self, _addr: Address, _len: Size) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Makes sure that the given range is backed by actual memory, so that
+    /// accessing it later does not cause page faults. Storages that are not
+    /// backed lazily, like `MemStore`, don't need to do anything here.
+    fn prefault(&self, _addr: Address, _len: Size) {}
+
+    /// Returns the whole backing buffer if the storage keeps it in one piece.
+    ///
+    /// # Safety
+    ///
+    /// The same rules as for `get_bytes()` apply.
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Selects how `Memory` protects its `Allocator`. `MultiThreaded` uses a
+/// mutex so that a `Memory` can be shared between threads, `SingleThreaded`
+/// uses a `RefCell` and avoids the locking overhead.
+pub trait Threading {
+    type AllocatorCell: AllocatorCell;
+}
+
+pub enum MultiThreaded {}
+impl Threading for MultiThreaded {
+    type AllocatorCell = Mutex<Allocator>;
+}
+
+pub enum SingleThreaded {}
+impl Threading for SingleThreaded {
+    type AllocatorCell = RefCell<Allocator>;
+}
+
+/// Used by views into another `Memory`, which share that memory's allocator
+/// instead of having their own.
+pub struct Borrowed<'a, T: Threading + 'a> {
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: Threading + 'a> Threading for Borrowed<'a, T> {
+    type AllocatorCell = &'a T::AllocatorCell;
+}
+
+pub trait AllocatorCell {
+    fn new(allocator: Allocator) -> Self where Self: Sized;
+    fn lock(&self) -> AllocatorGuard<'_>;
+}
+
+impl AllocatorCell for Mutex<Allocator> {
+    #[inline]
+    fn new(allocator: Allocator) -> Self {
+        Mutex::new(allocator)
+    }
+
+    #[inline]
+    fn lock(&self) -> AllocatorGuard<'_> {
+        AllocatorGuard::Mutex(Mutex::lock(self))
+    }
+}
+
+impl<'a, C: AllocatorCell + 'a> AllocatorCell for &'a C {
+    fn new(_allocator: Allocator) -> Self {
+        panic!("A borrowed allocator cell cannot be created from an allocator")
+    }
+
+    #[inline]
+    fn lock(&self) -> AllocatorGuard<'_> {
+        (**self).lock()
+    }
+}
+
+impl AllocatorCell for RefCell<Allocator> {
+    #[inline]
+    fn new(allocator: Allocator) -> Self {
+        RefCell::new(allocator)
+    }
+
+    #[inline]
+    fn lock(&self) -> AllocatorGuard<'_> {
+        AllocatorGuard::RefCell(self.borrow_mut())
+    }
+}
+
+pub enum AllocatorGuard<'a> {
+    Mutex(MutexGuard<'a, Allocator>),
+    RefCell(RefMut<'a, Allocator>),
+}
+
+impl<'a> Deref for AllocatorGuard<'a> {
+    type Target = Allocator;
+
+    #[inline]
+    fn deref(&self) -> &Allocator {
+        match *self {
+            AllocatorGuard::Mutex(ref guard) => guard,
+            AllocatorGuard::RefCell(ref guard) => guard,
+        }
+    }
+}
+
+impl<'a> DerefMut for AllocatorGuard<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Allocator {
+        match *self {
+            AllocatorGuard::Mutex(ref mut guard) => guard,
+            AllocatorGuard::RefCell(ref mut guard) => guard,
+        }
+    }
 }
 
 pub struct Memory<S: Storage> {
     pub(crate) storage: S,
-    pub(crate) allocator: Mutex<Allocator>,
+    pub(crate) allocator: <S::Threading as Threading>::AllocatorCell,
 }
 
 impl<S: Storage> Memory<S> {
@@ -114,7 +299,7 @@ impl<S: Storage> Memory<S> {
     #[inline]
     pub fn new(storage: S) -> Memory<S> {
         Memory {
-            allocator: Mutex::new(Allocator::new(storage.size())),
+            allocator: AllocatorCell::new(Allocator::new(storage.size())),
             storage,
         }
     }
@@ -124,7 +309,7 @@ impl<S: Storage> Memory<S> {
         assert!(storage.size() >= allocator.total_size());
 
         Memory {
-            allocator: Mutex::new(allocator),
+            allocator: AllocatorCell::new(allocator),
             storage,
         }
     }
@@ -134,6 +319,57 @@ impl<S: Storage> Memory<S> {
         self.storage.size()
     }
 
+    #[inline]
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    #[inline]
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Asks the storage to fault in the pages of the given range ahead of
+    /// time, e.g. before a burst of writes into memory that was reserved
+    /// earlier. Does not change any data.
+    pub fn prefault(&self, addr: Address, len: Size) {
+        assert!((addr + len).offset() <= self.size().bytes(),
+                "Cannot prefault {:?} bytes at {:?}, storage is only {:?} bytes",
+                len, addr, self.size());
+        self.storage.prefault(addr, len);
+    }
+
+    /// Whether some `MemRef` or `MemRefMut` into the given range is alive.
+    #[cfg(debug_assertions)]
+    pub(crate) fn is_borrowed(&self, addr: Address, len: Size) -> bool {
+        self.allocator.lock().is_borrowed(addr, len)
+    }
+
+    /// Returns the entire contents of the storage, including unallocated
+    /// space, or `None` if the storage cannot provide them in one piece.
+    pub fn as_slice(&self) -> Option<MemRef<'_>> {
+        let slice = unsafe { self.storage.as_slice()? };
+        debug_assert_eq!(slice.len(), self.size().bytes());
+
+        #[cfg(debug_assertions)]
+        {
+            let mem_ref = self.allocator.lock()
+                              .register_spanning_mem_ref(Address(0), self.size());
+            Some(MemRef {
+                slice,
+                allocator: &self.allocator,
+                mem_ref,
+            })
+        }
+
+        #[cfg(not(debug_assertions))]
+        {
+            Some(MemRef {
+                slice,
+            })
+        }
+    }
+
     #[inline]
     pub fn get_bytes(&self, addr: Address, len: Size) -> MemRef {
         #[cfg(debug_assertions)]
@@ -153,6 +389,27 @@ impl<S: Storage> Memory<S> {
         }
     }
 
+    /// Sets every byte in the given range to `byte`.
+    #[inline]
+    pub fn fill(&self, addr: Address, len: Size, byte: u8) {
+        for b in self.get_bytes_mut(addr, len).iter_mut() {
+            *b = byte;
+        }
+    }
+
+    /// Reads a single value, e.g. a `u32` or an `Address`, at `addr`.
+    #[inline]
+    pub fn read<T: Deserialize>(&self, addr: Address) -> T {
+        T::read_at(self, addr)
+    }
+
+    /// Copies the given range out of memory. Unlike `get_bytes()`, the
+    /// result does not borrow from `self`.
+    #[inline]
+    pub fn read_to_vec(&self, addr: Address, len: Size) -> Vec<u8> {
+        self.get_bytes(addr, len).to_vec()
+    }
+
     #[inline]
     pub fn get_bytes_mut(&self, addr: Address, len: Size) -> MemRefMut {
         assert!(!S::IS_READONLY);
@@ -178,19 +435,179 @@ impl<S: Storage> Memory<S> {
     pub fn alloc(&self, size: Size) -> Allocation {
         assert!(!S::IS_READONLY);
 
-        self.allocator.lock().alloc(size)
+        let allocation = self.allocator.lock().alloc(size);
+
+        // Freed memory is poisoned in debug builds, but callers rely on
+        // getting zeroed memory.
+        #[cfg(debug_assertions)]
+        unsafe {
+            fill_zero(self.storage.get_bytes_mut(allocation.addr, allocation.size));
+        }
+
+        allocation
+    }
+
+    /// Allocates a zeroed array of `len` elements of type `T`. An empty array
+    /// does not allocate anything, its allocation is empty and must not be
+    /// freed.
+    pub fn alloc_array<T: FixedSizeSerialize>(&self, len: usize) -> TypedArray<'_, S, T> {
+        let allocation = if len == 0 {
+            Allocation::new(Address(0), Size(0))
+        } else {
+            self.alloc(T::SERIALIZED_SIZE * len)
+        };
+
+        TypedArray::at(self, allocation, len)
+    }
+
+    /// Like `alloc()` but for callers that overwrite the whole allocation
+    /// anyway. In debug builds the memory is filled with
+    /// `UNINITIALIZED_MEMORY_POISON` instead of zeros, so that reading it
+    /// before it has been written is easier to spot.
+    #[inline]
+    pub fn alloc_uninit(&self, size: Size) -> Allocation {
+        assert!(!S::IS_READONLY);
+
+        let allocation = self.allocator.lock().alloc(size);
+
+        #[cfg(debug_assertions)]
+        unsafe {
+            for b in self.storage.get_bytes_mut(allocation.addr, allocation.size).iter_mut() {
+                *b = UNINITIALIZED_MEMORY_POISON;
+            }
+        }
+
+        allocation
+    }
+
+    /// Checks whether an allocation of the given size would currently succeed.
+    #[inline]
+    pub fn can_alloc(&self, size: Size) -> bool {
+        self.allocator.lock().can_alloc(size)
     }
 
     #[inline]
     pub fn free(&self, allocation: Allocation) {
         assert!(!S::IS_READONLY);
 
+        // In debug builds, fill freed memory with a recognizable pattern
+        // instead of zeros, so that reads through dangling addresses are
+        // easier to spot.
+        #[cfg(debug_assertions)]
+        unsafe {
+            for b in self.storage.get_bytes_mut(allocation.addr, allocation.size).iter_mut() {
+                *b = FREED_MEMORY_POISON;
+            }
+        }
+
+        #[cfg(not(debug_assertions))]
         unsafe {
             fill_zero(&mut self.storage.get_bytes_mut(allocation.addr, allocation.size));
         }
+
         self.allocator.lock().free(allocation);
     }
 
+    /// Computes a hash over the addresses and contents of all allocated
+    /// regions, e.g. for detecting silent corruption between sessions. Free
+    /// space is skipped, so freeing and re-allocating a region with the same
+    /// contents leaves the checksum unchanged.
+    pub fn checksum(&self) -> u64 {
+        self.checksum_filtered(|_| true)
+    }
+
+    pub(crate) fn checksum_filtered<F: Fn(Allocation) -> bool>(&self, include: F) -> u64 {
+        use metrohash::MetroHash;
+        use std::hash::Hasher;
+
+        // Don't hold the lock while reading, get_bytes() needs it too.
+        let allocations: Vec<Allocation> = self.allocator
+                                               .lock()
+                                               .allocated_regions()
+                                               .filter(|&allocation| include(allocation))
+                                               .collect();
+
+        let mut hasher = MetroHash::new();
+        for allocation in allocations {
+            hasher.write_u32(allocation.addr.as_u32());
+            hasher.write_u32(allocation.size.as_u32());
+            hasher.write(&self.get_bytes(allocation.addr, allocation.size));
+        }
+        hasher.finish()
+    }
+
+    /// Captures the contents of the whole storage together with the
+    /// allocator state, so that both can be reset later via `restore()`.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let bytes = unsafe {
+            self.storage.get_bytes(Address(0), self.storage.size()).to_vec()
+        };
+
+        MemorySnapshot {
+            bytes,
+            allocator: self.allocator.lock().clone(),
+        }
+    }
+
+    /// Returns a `Memory` that reads from the same storage but cannot be used
+    /// to modify it. The view shares the allocator of `self`, so borrows
+    /// through the view and through `self` are checked against each other in
+    /// debug builds.
+    pub fn readonly_view(&self) -> Memory<ReadOnlyView<'_, S>> {
+        Memory {
+            storage: ReadOnlyView { storage: &self.storage },
+            allocator: &self.allocator,
+        }
+    }
+
+    /// Like `readonly_view()` but borrows `self` mutably, so that nothing can
+    /// write to the storage while the view is alive. This makes the view safe
+    /// to share between threads.
+    pub fn shared_view(&mut self) -> Memory<SharedView<'_, S>> {
+        Memory {
+            storage: SharedView { view: ReadOnlyView { storage: &self.storage } },
+            allocator: &self.allocator,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &MemorySnapshot) {
+        assert!(!S::IS_READONLY);
+        assert_eq!(self.storage.size().bytes(), snapshot.bytes.len(),
+                   "Snapshot was taken from a storage of different size");
+
+        let mut allocator = self.allocator.lock();
+        assert!(!allocator.has_live_mem_refs(), "Cannot restore memory while it is borrowed");
+
+        unsafe {
+            self.storage
+                .get_bytes_mut(Address(0), self.storage.size())
+                .copy_from_slice(&snapshot.bytes);
+        }
+
+        *allocator = snapshot.allocator.clone();
+    }
+
+    /// Shrinks the underlying storage so that it ends at `addr`, e.g. after
+    /// all live data has been moved to the front. Fails if there are
+    /// allocations beyond `addr` or if the storage does not support it.
+    pub fn truncate_to(&mut self, addr: Address) -> Result<(), String> {
+        assert!(!S::IS_READONLY);
+
+        let max_addr = self.allocator.lock().max_addr();
+
+        if max_addr > addr {
+            return Err(format!("Cannot truncate memory to {:?}, there are allocations up to {:?}",
+                               addr,
+                               max_addr));
+        }
+
+        let new_size = Size(addr.as_u32());
+        self.storage.truncate(new_size)?;
+        self.allocator.lock().truncate(new_size);
+
+        Ok(())
+    }
+
     #[inline]
     pub fn copy_nonoverlapping(&self, src: Address, dst: Address, len: Size) {
         assert!(!S::IS_READONLY);
@@ -230,15 +647,116 @@ impl<S: Storage> Memory<S> {
 //     }
 // }
 
-pub struct MemStore {
+/// A copy of a `Memory`'s contents and allocator state, see
+/// `Memory::snapshot()`.
+pub struct MemorySnapshot {
+    bytes: Vec<u8>,
+    allocator: Allocator,
+}
+
+/// A read-only window into another `Storage`, see `Memory::readonly_view()`.
+pub struct ReadOnlyView<'s, S: Storage + 's> {
+    storage: &'s S,
+}
+
+impl<'s, S: Storage + 's> Storage for ReadOnlyView<'s, S> {
+    const IS_READONLY: bool = true;
+    type Threading = Borrowed<'s, S::Threading>;
+
+    #[inline]
+    fn size(&self) -> Size {
+        self.storage.size()
+    }
+
+    #[inline]
+    unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+        self.storage.get_bytes(addr, len)
+    }
+
+    unsafe fn get_bytes_mut(&self, _addr: Address, _len: Size) -> &mut [u8] {
+        panic!("Cannot modify a read-only view")
+    }
+
+    #[inline]
+    fn prefault(&self, addr: Address, len: Size) {
+        self.storage.prefault(addr, len)
+    }
+
+    #[inline]
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        self.storage.as_slice()
+    }
+
+    unsafe fn copy_nonoverlapping_exclusive(&mut self, _src: Address, _dst: Address, _len: Size) {
+        panic!("Cannot modify a read-only view")
+    }
+}
+
+/// A read-only window into another `Storage` that can be shared between
+/// threads, see `Memory::shared_view()`.
+pub struct SharedView<'s, S: Storage + 's> {
+    view: ReadOnlyView<'s, S>,
+}
+
+impl<'s, S: Storage + 's> Storage for SharedView<'s, S> {
+    const IS_READONLY: bool = true;
+    type Threading = Borrowed<'s, S::Threading>;
+
+    #[inline]
+    fn size(&self) -> Size {
+        self.view.size()
+    }
+
+    #[inline]
+    unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+        self.view.get_bytes(addr, len)
+    }
+
+    unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+        self.view.get_bytes_mut(addr, len)
+    }
+
+    #[inline]
+    fn prefault(&self, addr: Address, len: Size) {
+        self.view.prefault(addr, len)
+    }
+
+    #[inline]
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        self.view.as_slice()
+    }
+
+    unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+        self.view.copy_nonoverlapping_exclusive(src, dst, len)
+    }
+}
+
+// The `Memory` owning the store is mutably borrowed for as long as the view
+// exists and the view itself cannot write, so there are only readers.
+unsafe impl<'s> Sync for SharedView<'s, MemStore<MultiThreaded>> {}
+
+pub struct MemStore<T: Threading = MultiThreaded> {
     data: *mut u8,
     len: usize,
     // used for dropping
     capacity: usize,
+    threading: PhantomData<T>,
 }
 
 impl MemStore {
     pub fn new(size: usize) -> MemStore {
+        MemStore::with_size(size)
+    }
+}
+
+impl MemStore<SingleThreaded> {
+    pub fn new_single_threaded(size: usize) -> MemStore<SingleThreaded> {
+        MemStore::with_size(size)
+    }
+}
+
+impl<T: Threading> MemStore<T> {
+    fn with_size(size: usize) -> MemStore<T> {
         let mut vec = vec![0u8; size];
 
         let data = vec.as_mut_ptr();
@@ -251,27 +769,34 @@ impl MemStore {
             data,
             len,
             capacity,
+            threading: PhantomData,
         }
     }
 
     fn get_slice(&self, start: Address, len: Size) -> &[u8] {
-        assert!((start + len).as_usize() <= self.len);
+        assert!((start + len).offset() <= self.len);
 
         unsafe {
-            slice::from_raw_parts(self.data.offset(start.as_isize()), len.as_usize())
+            slice::from_raw_parts(self.data.offset(start.as_isize()), len.bytes())
         }
     }
 
     fn get_slice_mut(&self, start: Address, len: Size) -> &mut [u8] {
-        assert!((start + len).as_usize() <= self.len);
+        assert!((start + len).offset() <= self.len);
 
         unsafe {
-            slice::from_raw_parts_mut(self.data.offset(start.as_isize()), len.as_usize())
+            slice::from_raw_parts_mut(self.data.offset(start.as_isize()), len.bytes())
         }
     }
 }
 
-impl Drop for MemStore {
+// MemStore owns its buffer, so it can be moved to another thread. It is not
+// `Sync` because `Memory::get_bytes_mut()` writes through a shared reference
+// and only debug builds check for overlapping borrows. Use
+// `Memory::shared_view()` for reading from several threads.
+unsafe impl<T: Threading> Send for MemStore<T> {}
+
+impl<T: Threading> Drop for MemStore<T> {
     fn drop(&mut self) {
         let drop_me = unsafe {
             Vec::from_raw_parts(self.data, self.len, self.capacity)
@@ -280,12 +805,13 @@ impl Drop for MemStore {
     }
 }
 
-impl Storage for MemStore {
+impl<T: Threading> Storage for MemStore<T> {
     const IS_READONLY: bool = false;
+    type Threading = T;
 
     #[inline]
     fn size(&self) -> Size {
-        Size::from_usize(self.len)
+        Size::from_bytes(self.len)
     }
 
     #[inline]
@@ -306,6 +832,30 @@ impl Storage for MemStore {
 
         self.get_slice_mut(dst, len).copy_from_slice(self.get_slice(src, len));
     }
+
+    #[inline]
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        Some(self.get_slice(Address(0), self.size()))
+    }
+
+    fn truncate(&mut self, size: Size) -> Result<(), String> {
+        assert!(size.bytes() <= self.len);
+
+        let mut vec = unsafe {
+            Vec::from_raw_parts(self.data, self.len, self.capacity)
+        };
+
+        vec.truncate(size.bytes());
+        vec.shrink_to_fit();
+
+        self.data = vec.as_mut_ptr();
+        self.len = vec.len();
+        self.capacity = vec.capacity();
+
+        mem::forget(vec);
+
+        Ok(())
+    }
 }
 
 
@@ -414,22 +964,63 @@ impl Address {
     pub fn as_u32(self) -> u32 {
         self.0
     }
+
+    /// Creates the address `offset` bytes from the start of storage.
+    #[inline]
+    pub fn from_offset(offset: usize) -> Address {
+        Address::from_usize(offset)
+    }
+
+    /// The distance in bytes from the start of storage.
+    #[inline]
+    pub fn offset(self) -> usize {
+        self.as_usize()
+    }
+
+    /// Rounds up to the next multiple of `align`, which must be a power of two.
+    #[inline]
+    pub fn align_up(self, align: u32) -> Address {
+        Address(align_up(self.0, align))
+    }
+}
+
+#[inline]
+fn align_up(x: u32, align: u32) -> u32 {
+    assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+    x.checked_add(align - 1).expect("overflow while aligning") & !(align - 1)
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x}", self.0)
+    }
+}
+
+impl From<Address> for u64 {
+    #[inline]
+    fn from(addr: Address) -> u64 {
+        addr.0 as u64
+    }
 }
 
 impl Serialize for Address {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         writer.write_u32(self.0);
     }
 }
 
 impl Deserialize for Address {
     #[inline]
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Address {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Address {
         Address(reader.read_u32())
     }
 }
 
+impl FixedSizeSerialize for Address {
+    const SERIALIZED_SIZE: Size = Size(4);
+}
+
 
 impl Size {
     #[inline]
@@ -453,25 +1044,458 @@ impl Size {
     pub fn as_u32(self) -> u32 {
         self.0
     }
+
+    #[inline]
+    pub fn from_bytes(byte_count: usize) -> Size {
+        Size::from_usize(byte_count)
+    }
+
+    /// The number of bytes.
+    #[inline]
+    pub fn bytes(self) -> usize {
+        self.as_usize()
+    }
+
+    /// `self - rhs`, or `None` if `rhs` is larger than `self`.
+    #[inline]
+    pub fn checked_sub(self, rhs: Size) -> Option<Size> {
+        self.0.checked_sub(rhs.0).map(Size)
+    }
+
+    /// `self - rhs`, or zero if `rhs` is larger than `self`.
+    #[inline]
+    pub fn saturating_sub(self, rhs: Size) -> Size {
+        Size(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Rounds up to the next multiple of `align`, which must be a power of two.
+    #[inline]
+    pub fn align_up(self, align: u32) -> Size {
+        Size(align_up(self.0, align))
+    }
+
+    /// Formats the size with a binary unit, e.g. `512 B` or `1.5 KiB`.
+    pub fn human_readable(self) -> String {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if self.0 < 1024 {
+            return format!("{} B", self.0)
+        }
+
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.human_readable())
+    }
+}
+
+impl From<Size> for u64 {
+    #[inline]
+    fn from(size: Size) -> u64 {
+        size.0 as u64
+    }
 }
 
 impl Serialize for Size {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         writer.write_u32(self.0);
     }
 }
 
 impl Deserialize for Size {
     #[inline]
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Size {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Size {
         Size(reader.read_u32())
     }
 }
 
+impl FixedSizeSerialize for Size {
+    const SERIALIZED_SIZE: Size = Size(4);
+}
+
 #[inline]
 pub fn fill_zero(slice: &mut [u8]) {
     for b in slice {
         *b = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::AllocStrategy;
+
+    #[test]
+    fn truncate_to() {
+        let allocator = Allocator::with_strategy(Size(1000), AllocStrategy::FirstFit);
+        let mut memory = Memory::new_with_allocator(MemStore::new(1000), allocator);
+
+        let alloc1 = memory.alloc(Size(10));
+        let alloc2 = memory.alloc(Size(500));
+        let alloc3 = memory.alloc(Size(20));
+
+        memory.get_bytes_mut(alloc1.addr, alloc1.size).copy_from_slice(&[1; 10]);
+        memory.get_bytes_mut(alloc3.addr, alloc3.size).copy_from_slice(&[3; 20]);
+
+        // Move alloc3 down into the space of alloc2
+        memory.free(alloc2);
+        let moved = memory.alloc(Size(20));
+        assert_eq!(moved.addr, alloc2.addr);
+        memory.copy_nonoverlapping(alloc3.addr, moved.addr, alloc3.size);
+        memory.free(alloc3);
+
+        assert!(memory.truncate_to(moved.addr).is_err());
+        assert_eq!(memory.truncate_to(moved.end()), Ok(()));
+
+        assert_eq!(memory.size(), Size(30));
+        assert_eq!(memory.allocator.lock().total_size(), Size(30));
+        assert_eq!(memory.allocator.lock().verify(), Ok(()));
+        assert_eq!(&*memory.get_bytes(alloc1.addr, alloc1.size), &[1; 10][..]);
+        assert_eq!(&*memory.get_bytes(moved.addr, moved.size), &[3; 20][..]);
+    }
+
+    #[test]
+    fn can_alloc() {
+        let memory = Memory::new(MemStore::new(100));
+        memory.alloc(Size(10));
+        let alloc = memory.alloc(Size(40));
+        memory.alloc(Size(20));
+        memory.free(alloc);
+
+        assert!(memory.can_alloc(Size(40)));
+        assert!(!memory.can_alloc(Size(41)));
+        assert!(!memory.can_alloc(Size(0)));
+
+        memory.alloc(Size(40));
+        assert!(memory.can_alloc(Size(30)));
+        assert!(!memory.can_alloc(Size(31)));
+    }
+
+    #[test]
+    fn read_to_vec() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(4));
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[1, 2, 3, 4]);
+
+        let copy = memory.read_to_vec(alloc.addr, alloc.size);
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[5, 6, 7, 8]);
+
+        assert_eq!(copy, vec![1, 2, 3, 4]);
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[5, 6, 7, 8][..]);
+    }
+
+    #[test]
+    fn align_up() {
+        assert_eq!(Size(0).align_up(8), Size(0));
+        assert_eq!(Size(16).align_up(8), Size(16));
+        assert_eq!(Size(17).align_up(8), Size(24));
+        assert_eq!(Size(5).align_up(1), Size(5));
+        assert_eq!(Address(0).align_up(4), Address(0));
+        assert_eq!(Address(12).align_up(4), Address(12));
+        assert_eq!(Address(13).align_up(4), Address(16));
+    }
+
+    #[test]
+    #[should_panic(expected = "not a power of two")]
+    fn align_up_non_power_of_two() {
+        Size(10).align_up(6);
+    }
+
+    #[test]
+    fn read() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(16));
+
+        0x01020304u32.write_at(&memory, alloc.addr);
+        0x05060708090a0b0cu64.write_at(&memory, alloc.addr + Size(4));
+        Address(42).write_at(&memory, alloc.addr + Size(12));
+
+        assert_eq!(memory.read::<u32>(alloc.addr), 0x01020304);
+        assert_eq!(memory.read::<u64>(alloc.addr + Size(4)), 0x05060708090a0b0c);
+        assert_eq!(memory.read::<Address>(alloc.addr + Size(12)), Address(42));
+    }
+
+    #[test]
+    fn split_at_mut() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(8));
+
+        {
+            let (mut header, mut body) = memory.get_bytes_mut(alloc.addr, alloc.size).split_at_mut(3);
+            header.copy_from_slice(&[1, 2, 3]);
+            body.copy_from_slice(&[4, 5, 6, 7, 8]);
+
+            // Both halves are still tracked as borrowed
+            #[cfg(debug_assertions)]
+            assert!(memory.allocator.lock().has_live_mem_refs());
+        }
+
+        assert!(!memory.allocator.lock().has_live_mem_refs());
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[1, 2, 3, 4, 5, 6, 7, 8][..]);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Size::from_bytes(17).bytes(), 17);
+        assert_eq!(Address::from_offset(23).offset(), 23);
+        assert_eq!(u64::from(Size(!0)), 0xffff_ffff);
+        assert_eq!(u64::from(Address(!0)), 0xffff_ffff);
+        assert_eq!(Size::from_bytes(0xffff_ffff), Size(!0));
+        assert_eq!((Address::from_offset(8) + Size::from_bytes(4)).offset(), 12);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(target_pointer_width = "64")]
+    fn size_from_bytes_overflow() {
+        Size::from_bytes(0x1_0000_0000);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(target_pointer_width = "64")]
+    fn address_from_offset_overflow() {
+        Address::from_offset(0x1_0000_0000);
+    }
+
+    #[test]
+    fn fill() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(10));
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[1; 10]);
+
+        memory.fill(alloc.addr + Size(2), Size(5), 0xAA);
+
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size),
+                   &[1, 1, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 1, 1, 1][..]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn poison_on_free() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(10));
+        memory.alloc(Size(10));
+        memory.fill(alloc.addr, alloc.size, 1);
+
+        memory.free(alloc);
+        let bytes = unsafe { memory.storage.get_bytes(alloc.addr, alloc.size) };
+        assert!(bytes.iter().all(|&b| b == FREED_MEMORY_POISON));
+
+        // Reallocated memory is zeroed again
+        let alloc = memory.alloc(Size(10));
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[0; 10][..]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn poison_uninit() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc_uninit(Size(10));
+        assert!(memory.get_bytes(alloc.addr, alloc.size).iter().all(|&b| b == UNINITIALIZED_MEMORY_POISON));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut memory = Memory::new(MemStore::new(100));
+        let alloc1 = memory.alloc(Size(10));
+        memory.fill(alloc1.addr, alloc1.size, 1);
+
+        let snapshot = memory.snapshot();
+
+        memory.fill(alloc1.addr, alloc1.size, 2);
+        let alloc2 = memory.alloc(Size(20));
+        memory.fill(alloc2.addr, alloc2.size, 3);
+        memory.free(alloc1);
+
+        memory.restore(&snapshot);
+
+        assert_eq!(&*memory.get_bytes(alloc1.addr, alloc1.size), &[1; 10][..]);
+        {
+            let allocator = memory.allocator.lock();
+            assert_eq!(allocator.verify(), Ok(()));
+            assert_eq!(allocator.allocation_count(), 1);
+            assert_eq!(allocator.allocation_at(alloc1.addr), Some(alloc1));
+            assert_eq!(allocator.largest_free_block(), Size(90));
+        }
+
+        // The snapshot can be used more than once
+        memory.fill(alloc1.addr, alloc1.size, 4);
+        memory.restore(&snapshot);
+        assert_eq!(&*memory.get_bytes(alloc1.addr, alloc1.size), &[1; 10][..]);
+    }
+
+    #[test]
+    fn readonly_view() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(4));
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[1, 2, 3, 4]);
+
+        let view = memory.readonly_view();
+        assert_eq!(&*view.get_bytes(alloc.addr, alloc.size), &[1, 2, 3, 4][..]);
+        assert_eq!(view.size(), memory.size());
+    }
+
+    #[test]
+    #[should_panic]
+    fn readonly_view_rejects_mutation() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(4));
+        let view = memory.readonly_view();
+        view.get_bytes_mut(alloc.addr, alloc.size);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn readonly_view_conflicts_with_parent() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(4));
+        let view = memory.readonly_view();
+
+        let _bytes = view.get_bytes(alloc.addr, alloc.size);
+        memory.get_bytes_mut(alloc.addr, alloc.size);
+    }
+
+    #[test]
+    fn readonly_view_sees_new_allocations() {
+        let memory = Memory::new(MemStore::new(100));
+        let view = memory.readonly_view();
+
+        let alloc = memory.alloc(Size(4));
+        memory.fill(alloc.addr, alloc.size, 9);
+        assert_eq!(&*view.get_bytes(alloc.addr, alloc.size), &[9; 4][..]);
+    }
+
+    #[test]
+    fn as_slice() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(10));
+        memory.fill(alloc.addr, alloc.size, 7);
+
+        let slice = memory.as_slice().unwrap();
+        assert_eq!(slice.len(), memory.size().bytes());
+        assert_eq!(&slice[alloc.addr.offset() .. alloc.end().offset()], &[7; 10][..]);
+
+        assert_eq!(memory.readonly_view().as_slice().map(|s| s.len()), Some(100));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Size(0).to_string(), "0 B");
+        assert_eq!(Size(1023).to_string(), "1023 B");
+        assert_eq!(Size(1024).to_string(), "1.0 KiB");
+        assert_eq!(Size(1229).to_string(), "1.2 KiB");
+        assert_eq!(Size(3 * 1024 * 1024 / 2).human_readable(), "1.5 MiB");
+        assert_eq!(Size(u32::MAX).to_string(), "4.0 GiB");
+
+        assert_eq!(Address(0).to_string(), "0x00000000");
+        assert_eq!(Address(1234).to_string(), "0x000004d2");
+        assert_eq!(format!("{:?}", Address(1234)), "Address(1234)");
+    }
+
+    #[test]
+    fn size_sub() {
+        assert_eq!(Size(10).checked_sub(Size(4)), Some(Size(6)));
+        assert_eq!(Size(10).checked_sub(Size(10)), Some(Size(0)));
+        assert_eq!(Size(10).checked_sub(Size(11)), None);
+
+        assert_eq!(Size(10).saturating_sub(Size(4)), Size(6));
+        assert_eq!(Size(10).saturating_sub(Size(10)), Size(0));
+        assert_eq!(Size(10).saturating_sub(Size(11)), Size(0));
+    }
+
+    #[test]
+    fn prefault() {
+        use std::sync::Mutex;
+
+        struct PrefaultRecordingStore {
+            store: MemStore,
+            prefaulted: Mutex<Vec<(Address, Size)>>,
+        }
+
+        impl Storage for PrefaultRecordingStore {
+            const IS_READONLY: bool = false;
+            type Threading = <MemStore as Storage>::Threading;
+
+            fn size(&self) -> Size {
+                self.store.size()
+            }
+
+            unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+                self.store.get_bytes(addr, len)
+            }
+
+            unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+                self.store.get_bytes_mut(addr, len)
+            }
+
+            unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+                self.store.copy_nonoverlapping_exclusive(src, dst, len);
+            }
+
+            fn prefault(&self, addr: Address, len: Size) {
+                self.prefaulted.lock().unwrap().push((addr, len));
+            }
+        }
+
+        let memory = Memory::new(PrefaultRecordingStore {
+            store: MemStore::new(1000),
+            prefaulted: Mutex::new(vec![]),
+        });
+
+        let alloc = memory.alloc(Size(500));
+        memory.prefault(alloc.addr, alloc.size);
+        memory.readonly_view().prefault(Address(0), Size(1000));
+        assert_eq!(*memory.storage().prefaulted.lock().unwrap(),
+                   vec![(alloc.addr, alloc.size), (Address(0), Size(1000))]);
+
+        memory.fill(alloc.addr, alloc.size, 9);
+        assert!(memory.get_bytes(alloc.addr, alloc.size).iter().all(|&b| b == 9));
+
+        // A no-op for MemStore
+        let memory = Memory::new(MemStore::new(100));
+        memory.prefault(Address(0), Size(100));
+    }
+
+    #[test]
+    fn checksum() {
+        let allocator = Allocator::with_strategy(Size(1000), AllocStrategy::FirstFit);
+        let memory = Memory::new_with_allocator(MemStore::new(1000), allocator);
+
+        let alloc1 = memory.alloc(Size(10));
+        let alloc2 = memory.alloc(Size(20));
+        memory.get_bytes_mut(alloc1.addr, alloc1.size).copy_from_slice(&[1; 10]);
+        memory.get_bytes_mut(alloc2.addr, alloc2.size).copy_from_slice(&[2; 20]);
+
+        let checksum = memory.checksum();
+
+        memory.get_bytes_mut(alloc2.addr, Size(1))[0] = 3;
+        assert_ne!(memory.checksum(), checksum);
+        memory.get_bytes_mut(alloc2.addr, Size(1))[0] = 2;
+        assert_eq!(memory.checksum(), checksum);
+
+        // Free space does not contribute to the checksum.
+        memory.free(alloc2);
+        assert_ne!(memory.checksum(), checksum);
+        let realloc = memory.alloc(Size(20));
+        assert_eq!(realloc, alloc2);
+        memory.get_bytes_mut(realloc.addr, realloc.size).copy_from_slice(&[2; 20]);
+        assert_eq!(memory.checksum(), checksum);
+
+        unsafe { memory.storage().get_bytes_mut(Address(500), Size(1))[0] = 5 };
+        assert_eq!(memory.checksum(), checksum);
+    }
+}
@@ -1,37 +1,74 @@
 
+use std::marker::PhantomData;
 use byteorder::{ByteOrder, LittleEndian};
 use memory::*;
+use allocator::Allocation;
 
-pub struct StorageWriter<'s, S: Storage + 's> {
+pub struct StorageWriter<'s, S: Storage + 's, B: ByteOrder = LittleEndian> {
     storage: &'s Memory<S>,
     addr: Address,
+    end: Address,
+    byte_order: PhantomData<B>,
 }
 
 impl<'s, S: Storage + 's> StorageWriter<'s, S> {
 
     #[inline]
     pub fn new(storage: &'s Memory<S>, addr: Address) -> Self {
+        StorageWriter::with_byte_order(storage, addr)
+    }
+
+    /// Like `new()` but panics instead of writing anything at or beyond
+    /// `end`, e.g. for serializing values whose size cannot be trusted.
+    #[inline]
+    pub fn with_limit(storage: &'s Memory<S>, addr: Address, end: Address) -> Self {
+        let mut writer = StorageWriter::new(storage, addr);
+        writer.end = end;
+        writer
+    }
+}
+
+impl<'s, S: Storage + 's, B: ByteOrder> StorageWriter<'s, S, B> {
+
+    #[inline]
+    pub fn with_byte_order(storage: &'s Memory<S>, addr: Address) -> Self {
         StorageWriter {
             storage,
             addr,
+            end: Address(0) + storage.size(),
+            byte_order: PhantomData,
         }
     }
 
     #[inline]
     pub fn write_u32(&mut self, val: u32) {
-        LittleEndian::write_u32(&mut self.storage.get_bytes_mut(self.addr, Size(4)), val);
+        self.check_limit(Size(4));
+        B::write_u32(&mut self.storage.get_bytes_mut(self.addr, Size(4)), val);
         self.addr += Size(4);
     }
 
     #[inline]
     pub fn write_u64(&mut self, val: u64) {
-        LittleEndian::write_u64(&mut self.storage.get_bytes_mut(self.addr, Size(8)), val);
+        self.check_limit(Size(8));
+        B::write_u64(&mut self.storage.get_bytes_mut(self.addr, Size(8)), val);
         self.addr += Size(8);
     }
+
+    #[inline]
+    fn check_limit(&self, len: Size) {
+        assert!(self.addr + len <= self.end,
+                "Writing {} bytes at {} exceeds the limit of the writer at {}",
+                len.bytes(), self.addr, self.end);
+    }
+
+    #[inline]
+    pub fn addr(&self) -> Address {
+        self.addr
+    }
 }
 
 pub trait Serialize {
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>);
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>);
 
     #[inline]
     fn write_at<S: Storage>(&self, storage: &Memory<S>, addr: Address) {
@@ -41,8 +78,8 @@ pub trait Serialize {
 
 impl<T: Serialize> Serialize for Vec<T> {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
-        Size::from_usize(self.len()).write(writer);
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+        Size::from_bytes(self.len()).write(writer);
 
         for x in self.iter() {
             x.write(writer);
@@ -50,38 +87,105 @@ impl<T: Serialize> Serialize for Vec<T> {
     }
 }
 
-pub struct StorageReader<'s, S: Storage + 's> {
+impl<T: Deserialize> Deserialize for Vec<T> {
+    #[inline]
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Vec<T> {
+        let len = Size::read(reader).bytes();
+        // Don't trust `len` for the initial capacity: a corrupt length would
+        // otherwise lead to a huge allocation before any read could fail.
+        let mut result = Vec::with_capacity(::std::cmp::min(len, 1024));
+        for _ in 0 .. len {
+            result.push(T::read(reader));
+        }
+        result
+    }
+}
+
+pub struct StorageReader<'s, S: Storage + 's, B: ByteOrder = LittleEndian> {
     storage: &'s Memory<S>,
     addr: Address,
+    byte_order: PhantomData<B>,
 }
 
 impl<'s, S: Storage + 's> StorageReader<'s, S> {
 
     #[inline]
     pub fn new(storage: &'s Memory<S>, addr: Address) -> StorageReader<'s, S> {
+        StorageReader::with_byte_order(storage, addr)
+    }
+}
+
+impl<'s, S: Storage + 's, B: ByteOrder> StorageReader<'s, S, B> {
+
+    #[inline]
+    pub fn with_byte_order(storage: &'s Memory<S>, addr: Address) -> StorageReader<'s, S, B> {
         StorageReader {
             storage,
             addr,
+            byte_order: PhantomData,
         }
     }
 
     #[inline]
     pub fn read_u32(&mut self) -> u32 {
-        let val = LittleEndian::read_u32(&self.storage.get_bytes(self.addr, Size(4)));
+        let val = B::read_u32(&self.storage.get_bytes(self.addr, Size(4)));
         self.addr += Size(4);
         val
     }
 
     #[inline]
     pub fn read_u64(&mut self) -> u64 {
-        let val = LittleEndian::read_u64(&self.storage.get_bytes(self.addr, Size(8)));
+        let val = B::read_u64(&self.storage.get_bytes(self.addr, Size(8)));
         self.addr += Size(8);
         val
     }
+
+    #[inline]
+    pub fn addr(&self) -> Address {
+        self.addr
+    }
+
+    /// Fails if fewer than `len` bytes are left before the end of the
+    /// storage, e.g. for reading untrusted data without panicking.
+    #[inline]
+    pub fn check_remaining(&self, len: Size) -> Result<(), String> {
+        if len.bytes() > self.remaining_bytes() {
+            return Err(format!("Reading {} bytes at {} exceeds the storage size of {}",
+                               len.bytes(), self.addr, self.storage.size().bytes()))
+        }
+
+        Ok(())
+    }
+
+    /// Reads the length of a serialized `Vec<T>` and checks that the elements
+    /// fit into the rest of the storage, so that a corrupt length is an error
+    /// instead of leading to reads beyond the end.
+    pub fn read_vec_len<T: FixedSizeSerialize>(&mut self) -> Result<usize, String> {
+        self.check_remaining(Size(4))?;
+        let len = self.read_u32() as usize;
+
+        match len.checked_mul(T::SERIALIZED_SIZE.bytes()) {
+            Some(byte_count) if byte_count <= self.remaining_bytes() => Ok(len),
+            _ => Err(format!("Length {} at {} exceeds the storage size of {}",
+                             len, self.addr, self.storage.size().bytes())),
+        }
+    }
+
+    /// Like `Vec::read()` but fails instead of reading beyond the end of the
+    /// storage if the length is corrupt.
+    pub fn try_read_vec<T: FixedSizeSerialize>(&mut self) -> Result<Vec<T>, String> {
+        let len = self.read_vec_len::<T>()?;
+        Ok((0 .. len).map(|_| T::read(self)).collect())
+    }
+
+    #[inline]
+    fn remaining_bytes(&self) -> usize {
+        self.storage.size().bytes().saturating_sub(self.addr.offset())
+    }
 }
 
 pub trait Deserialize: Sized {
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Self;
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Self;
 
     #[inline]
     fn read_at<S: Storage>(storage: &Memory<S>, addr: Address) -> Self {
@@ -89,27 +193,235 @@ pub trait Deserialize: Sized {
     }
 }
 
+/// Implemented by types whose serialized form always takes the same number of
+/// bytes, e.g. so that they can be stored in a `TypedArray`.
+pub trait FixedSizeSerialize: Serialize + Deserialize {
+    const SERIALIZED_SIZE: Size;
+}
 
 impl Serialize for u32 {
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         writer.write_u32(*self);
     }
 }
 
 impl Deserialize for u32 {
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Self {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Self {
         reader.read_u32()
     }
 }
 
+impl FixedSizeSerialize for u32 {
+    const SERIALIZED_SIZE: Size = Size(4);
+}
+
 impl Serialize for u64 {
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         writer.write_u64(*self);
     }
 }
 
 impl Deserialize for u64 {
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Self {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Self {
         reader.read_u64()
     }
 }
+
+impl FixedSizeSerialize for u64 {
+    const SERIALIZED_SIZE: Size = Size(8);
+}
+
+/// A fixed-length array of serializable values stored in an `Allocation`,
+/// see `Memory::alloc_array()`. Elements occupy `T::SERIALIZED_SIZE` bytes
+/// each.
+pub struct TypedArray<'m, S: Storage + 'm, T> {
+    memory: &'m Memory<S>,
+    data: Allocation,
+    len: usize,
+    element_type: PhantomData<T>,
+}
+
+impl<'m, S: Storage + 'm, T: FixedSizeSerialize> TypedArray<'m, S, T> {
+
+    /// Interprets the start of `data` as an array of `len` `T`s. The
+    /// allocation can be larger than that, e.g. with size classes.
+    pub fn at(memory: &'m Memory<S>, data: Allocation, len: usize) -> TypedArray<'m, S, T> {
+        assert!(Self::element_size() * len <= data.size,
+                "{} elements do not fit into {:?}", len, data);
+
+        TypedArray {
+            memory,
+            data,
+            len,
+            element_type: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> T {
+        T::read_at(self.memory, self.element_addr(index))
+    }
+
+    #[inline]
+    pub fn set(&self, index: usize, value: T) {
+        // Don't let a wrong `SERIALIZED_SIZE` overwrite the next element.
+        let addr = self.element_addr(index);
+        value.write(&mut StorageWriter::with_limit(self.memory, addr, addr + Self::element_size()));
+    }
+
+    #[inline]
+    pub fn allocation(&self) -> Allocation {
+        self.data
+    }
+
+    #[inline]
+    fn element_size() -> Size {
+        T::SERIALIZED_SIZE
+    }
+
+    #[inline]
+    fn element_addr(&self, index: usize) -> Address {
+        assert!(index < self.len(), "index {} out of bounds, len is {}", index, self.len());
+        self.data.addr + Self::element_size() * index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::BigEndian;
+
+    #[test]
+    fn big_endian_round_trip() {
+        let memory = Memory::new(MemStore::new(100));
+        let alloc = memory.alloc(Size(12));
+
+        {
+            let mut writer = StorageWriter::<_, BigEndian>::with_byte_order(&memory, alloc.addr);
+            writer.write_u32(0x01020304);
+            writer.write_u64(0x05060708090a0b0c);
+            assert_eq!(writer.addr(), alloc.end());
+        }
+
+        assert_eq!(&memory.get_bytes(alloc.addr, Size(4))[..], &[1, 2, 3, 4]);
+
+        let mut reader = StorageReader::<_, BigEndian>::with_byte_order(&memory, alloc.addr);
+        assert_eq!(reader.read_u32(), 0x01020304);
+        assert_eq!(reader.read_u64(), 0x05060708090a0b0c);
+        assert_eq!(reader.addr(), alloc.end());
+    }
+
+    #[test]
+    fn typed_array() {
+        let memory = Memory::new(MemStore::new(100));
+        let array = memory.alloc_array::<u32>(5);
+        assert_eq!(array.len(), 5);
+
+        for i in 0 .. 5 {
+            assert_eq!(array.get(i), 0);
+            array.set(i, i as u32 * 10);
+        }
+
+        assert_eq!((0 .. 5).map(|i| array.get(i)).collect::<Vec<_>>(), vec![0, 10, 20, 30, 40]);
+
+        let array = TypedArray::<_, u32>::at(&memory, array.allocation(), 5);
+        assert_eq!(array.get(4), 40);
+    }
+
+    #[test]
+    fn typed_array_size_classes() {
+        let memory = Memory::new(MemStore::new(100));
+        memory.allocator.lock().set_size_classes(::SizeClasses::PowerOfTwo);
+        let array = memory.alloc_array::<u32>(5);
+
+        assert_eq!(array.allocation().size, Size(32));
+        assert_eq!(array.len(), 5);
+    }
+
+    // Takes up a single byte in memory but four bytes when serialized.
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Flag(bool);
+
+    impl Serialize for Flag {
+        fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+            writer.write_u32(self.0 as u32);
+        }
+    }
+
+    impl Deserialize for Flag {
+        fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Flag {
+            Flag(reader.read_u32() != 0)
+        }
+    }
+
+    impl FixedSizeSerialize for Flag {
+        const SERIALIZED_SIZE: Size = Size(4);
+    }
+
+    #[test]
+    fn typed_array_uses_serialized_size() {
+        let memory = Memory::new(MemStore::new(100));
+        let array = memory.alloc_array::<Flag>(3);
+        assert_eq!(array.allocation().size, Size(12));
+
+        array.set(0, Flag(true));
+        array.set(1, Flag(false));
+        array.set(2, Flag(true));
+        assert_eq!((0 .. 3).map(|i| array.get(i)).collect::<Vec<_>>(),
+                   vec![Flag(true), Flag(false), Flag(true)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the limit of the writer")]
+    fn typed_array_wrong_serialized_size() {
+        struct Wide(u64);
+
+        impl Serialize for Wide {
+            fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
+                writer.write_u64(self.0);
+            }
+        }
+
+        impl Deserialize for Wide {
+            fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Wide {
+                Wide(reader.read_u64())
+            }
+        }
+
+        impl FixedSizeSerialize for Wide {
+            const SERIALIZED_SIZE: Size = Size(4);
+        }
+
+        let memory = Memory::new(MemStore::new(100));
+        let array = memory.alloc_array::<Wide>(2);
+        array.set(0, Wide(1));
+    }
+
+    #[test]
+    fn typed_array_empty() {
+        let memory = Memory::new(MemStore::new(100));
+        let array = memory.alloc_array::<u32>(0);
+
+        assert!(array.is_empty());
+        assert_eq!(array.allocation().size, Size(0));
+        assert_eq!(memory.allocator.lock().allocation_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "index 5 out of bounds")]
+    fn typed_array_out_of_bounds() {
+        let memory = Memory::new(MemStore::new(100));
+        let array = memory.alloc_array::<u32>(5);
+        array.get(5);
+    }
+}
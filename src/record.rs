@@ -1,8 +1,10 @@
 
 use std::mem;
+use std::collections::HashMap;
 use memory::*;
 use allocator::*;
 use persist::*;
+use byteorder::ByteOrder;
 
 // TODO: non-zero
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
@@ -24,23 +26,41 @@ impl RecordId {
 
 impl Serialize for RecordId {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         writer.write_u32(self.0);
     }
 }
 
 impl Deserialize for RecordId {
     #[inline]
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> RecordId {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> RecordId {
         RecordId(reader.read_u32())
     }
 }
 
+impl FixedSizeSerialize for RecordId {
+    const SERIALIZED_SIZE: Size = Size(4);
+}
+
+/// Returned when a record table cannot grow any further because its size
+/// would not be representable anymore.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct RecordTableFull;
+
+impl ::std::fmt::Display for RecordTableFull {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "record table is full")
+    }
+}
+
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub(crate) struct Record {
     pub addr: Address,
     pub size: Size,
     pub ref_count: u32,
+    // Where the ids of the records referenced by this record start, between
+    // the payload and the reference count at the end of the record.
+    pub ref_list_addr: Address,
 }
 
 impl Record {
@@ -49,39 +69,44 @@ impl Record {
             addr: Address(0),
             size: Size(0),
             ref_count: 0,
+            ref_list_addr: Address(0),
         }
     }
 }
 
 const EMPTY_RECORD_ADDRESS: Address = Address(0);
-const PENDING_RECORD_ADDRESS: Address = Address(0);
+const PENDING_RECORD_ADDRESS: Address = Address(!0);
 
 impl Serialize for Record {
     #[inline]
-    fn write<'s, S: Storage + 's>(&self, writer: &mut StorageWriter<'s, S>) {
+    fn write<'s, S: Storage + 's, B: ByteOrder>(&self, writer: &mut StorageWriter<'s, S, B>) {
         let Record {
             addr,
             size,
             ref_count,
+            ref_list_addr,
         } = *self;
 
         addr.write(writer);
         size.write(writer);
         ref_count.write(writer);
+        ref_list_addr.write(writer);
     }
 }
 
 impl Deserialize for Record {
     #[inline]
-    fn read<'s, S: Storage + 's>(reader: &mut StorageReader<'s, S>) -> Record {
+    fn read<'s, S: Storage + 's, B: ByteOrder>(reader: &mut StorageReader<'s, S, B>) -> Record {
         let addr = Address::read(reader);
         let size = Size::read(reader);
         let ref_count = u32::read(reader);
+        let ref_list_addr = Address::read(reader);
 
         Record {
             addr,
             size,
             ref_count,
+            ref_list_addr,
         }
     }
 }
@@ -105,6 +130,10 @@ const RECORD_SIZE: Size = Size(mem::size_of::<Record>() as u32);
 
 const FREE_PTR_OFFSET_WITHIN_RECORD: Size = Size(4);
 
+// The largest number of records for which the size of the table still fits
+// into a `Size`. This is well below the number of available `RecordId`s.
+const MAX_ITEM_COUNT: u32 = (u32::MAX - ARRAY_OFFSET.0) / RECORD_SIZE.0 - 1;
+
 impl<'s, S: Storage + 's> RecordTable<'s, S> {
 
     #[inline]
@@ -134,6 +163,80 @@ impl<'s, S: Storage + 's> RecordTable<'s, S> {
         assert!(record.addr != PENDING_RECORD_ADDRESS);
         record
     }
+
+    /// Like `get_record()` but returns `None` for ids that are out of range
+    /// or don't refer to a live record.
+    pub fn try_get_record(&self, id: RecordId) -> Option<Record> {
+        if id.0 == 0 || id.0 >= self.array_len().as_u32() {
+            return None
+        }
+
+        let addr = self.data.addr + ARRAY_OFFSET + RECORD_SIZE * id.idx();
+        let record = Record::read_at(self.storage, addr);
+
+        if record.addr == EMPTY_RECORD_ADDRESS || record.addr == PENDING_RECORD_ADDRESS {
+            None
+        } else {
+            Some(record)
+        }
+    }
+
+    /// Checks that the table header is consistent with the table's allocation
+    /// and that the free list is well-formed.
+    pub fn verify(&self) -> Result<(), String> {
+        if self.data.size < ARRAY_OFFSET {
+            return Err(format!("Record table allocation too small: {:?}", self.data))
+        }
+
+        let array_len = self.array_len();
+        let max_array_len = (self.data.size - ARRAY_OFFSET).0 / RECORD_SIZE.0;
+
+        if array_len.0 > max_array_len {
+            return Err(format!("Record table array length {} exceeds allocation {:?}",
+                               array_len.0, self.data))
+        }
+
+        if self.item_count() > array_len {
+            return Err(format!("Record table item count {} exceeds array length {}",
+                               self.item_count().0, array_len.0))
+        }
+
+        let mut free_ptr = RecordId::read_at(self.storage, self.data.addr + FIRST_FREE_OFFSET);
+        let mut free_count = 0;
+
+        while free_ptr != RecordId(0) {
+            if free_ptr.0 >= array_len.0 {
+                return Err(format!("Free record id {:?} out of range", free_ptr))
+            }
+
+            free_count += 1;
+            if free_count > array_len.0 {
+                return Err("Record table free list contains a cycle".to_string())
+            }
+
+            let addr = self.data.addr + ARRAY_OFFSET + RECORD_SIZE * free_ptr.idx();
+            if Address::read_at(self.storage, addr) != EMPTY_RECORD_ADDRESS {
+                return Err(format!("Free record id {:?} refers to a live record", free_ptr))
+            }
+
+            free_ptr = RecordId::read_at(self.storage, addr + FREE_PTR_OFFSET_WITHIN_RECORD);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` for every record that is in use. Free and pending slots are
+    /// skipped.
+    pub fn iter_live<F: FnMut(RecordId, Record)>(&self, mut f: F) {
+        for idx in 1 .. self.array_len().as_u32() {
+            let addr = self.data.addr + ARRAY_OFFSET + RECORD_SIZE * idx;
+            let record = Record::read_at(self.storage, addr);
+
+            if record.addr != EMPTY_RECORD_ADDRESS && record.addr != PENDING_RECORD_ADDRESS {
+                f(RecordId(idx), record);
+            }
+        }
+    }
 }
 
 
@@ -149,7 +252,7 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
 
     pub fn alloc(storage: &'s Memory<S>, records: &[Record]) -> RecordTableMut<'s, S> {
 
-        let item_count = Size::from_usize(records.len());
+        let item_count = Size::from_bytes(records.len());
         let array_len = item_count + Size(1);
         let table_byte_size = ARRAY_OFFSET + array_len * RECORD_SIZE;
 
@@ -166,6 +269,7 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
 
         for (index, &record) in records.iter().enumerate() {
             let record_id = RecordId(index as u32 + 1);
+            PENDING_RECORD_ADDRESS.write_at(storage, table.record_addr(record_id));
             table.set_record(record_id, record);
         }
 
@@ -200,42 +304,46 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
         record.write_at(self.storage, addr);
     }
 
+    /// Sets the address, size, and reference list address of a record while
+    /// keeping its reference count. Works for pending records too.
     #[inline]
-    pub fn alloc_record(&mut self) -> RecordId {
+    pub fn set_record_data(&mut self, id: RecordId, addr: Address, size: Size, ref_list_addr: Address) {
+        let record_addr = self.record_addr(id);
+        let mut record = Record::read_at(self.storage, record_addr);
+        assert_ne!(record.addr, EMPTY_RECORD_ADDRESS);
+        record.addr = addr;
+        record.size = size;
+        record.ref_list_addr = ref_list_addr;
+        record.write_at(self.storage, record_addr);
+    }
+
+    /// Increments the reference count of a live or pending record.
+    #[inline]
+    pub fn inc_ref_count(&mut self, id: RecordId) {
+        let record_addr = self.record_addr(id);
+        let mut record = Record::read_at(self.storage, record_addr);
+        assert_ne!(record.addr, EMPTY_RECORD_ADDRESS);
+        record.ref_count += 1;
+        record.write_at(self.storage, record_addr);
+    }
+
+    #[inline]
+    pub fn alloc_record(&mut self) -> Result<RecordId, RecordTableFull> {
         // Expand size if necessary
         if self.first_free() == RecordId(0) {
             let item_count = self.item_count();
             let old_array_len = self.array_len();
             debug_assert_eq!(old_array_len, item_count + Size(1));
-            let new_max_item_count = if item_count == Size(0) {
-                Size(8)
-            } else {
-                item_count * 2u32
-            };
-            let new_alloc = self.storage.alloc(record_table_alloc_size_for(new_max_item_count.as_usize()));
-            self.storage.copy_nonoverlapping(self.data.addr, new_alloc.addr, self.data.size);
-            fill_zero(&mut self.storage.get_bytes_mut(new_alloc.addr + self.data.size, new_alloc.size - self.data.size));
-            let new_array_len = new_max_item_count + Size(1u32);
-            new_array_len.write_at(self.storage, new_alloc.addr + ARRAY_LEN_OFFSET);
-
-            let mut free_ptr = new_alloc.addr + FIRST_FREE_OFFSET;
-            println!("&first_free = {:?}, array_len_before={:?}", free_ptr, old_array_len);
-            for free_record in old_array_len.as_u32() .. new_array_len.as_u32() {
-                let record_id = RecordId(free_record);
-                record_id.write_at(self.storage, free_ptr);
-                free_ptr = new_alloc.addr + ARRAY_OFFSET + RECORD_SIZE * free_record + FREE_PTR_OFFSET_WITHIN_RECORD;
-                println!("record_id = {:?}, free_ptr = {:?}", record_id, free_ptr);
-            }
+            let new_max_item_count = grown_item_count(item_count)?;
 
-            self.storage.free(self.data);
-            self.data = new_alloc;
+            self.grow(new_max_item_count);
 
             #[cfg(debug_assertions)]
             {
                 assert_eq!(self.item_count(), item_count);
 
                 let all_free = self.all_free();
-                let expected: Vec<_> = (old_array_len.as_u32() .. new_array_len.as_u32())
+                let expected: Vec<_> = (old_array_len.as_u32() .. self.array_len().as_u32())
                     .map(|i| RecordId(i))
                     .collect();
                 assert_eq!(all_free, expected);
@@ -259,7 +367,49 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
 
         (self.item_count() + Size(1)).write_at(self.storage, self.data.addr + ITEM_COUNT_OFFSET);
 
-        new_id
+        Ok(new_id)
+    }
+
+    /// Makes sure that at least `additional` more records can be allocated
+    /// without the table having to grow.
+    pub fn reserve(&mut self, additional: usize) {
+        let item_count = self.item_count();
+        let free_count = self.array_len() - Size(1) - item_count;
+
+        if free_count.bytes() < additional {
+            assert!(item_count.bytes() + additional <= MAX_ITEM_COUNT as usize,
+                    "cannot reserve {} more records, the record table would be too large",
+                    additional);
+            self.grow(item_count + Size::from_bytes(additional));
+        }
+    }
+
+    /// Moves the table to a new allocation with room for `new_max_item_count`
+    /// records. The new slots are put in front of the free list.
+    fn grow(&mut self, new_max_item_count: Size) {
+        let old_array_len = self.array_len();
+        let old_first_free = self.first_free();
+        let new_array_len = new_max_item_count + Size(1u32);
+        assert!(new_array_len > old_array_len);
+
+        // Allocations can be larger than requested, so only the part in use
+        // is copied.
+        let old_byte_size = ARRAY_OFFSET + RECORD_SIZE * old_array_len.as_u32();
+        let new_alloc = self.storage.alloc(record_table_alloc_size_for(new_max_item_count.bytes()));
+        self.storage.copy_nonoverlapping(self.data.addr, new_alloc.addr, old_byte_size);
+        fill_zero(&mut self.storage.get_bytes_mut(new_alloc.addr + old_byte_size, new_alloc.size - old_byte_size));
+        new_array_len.write_at(self.storage, new_alloc.addr + ARRAY_LEN_OFFSET);
+
+        let mut free_ptr = new_alloc.addr + FIRST_FREE_OFFSET;
+        for free_record in old_array_len.as_u32() .. new_array_len.as_u32() {
+            let record_id = RecordId(free_record);
+            record_id.write_at(self.storage, free_ptr);
+            free_ptr = new_alloc.addr + ARRAY_OFFSET + RECORD_SIZE * free_record + FREE_PTR_OFFSET_WITHIN_RECORD;
+        }
+        old_first_free.write_at(self.storage, free_ptr);
+
+        self.storage.free(self.data);
+        self.data = new_alloc;
     }
 
     pub fn delete_record(&mut self, record_id: RecordId) -> Record {
@@ -270,7 +420,7 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
 
         let record_addr = self.record_addr(record_id);
         let deleted_record = Record::read_at(self.storage, record_addr);
-        assert!(deleted_record.addr != EMPTY_RECORD_ADDRESS);
+        assert!(deleted_record.addr != EMPTY_RECORD_ADDRESS, "{:?} has already been deleted", record_id);
         assert!(deleted_record.addr != PENDING_RECORD_ADDRESS);
 
         fill_zero(&mut self.storage.get_bytes_mut(record_addr, RECORD_SIZE));
@@ -291,6 +441,34 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
         deleted_record
     }
 
+    /// Moves all records to the front of a newly allocated table that is just
+    /// large enough to hold them, leaving the free list empty. This changes
+    /// record ids, so the returned map from old to new ids must be used to
+    /// update any references to records.
+    pub fn compact(&mut self) -> HashMap<RecordId, RecordId> {
+        let mut old_ids = vec![];
+        let mut records = vec![];
+
+        for idx in 1 .. self.array_len().as_u32() {
+            let record_id = RecordId(idx);
+            let record = Record::read_at(self.storage, self.record_addr(record_id));
+
+            if record.addr != EMPTY_RECORD_ADDRESS {
+                old_ids.push(record_id);
+                records.push(record);
+            }
+        }
+
+        let old_data = self.data;
+        self.data = RecordTableMut::alloc(self.storage, &records).data;
+        self.storage.free(old_data);
+
+        old_ids.into_iter()
+               .enumerate()
+               .map(|(index, old_id)| (old_id, RecordId::from_usize(index + 1)))
+               .collect()
+    }
+
     #[inline]
     pub fn readonly(&'s self) -> RecordTable<'s, S> {
         RecordTable {
@@ -299,6 +477,12 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
         }
     }
 
+    /// The bytes in the table that hold the entry for `id`.
+    #[inline]
+    pub fn record_slot(&self, id: RecordId) -> Allocation {
+        Allocation::new(self.record_addr(id), RECORD_SIZE)
+    }
+
     #[inline]
     fn record_addr(&self, id: RecordId) -> Address {
         assert!(id.0 > 0 && id.0 < self.array_len().as_u32(),
@@ -326,7 +510,19 @@ impl<'s, S: Storage + 's> RecordTableMut<'s, S> {
     }
 }
 
-fn record_table_alloc_size_for(record_count: usize) -> Size {
+/// Computes the number of records a full table with `item_count` records
+/// should have room for after growing.
+fn grown_item_count(item_count: Size) -> Result<Size, RecordTableFull> {
+    if item_count == Size(0) {
+        Ok(Size(8))
+    } else if item_count.as_u32() >= MAX_ITEM_COUNT {
+        Err(RecordTableFull)
+    } else {
+        Ok(Size(item_count.as_u32().saturating_mul(2).min(MAX_ITEM_COUNT)))
+    }
+}
+
+pub(crate) fn record_table_alloc_size_for(record_count: usize) -> Size {
     ARRAY_OFFSET + RECORD_SIZE * (record_count + 1)
 }
 
@@ -362,20 +558,28 @@ impl<S: Storage> RuntimeRecordTable<S> {
         f(&record_table)
     }
 
-    pub(crate) fn with_mut<R, F: FnOnce(&mut RecordTableMut<S>) -> R>(&self, memory: &Memory<S>, f: F) -> R {
+    pub(crate) fn with_mut<R, F: FnOnce(&mut RecordTableMut<S>) -> R>(&mut self, memory: &Memory<S>, f: F) -> R {
         assert!(!S::IS_READONLY);
-        let record_table = RecordTableMut::at(memory, self.data.addr, self.data.size);
+        let mut record_table = RecordTableMut::at(memory, self.data.addr, self.data.size);
         let result = f(&mut record_table);
         self.data = record_table.data;
         result
     }
 
     pub(crate) fn from(table: RecordTableMut<S>) -> RuntimeRecordTable<S> {
+        RuntimeRecordTable::at(table.data)
+    }
+
+    pub(crate) fn at(data: Allocation) -> RuntimeRecordTable<S> {
         RuntimeRecordTable {
-            data: table.data,
+            data,
             storage: ::std::marker::PhantomData,
         }
     }
+
+    pub(crate) fn data(&self) -> Allocation {
+        self.data
+    }
 }
 
 #[cfg(test)]
@@ -384,7 +588,7 @@ mod tests {
 
     fn create_storage(record_count: usize) -> Memory<MemStore> {
         let size = ARRAY_OFFSET + RECORD_SIZE * (record_count + 1) + Size(1);
-        let memory = Memory::new(MemStore::new(size.as_usize()));
+        let memory = Memory::new(MemStore::new(size.bytes()));
         memory.alloc(Size(1));
         memory
     }
@@ -397,16 +601,19 @@ mod tests {
                 addr: Address(1010),
                 size: Size(2323),
                 ref_count: 3432,
+                ref_list_addr: Address(2000),
             },
             Record {
                 addr: Address(76),
                 size: Size(34324),
                 ref_count: 23,
+                ref_list_addr: Address(34000),
             },
             Record {
                 addr: Address(743),
                 size: Size(23),
                 ref_count: 8,
+                ref_list_addr: Address(750),
             },
         ];
 
@@ -443,9 +650,10 @@ mod tests {
                 addr: Address(i * 7 + 1),
                 size: Size(i * 3),
                 ref_count: i * 11,
+                ref_list_addr: Address(i * 9 + 1),
             };
 
-            let id = record_table.alloc_record();
+            let id = record_table.alloc_record().unwrap();
             record_table.set_record(id, record);
 
             records.push((id, record));
@@ -470,9 +678,10 @@ mod tests {
                 addr: Address(i * 7 + 1),
                 size: Size(i * 3),
                 ref_count: i * 11,
+                ref_list_addr: Address(i * 9 + 1),
             };
 
-            let id = record_table.alloc_record();
+            let id = record_table.alloc_record().unwrap();
             record_table.set_record(id, record);
 
             records.push((id, record));
@@ -491,4 +700,173 @@ mod tests {
             assert_eq!(free_records, record_table.all_free());
         }
     }
+
+    #[test]
+    fn test_iter_live() {
+
+        let storage = create_storage(100);
+
+        let mut record_table = RecordTableMut::alloc(&storage, &[]);
+
+        let mut records = vec![];
+
+        for i in 0 .. 20 {
+            let record = Record {
+                addr: Address(i * 7 + 1),
+                size: Size(i * 3),
+                ref_count: i * 11,
+                ref_list_addr: Address(i * 9 + 1),
+            };
+
+            let id = record_table.alloc_record().unwrap();
+            record_table.set_record(id, record);
+
+            records.push((id, record));
+        }
+
+        // Leave one record pending
+        record_table.alloc_record().unwrap();
+
+        for &(id, _) in records.iter().step_by(3) {
+            record_table.delete_record(id);
+        }
+
+        let expected: Vec<_> = records.iter()
+                                      .enumerate()
+                                      .filter(|&(i, _)| i % 3 != 0)
+                                      .map(|(_, &x)| x)
+                                      .collect();
+
+        let mut actual = vec![];
+        record_table.readonly().iter_live(|id, record| actual.push((id, record)));
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_compact() {
+
+        let storage = create_storage(300);
+
+        let mut record_table = RecordTableMut::alloc(&storage, &[]);
+
+        let mut records = vec![];
+
+        for i in 0 .. 50 {
+            let record = Record {
+                addr: Address(i * 7 + 1),
+                size: Size(i * 3),
+                ref_count: i * 11,
+                ref_list_addr: Address(i * 9 + 1),
+            };
+
+            let id = record_table.alloc_record().unwrap();
+            record_table.set_record(id, record);
+
+            records.push((id, record));
+        }
+
+        for &(id, _) in records.iter().filter(|&&(id, _)| id.0 % 2 == 0) {
+            record_table.delete_record(id);
+        }
+        records.retain(|&(id, _)| id.0 % 2 != 0);
+
+        let remap = record_table.compact();
+
+        assert_eq!(remap.len(), 25);
+        assert_eq!(record_table.item_count(), Size(25));
+        assert_eq!(record_table.array_len(), Size(26));
+        assert_eq!(record_table.first_free(), RecordId(0));
+        assert_eq!(record_table.data.size, record_table_alloc_size_for(25));
+
+        for (old_id, record) in records {
+            assert_eq!(record_table.get_record(remap[&old_id]), record);
+        }
+
+        // The table is usable as usual afterwards
+        let id = record_table.alloc_record().unwrap();
+        assert_eq!(id, RecordId(26));
+    }
+
+    #[test]
+    fn test_reserve() {
+
+        let storage = create_storage(3000);
+
+        let mut record_table = RecordTableMut::alloc(&storage, &[]);
+
+        let id = record_table.alloc_record().unwrap();
+        record_table.set_record(id, Record { addr: Address(1), size: Size(2), ref_count: 3, ref_list_addr: Address(2) });
+
+        record_table.reserve(1000);
+        let data = record_table.data;
+        assert_eq!(record_table.array_len(), Size(1002));
+
+        // Reserving space that is already there doesn't do anything
+        record_table.reserve(1000);
+        assert_eq!(record_table.data, data);
+
+        for i in 0 .. 1000 {
+            let id = record_table.alloc_record().unwrap();
+            record_table.set_record(id, Record { addr: Address(i + 1), size: Size(i), ref_count: 0, ref_list_addr: Address(i + 1) });
+        }
+
+        assert_eq!(record_table.data, data);
+        assert_eq!(record_table.item_count(), Size(1001));
+        assert_eq!(record_table.first_free(), RecordId(0));
+        assert_eq!(record_table.get_record(id).ref_count, 3);
+
+        // Existing free slots are kept when growing
+        let mut storage_table = RecordTableMut::alloc(&storage, &[]);
+        let ids: Vec<_> = (0 .. 8).map(|_| storage_table.alloc_record().unwrap()).collect();
+        for &id in &ids {
+            storage_table.set_record(id, Record { addr: Address(1), size: Size(1), ref_count: 0, ref_list_addr: Address(1) });
+        }
+        storage_table.delete_record(ids[3]);
+        storage_table.reserve(5);
+        assert_eq!(storage_table.all_free(), vec![ids[3], RecordId(9), RecordId(10), RecordId(11), RecordId(12)]);
+    }
+
+    #[test]
+    fn test_grown_item_count() {
+        assert_eq!(grown_item_count(Size(0)), Ok(Size(8)));
+        assert_eq!(grown_item_count(Size(8)), Ok(Size(16)));
+        assert_eq!(grown_item_count(Size(MAX_ITEM_COUNT - 1)), Ok(Size(MAX_ITEM_COUNT)));
+        assert_eq!(grown_item_count(Size(MAX_ITEM_COUNT)), Err(RecordTableFull));
+        // Must not overflow
+        record_table_alloc_size_for(MAX_ITEM_COUNT as usize);
+    }
+
+    #[test]
+    fn test_runtime_record_table_with_mut() {
+        let storage = create_storage(500);
+
+        let mut runtime_table = RuntimeRecordTable::from(RecordTableMut::alloc(&storage, &[]));
+        let initial_data = runtime_table.data();
+
+        let mut ids = vec![];
+
+        for i in 0 .. 100 {
+            let id = runtime_table.with_mut(&storage, |record_table| {
+                let id = record_table.alloc_record().unwrap();
+                record_table.set_record_data(id, Address(i * 7 + 1), Size(i), Address(i * 7 + 1));
+                id
+            });
+            ids.push(id);
+        }
+
+        // Growing moved the table and `with_mut` kept track of it.
+        assert!(runtime_table.data() != initial_data);
+        assert!(runtime_table.data().size > initial_data.size);
+
+        runtime_table.with(&storage, |record_table| {
+            assert_eq!(record_table.item_count(), Size(100));
+
+            for (i, &id) in ids.iter().enumerate() {
+                let record = record_table.get_record(id);
+                assert_eq!(record.addr, Address(i as u32 * 7 + 1));
+                assert_eq!(record.size, Size(i as u32));
+            }
+        });
+    }
 }
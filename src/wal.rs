@@ -0,0 +1,141 @@
+use std::io;
+use std::mem;
+use parking_lot::Mutex;
+use memory::{Storage, Address, Size};
+
+/// A storage adapter that remembers the original contents of every region
+/// that is handed out for writing, so that all changes since the last
+/// `commit()` can be undone with `rollback()`.
+///
+/// Note that only the bytes in storage are restored. The state of an
+/// `Allocator` working on top of this storage is not tracked.
+pub struct WalStorage<S: Storage> {
+    storage: S,
+    // (addr, original bytes), in the order in which they were recorded
+    undo_log: Mutex<Vec<(Address, Vec<u8>)>>,
+}
+
+impl<S: Storage> WalStorage<S> {
+
+    pub fn new(storage: S) -> WalStorage<S> {
+        WalStorage {
+            storage,
+            undo_log: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Makes all changes so far permanent.
+    pub fn commit(&mut self) {
+        self.undo_log.get_mut().clear();
+    }
+
+    /// Undoes all changes since the last commit.
+    pub fn rollback(&mut self) {
+        let undo_log = mem::take(self.undo_log.get_mut());
+
+        // Restore in reverse order so that the oldest contents win for
+        // regions that have been recorded more than once.
+        for (addr, bytes) in undo_log.into_iter().rev() {
+            unsafe {
+                self.storage
+                    .get_bytes_mut(addr, Size::from_bytes(bytes.len()))
+                    .copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    /// The number of regions that would be restored by `rollback()`.
+    pub fn pending_changes(&self) -> usize {
+        self.undo_log.lock().len()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    fn record(&self, addr: Address, len: Size) {
+        let original = unsafe { self.storage.get_bytes(addr, len).to_vec() };
+        self.undo_log.lock().push((addr, original));
+    }
+}
+
+impl<S: Storage> Storage for WalStorage<S> {
+    const IS_READONLY: bool = S::IS_READONLY;
+    type Threading = S::Threading;
+
+    #[inline]
+    fn size(&self) -> Size {
+        self.storage.size()
+    }
+
+    #[inline]
+    unsafe fn get_bytes(&self, addr: Address, len: Size) -> &[u8] {
+        self.storage.get_bytes(addr, len)
+    }
+
+    unsafe fn get_bytes_mut(&self, addr: Address, len: Size) -> &mut [u8] {
+        self.record(addr, len);
+        self.storage.get_bytes_mut(addr, len)
+    }
+
+    unsafe fn copy_nonoverlapping_exclusive(&mut self, src: Address, dst: Address, len: Size) {
+        self.record(dst, len);
+        self.storage.copy_nonoverlapping_exclusive(src, dst, len);
+    }
+
+    fn truncate(&mut self, size: Size) -> Result<(), String> {
+        if self.pending_changes() != 0 {
+            return Err("Cannot truncate storage with uncommitted changes".to_string());
+        }
+
+        self.storage.truncate(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.storage.flush()
+    }
+
+    fn sync_range(&mut self, addr: Address, len: Size) -> io::Result<()> {
+        self.storage.sync_range(addr, len)
+    }
+
+    #[inline]
+    fn prefault(&self, addr: Address, len: Size) {
+        self.storage.prefault(addr, len)
+    }
+
+    #[inline]
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        self.storage.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{Memory, MemStore};
+
+    #[test]
+    fn rollback() {
+        let mut memory = Memory::new(WalStorage::new(MemStore::new(100)));
+        let alloc = memory.alloc(Size(4));
+        let copy = memory.alloc(Size(4));
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[1, 2, 3, 4]);
+        memory.storage_mut().commit();
+        assert_eq!(memory.storage().pending_changes(), 0);
+
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[5, 6, 7, 8]);
+        memory.get_bytes_mut(alloc.addr + Size(1), Size(2)).copy_from_slice(&[9, 9]);
+        memory.copy_nonoverlapping(alloc.addr, copy.addr, alloc.size);
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[5, 9, 9, 8][..]);
+
+        memory.storage_mut().rollback();
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[1, 2, 3, 4][..]);
+        assert_eq!(&*memory.get_bytes(copy.addr, copy.size), &[0; 4][..]);
+
+        memory.get_bytes_mut(alloc.addr, alloc.size).copy_from_slice(&[5, 6, 7, 8]);
+        memory.storage_mut().commit();
+        memory.storage_mut().rollback();
+        assert_eq!(&*memory.get_bytes(alloc.addr, alloc.size), &[5, 6, 7, 8][..]);
+    }
+}
@@ -0,0 +1,84 @@
+// Checks that streamed records are not buffered on the heap. This lives in its
+// own test binary because it needs a counting global allocator.
+
+extern crate dibs;
+
+use dibs::{Database, Memory, MemStore, Size};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    // The bytes currently allocated by this thread and the peak since the
+    // last `reset_peak()`.
+    static USAGE: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = USAGE.try_with(|usage| {
+            let current = usage.get().0 + layout.size();
+            usage.set((current, usage.get().1.max(current)));
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Memory can be freed by a different thread than the one that
+        // allocated it.
+        let _ = USAGE.try_with(|usage| {
+            usage.set((usage.get().0.saturating_sub(layout.size()), usage.get().1));
+        });
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+// Starts a measurement, returns the number of bytes currently allocated.
+fn reset_peak() -> usize {
+    USAGE.with(|usage| {
+        usage.set((usage.get().0, usage.get().0));
+        usage.get().0
+    })
+}
+
+// The peak since the last `reset_peak()`, relative to what was allocated
+// back then.
+fn peak_increase(since: usize) -> usize {
+    USAGE.with(|usage| usage.get().1 - since)
+}
+
+#[test]
+fn streaming_does_not_buffer() {
+    const PAYLOAD_SIZE: usize = 10 * 1024 * 1024;
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let mut db = Database::init(Memory::new(MemStore::new(PAYLOAD_SIZE + 1024 * 1024)));
+
+    let heap_before = reset_peak();
+
+    let record_id = {
+        let mut writer = db.write_record_streaming(Size::from_bytes(PAYLOAD_SIZE));
+        let chunk = vec![7u8; CHUNK_SIZE];
+
+        while writer.remaining() != Size(0) {
+            let len = ::std::cmp::min(chunk.len(), writer.remaining().bytes());
+            writer.write(&chunk[.. len]);
+        }
+
+        writer.finish().unwrap()
+    };
+
+    // Nothing but the chunk has been buffered.
+    assert!(peak_increase(heap_before) < 2 * CHUNK_SIZE);
+    assert_eq!(db.get_record(record_id).len(), PAYLOAD_SIZE);
+
+    // Whereas `write_record()` needs a copy of the whole payload, which shows
+    // that the measurement works.
+    let heap_before = reset_peak();
+    db.write_record(|encoder, _| encoder.buffer().write_bytes(&[0u8; 512 * 1024]));
+    assert!(peak_increase(heap_before) >= 512 * 1024);
+}